@@ -1,11 +1,11 @@
-#[cfg(target_os = "android")]
+#[cfg(all(target_os = "android", feature = "android"))]
 mod oboe;
-#[cfg(target_os = "android")]
+#[cfg(all(target_os = "android", feature = "android"))]
 type Audio = oboe::OboeAudio;
 
-#[cfg(not(target_os = "android"))]
+#[cfg(not(all(target_os = "android", feature = "android")))]
 mod noop;
-#[cfg(not(target_os = "android"))]
+#[cfg(not(all(target_os = "android", feature = "android")))]
 type Audio = noop::NoopAudio;
 
 pub fn init(sample_rate: Option<i32>, frames_per_burst: Option<i32>) {
@@ -14,7 +14,7 @@ pub fn init(sample_rate: Option<i32>, frames_per_burst: Option<i32>) {
         sample_rate,
         frames_per_burst
     );
-    #[cfg(target_os = "android")]
+    #[cfg(all(target_os = "android", feature = "android"))]
     oboe::init(sample_rate, frames_per_burst);
 }
 
@@ -60,7 +60,8 @@ pub mod jni {
         let settings = get_settings(env, settings)?;
         let audio = {
             let mut emulator = get_emulator(env, emulator)?;
-            Audio::new(emulator.claim_audio_player(settings.buffer_size, settings.volume))?
+            emulator.set_audio_buffer_size(settings.buffer_size);
+            Audio::new(emulator.claim_audio_player(settings.volume))?
         };
         AUDIO_BINDING.init_value(env, this, audio)
     }
@@ -1,7 +1,7 @@
 mod manager;
 use manager::{AudioStreamManager, ManagedAudioOutputCallback};
 
-use crate::emulator::audio::AudioPlayer;
+use crate::emulator::audio::{AudioPlayer, SAMPLE_RATE};
 use anyhow::Result;
 use oboe::{
     AudioOutputStreamSafe, AudioStream, AudioStreamBuilder, DataCallbackResult, Error, Output,
@@ -30,7 +30,7 @@ impl ManagedAudioOutputCallback for OboeStreamConfiguration {
             .set_performance_mode(PerformanceMode::LowLatency)
             .set_sharing_mode(SharingMode::Exclusive)
             // virtual boy sample rate is mercifully low
-            .set_sample_rate(41667)
+            .set_sample_rate(SAMPLE_RATE as i32)
             .set_sample_rate_conversion_quality(SampleRateConversionQuality::Best)
             .set_usage(Usage::Game)
     }
@@ -1,6 +1,6 @@
 use crate::emulator::memory::Memory;
 use log::debug;
-use ringbuf::traits::{Consumer, Producer, Split};
+use ringbuf::traits::{Consumer, Observer, Producer, Split};
 use ringbuf::{HeapCons, HeapProd, HeapRb};
 use serde_derive::{Deserialize, Serialize};
 use std::cell::RefCell;
@@ -8,6 +8,8 @@ use std::rc::Rc;
 
 const CPU_CYCLES_PER_FRAME: u64 = 480;
 const FRAMES_PER_SECOND: f32 = 20_000_000. / (CPU_CYCLES_PER_FRAME as f32);
+// FRAMES_PER_SECOND, rounded to something an audio device can play
+pub const SAMPLE_RATE: u32 = 41667;
 
 const PCM_BASE_CYCLES_PER_FRAME: usize = (5_000_000. / FRAMES_PER_SECOND) as usize;
 const NOISE_BASE_CYCLES_PER_FRAME: usize = (500_000. / FRAMES_PER_SECOND) as usize;
@@ -420,6 +422,7 @@ impl Channel {
 }
 
 #[derive(Serialize, Deserialize)]
+#[serde(default)]
 pub struct AudioState {
     cycle: u64,
     prev_input: (f32, f32),
@@ -450,6 +453,10 @@ pub struct AudioController {
     channels: [Channel; 6],
     memory: Rc<RefCell<Memory>>,
     buffer: Option<HeapProd<(f32, f32)>>,
+    samples_produced: u64,
+    playback_speed: f32,
+    playback_position: f32,
+    playing: bool,
 }
 
 impl AudioController {
@@ -464,6 +471,10 @@ impl AudioController {
             channels: state.channels,
             memory,
             buffer: None,
+            samples_produced: 0,
+            playback_speed: 1.,
+            playback_position: 0.,
+            playing: true,
         }
     }
 
@@ -720,17 +731,39 @@ impl AudioController {
 
             let input = self.normalize_frame(frame);
             let output = self.apply_analog_filter(input);
-            values.push(output);
+            // Drop samples when running fast, or repeat them in slow motion,
+            // so the player gets audio as fast as it plays it
+            self.playback_position += 1. / self.playback_speed;
+            while self.playback_position >= 1. {
+                values.push(output);
+                self.playback_position -= 1.;
+            }
             self.prev_input = input;
             self.prev_output = output;
+            self.samples_produced += 1;
 
             self.cycle += CPU_CYCLES_PER_FRAME;
         }
-        if let Some(buffer) = self.buffer.as_mut() {
+        if let Some(buffer) = self.buffer.as_mut().filter(|_| self.playing) {
             buffer.push_slice(&values);
         }
     }
 
+    // Stops sending samples to the player, for emulating audio nobody should hear
+    pub fn set_playing(&mut self, playing: bool) {
+        self.playing = playing;
+    }
+
+    pub fn set_playback_speed(&mut self, speed: f32) {
+        self.playback_speed = speed;
+        self.playback_position = 0.;
+    }
+
+    // How many stereo samples have been generated, whether or not anyone was listening
+    pub fn samples_produced(&self) -> u64 {
+        self.samples_produced
+    }
+
     fn normalize_frame(&self, frame: (u16, u16)) -> (f32, f32) {
         fn to_float(input: u16) -> f32 {
             (input >> 4) as f32 / 685.
@@ -756,6 +789,11 @@ pub struct AudioPlayer {
 }
 
 impl AudioPlayer {
+    // How many frames have been produced but not played yet
+    pub fn available(&self) -> usize {
+        self.buffer.occupied_len()
+    }
+
     pub fn play(&mut self, frames: &mut [(f32, f32)]) {
         let count = self.buffer.pop_slice(frames);
         for frame in &mut frames[..count] {
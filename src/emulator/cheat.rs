@@ -0,0 +1,376 @@
+use anyhow::{anyhow, Result};
+use std::fmt;
+
+use super::memory::Memory;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CheatValue {
+    Byte(u8),
+    Halfword(u16),
+    Word(u32),
+}
+impl CheatValue {
+    // The width comes from the number of digits, so "05" is a byte and "0005" is a halfword
+    fn parse(text: &str) -> Result<Self> {
+        let value =
+            u32::from_str_radix(text, 16).map_err(|_| anyhow!("invalid value \"{}\"", text))?;
+        match text.len() {
+            2 => Ok(CheatValue::Byte(value as u8)),
+            4 => Ok(CheatValue::Halfword(value as u16)),
+            8 => Ok(CheatValue::Word(value)),
+            _ => Err(anyhow!("value \"{}\" must be 2, 4 or 8 hex digits", text)),
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            CheatValue::Byte(_) => 1,
+            CheatValue::Halfword(_) => 2,
+            CheatValue::Word(_) => 4,
+        }
+    }
+
+    fn get(&self) -> u32 {
+        match *self {
+            CheatValue::Byte(value) => value as u32,
+            CheatValue::Halfword(value) => value as u32,
+            CheatValue::Word(value) => value,
+        }
+    }
+
+    fn read(&self, memory: &Memory, address: usize) -> u32 {
+        match self {
+            CheatValue::Byte(_) => memory.read_byte(address) as u32,
+            CheatValue::Halfword(_) => memory.read_halfword(address) as u32,
+            CheatValue::Word(_) => memory.read_word(address),
+        }
+    }
+
+    fn write(&self, memory: &mut Memory, address: usize) {
+        match *self {
+            CheatValue::Byte(value) => memory.write_byte(address, value),
+            CheatValue::Halfword(value) => memory.write_halfword(address, value),
+            CheatValue::Word(value) => memory.write_word(address, value),
+        };
+    }
+}
+impl fmt::Display for CheatValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheatValue::Byte(value) => write!(f, "{:02x}", value),
+            CheatValue::Halfword(value) => write!(f, "{:04x}", value),
+            CheatValue::Word(value) => write!(f, "{:08x}", value),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    Greater,
+}
+// Longest first, so "<" doesn't match part of something else
+const COMPARISONS: [(&str, Comparison); 4] = [
+    ("==", Comparison::Equal),
+    ("!=", Comparison::NotEqual),
+    ("<", Comparison::Less),
+    (">", Comparison::Greater),
+];
+
+// Compares memory with a value, unsigned
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Condition {
+    pub address: usize,
+    pub comparison: Comparison,
+    pub value: CheatValue,
+}
+impl Condition {
+    fn parse(text: &str) -> Result<Self> {
+        let (address, comparison, value) = COMPARISONS
+            .iter()
+            .find_map(|(operator, comparison)| {
+                text.split_once(operator)
+                    .map(|(address, value)| (address, *comparison, value))
+            })
+            .ok_or_else(|| anyhow!("condition \"{}\" has no comparison", text))?;
+        let value = CheatValue::parse(value.trim())?;
+        let address = parse_address(address.trim(), value.size())?;
+        Ok(Self {
+            address,
+            comparison,
+            value,
+        })
+    }
+
+    fn holds(&self, memory: &Memory) -> bool {
+        let actual = self.value.read(memory, self.address);
+        let expected = self.value.get();
+        match self.comparison {
+            Comparison::Equal => actual == expected,
+            Comparison::NotEqual => actual != expected,
+            Comparison::Less => actual < expected,
+            Comparison::Greater => actual > expected,
+        }
+    }
+}
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (operator, _) = COMPARISONS
+            .iter()
+            .find(|(_, comparison)| *comparison == self.comparison)
+            .unwrap();
+        write!(f, "{:08x}{}{}", self.address, operator, self.value)
+    }
+}
+
+// Cheats can only touch work RAM and save RAM
+fn parse_address(text: &str, size: usize) -> Result<usize> {
+    let address =
+        usize::from_str_radix(text, 16).map_err(|_| anyhow!("invalid address \"{}\"", text))?;
+    if !matches!(address >> 24, 0x05 | 0x06) {
+        return Err(anyhow!("address {:08x} is not in DRAM or SRAM", address));
+    }
+    if !address.is_multiple_of(size) {
+        return Err(anyhow!("address {:08x} is not aligned", address));
+    }
+    Ok(address)
+}
+
+// Writes a value to memory at the start of every frame, or only once.
+// As text, a cheat looks like "[-][name: ][once ]<address>=<value>[ if <address><op><value>]",
+// where "-" means disabled and op is one of ==, !=, < or >. Addresses and values are hex.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cheat {
+    pub name: String,
+    pub enabled: bool,
+    // Only write the value the first time the condition holds, instead of freezing it there
+    pub once: bool,
+    pub address: usize,
+    pub value: CheatValue,
+    pub condition: Option<Condition>,
+}
+impl Cheat {
+    pub fn parse(text: &str) -> Result<Self> {
+        let (enabled, text) = match text.trim().strip_prefix('-') {
+            Some(rest) => (false, rest.trim()),
+            None => (true, text.trim()),
+        };
+        let (name, text) = match text.split_once(':') {
+            Some((name, rest)) => (name.trim().to_string(), rest.trim()),
+            None => (String::new(), text),
+        };
+        let (once, text) = match text.strip_prefix("once ") {
+            Some(rest) => (true, rest.trim()),
+            None => (false, text),
+        };
+        let (write, condition) = match text.split_once(" if ") {
+            Some((write, condition)) => (write, Some(Condition::parse(condition.trim())?)),
+            None => (text, None),
+        };
+        let (address, value) = write
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected <address>=<value>, found \"{}\"", write))?;
+        let value = CheatValue::parse(value.trim())?;
+        let address = parse_address(address.trim(), value.size())?;
+        Ok(Self {
+            name,
+            enabled,
+            once,
+            address,
+            value,
+            condition,
+        })
+    }
+}
+impl fmt::Display for Cheat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.enabled {
+            write!(f, "-")?;
+        }
+        if !self.name.is_empty() {
+            write!(f, "{}: ", self.name)?;
+        }
+        if self.once {
+            write!(f, "once ")?;
+        }
+        write!(f, "{:08x}={}", self.address, self.value)?;
+        if let Some(condition) = &self.condition {
+            write!(f, " if {}", condition)?;
+        }
+        Ok(())
+    }
+}
+
+// The cheats for one game, one per line, with "#" starting a comment.
+// The emulator applies them in order at every frame boundary, so they're deterministic.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CheatList {
+    cheats: Vec<Cheat>,
+    // Which one-shot cheats have already been written
+    applied: Vec<bool>,
+}
+impl CheatList {
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut list = Self::default();
+        for (index, line) in text.lines().enumerate() {
+            let line = match line.split_once('#') {
+                Some((content, _)) => content,
+                None => line,
+            }
+            .trim();
+            if line.is_empty() {
+                continue;
+            }
+            let cheat = Cheat::parse(line).map_err(|e| anyhow!("Line {}: {}", index + 1, e))?;
+            list.push(cheat);
+        }
+        Ok(list)
+    }
+
+    pub fn cheats(&self) -> &[Cheat] {
+        &self.cheats
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cheats.is_empty()
+    }
+
+    pub fn push(&mut self, cheat: Cheat) {
+        self.cheats.push(cheat);
+        self.applied.push(false);
+    }
+
+    pub fn remove(&mut self, index: usize) -> Cheat {
+        self.applied.remove(index);
+        self.cheats.remove(index)
+    }
+
+    // Re-enabling a one-shot cheat lets it fire again
+    pub fn set_enabled(&mut self, index: usize, enabled: bool) {
+        self.cheats[index].enabled = enabled;
+        if enabled {
+            self.applied[index] = false;
+        }
+    }
+
+    // After a reset, every one-shot cheat should fire again
+    pub fn rearm(&mut self) {
+        self.applied.fill(false);
+    }
+
+    // Which one-shot cheats have fired, so rolling memory back can roll this back too
+    pub fn applied(&self) -> Vec<bool> {
+        self.applied.clone()
+    }
+
+    // Ignored if the cheats have changed since, since there's no telling which is which
+    pub fn restore_applied(&mut self, applied: &[bool]) {
+        if applied.len() == self.applied.len() {
+            self.applied.copy_from_slice(applied);
+        }
+    }
+
+    pub fn apply(&mut self, memory: &mut Memory) {
+        for (cheat, applied) in self.cheats.iter().zip(self.applied.iter_mut()) {
+            if !cheat.enabled || (cheat.once && *applied) {
+                continue;
+            }
+            if cheat
+                .condition
+                .is_some_and(|condition| !condition.holds(memory))
+            {
+                continue;
+            }
+            cheat.value.write(memory, cheat.address);
+            *applied = true;
+        }
+    }
+}
+impl fmt::Display for CheatList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for cheat in &self.cheats {
+            writeln!(f, "{}", cheat)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::cheat::{Cheat, CheatList, CheatValue, Comparison, Condition};
+    use crate::emulator::memory::Memory;
+
+    #[test]
+    fn parses_codes() {
+        let cheat = Cheat::parse("-Infinite lives: once 05000124=0009 if 05000010!=00").unwrap();
+        assert_eq!(
+            cheat,
+            Cheat {
+                name: "Infinite lives".to_string(),
+                enabled: false,
+                once: true,
+                address: 0x05000124,
+                value: CheatValue::Halfword(9),
+                condition: Some(Condition {
+                    address: 0x05000010,
+                    comparison: Comparison::NotEqual,
+                    value: CheatValue::Byte(0),
+                }),
+            }
+        );
+        assert_eq!(
+            cheat.to_string(),
+            "-Infinite lives: once 05000124=0009 if 05000010!=00"
+        );
+        assert_eq!(Cheat::parse(&cheat.to_string()).unwrap(), cheat);
+    }
+
+    #[test]
+    fn rejects_bad_codes() {
+        assert!(Cheat::parse("07000000=00").is_err());
+        assert!(Cheat::parse("05000001=0000").is_err());
+        assert!(Cheat::parse("05000000=000").is_err());
+        assert!(Cheat::parse("05000000").is_err());
+        assert!(Cheat::parse("05000000=00 if 05000000~00").is_err());
+        let error = CheatList::parse("# Comment\n\n05000000=zz").unwrap_err();
+        assert_eq!(error.to_string(), "Line 3: invalid value \"zz\"");
+    }
+
+    #[test]
+    fn freezes_values_every_frame() {
+        let mut memory = Memory::new();
+        let mut cheats = CheatList::parse("05000000=00000063\n06000000=07").unwrap();
+        cheats.apply(&mut memory);
+        assert_eq!(memory.read_word(0x05000000), 99);
+        memory.write_word(0x05000000, 1);
+        cheats.apply(&mut memory);
+        assert_eq!(memory.read_word(0x05000000), 99);
+
+        cheats.set_enabled(0, false);
+        memory.write_word(0x05000000, 1);
+        cheats.apply(&mut memory);
+        assert_eq!(memory.read_word(0x05000000), 1);
+    }
+
+    #[test]
+    fn writes_one_shot_codes_once_their_condition_holds() {
+        let mut memory = Memory::new();
+        let mut cheats = CheatList::parse("once 05000000=05 if 05000010>02").unwrap();
+        cheats.apply(&mut memory);
+        assert_eq!(memory.read_byte(0x05000000), 0);
+
+        memory.write_byte(0x05000010, 3);
+        cheats.apply(&mut memory);
+        assert_eq!(memory.read_byte(0x05000000), 5);
+
+        memory.write_byte(0x05000000, 0);
+        cheats.apply(&mut memory);
+        assert_eq!(memory.read_byte(0x05000000), 0);
+
+        cheats.rearm();
+        cheats.apply(&mut memory);
+        assert_eq!(memory.read_byte(0x05000000), 5);
+    }
+}
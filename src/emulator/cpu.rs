@@ -1,5 +1,9 @@
+mod cache;
+use super::debug::{Breakpoint, DebugHit};
 use super::memory::Memory;
+use super::trace::{TraceEntry, Tracer};
 use anyhow::Result;
+use cache::InstructionCache;
 use serde_derive::{Deserialize, Serialize};
 use std::cell::{RefCell, RefMut};
 use std::num::FpCategory;
@@ -19,6 +23,15 @@ const EIPSW: usize = 1;
 const FEPC: usize = 2;
 // FEPSW: fatal error PSW (for duplexed exceptions)
 const FEPSW: usize = 3;
+// CHCW: cache control word
+const CHCW: usize = 24;
+
+// CHCW flags and fields
+const CACHE_CLEAR_FLAG: u32 = 0x00000001;
+const CACHE_ENABLE_FLAG: u32 = 0x00000002;
+const CACHE_DUMP_FLAG: u32 = 0x00000010;
+const CACHE_RESTORE_FLAG: u32 = 0x00000020;
+const CACHE_SPILL_ADDRESS: u32 = 0xffffff00;
 
 // PSW flags and masks
 const INTERRUPT_LEVEL: u32 = 0x000f0000;
@@ -53,6 +66,7 @@ fn bit_range_mask(start: u32, length: u32) -> u32 {
 }
 
 #[derive(Serialize, Deserialize)]
+#[serde(default)]
 pub struct CpuState {
     cycle: u64,
     bitstring_cycle: u64,
@@ -60,6 +74,7 @@ pub struct CpuState {
     pc: usize,
     registers: [u32; 32],
     sys_registers: [u32; 32],
+    cache: InstructionCache,
 }
 
 impl Default for CpuState {
@@ -71,6 +86,7 @@ impl Default for CpuState {
             pc: 0xfffffff0,
             registers: [0; 32],
             sys_registers: [0; 32],
+            cache: InstructionCache::default(),
         };
         for (sys_reg_index, sys_reg) in state.sys_registers.iter_mut().enumerate() {
             *sys_reg = match sys_reg_index {
@@ -93,8 +109,13 @@ pub struct Cpu<THandler: EventHandler> {
     pub pc: usize,
     pub registers: [u32; 32],
     pub sys_registers: [u32; 32],
+    cache: InstructionCache,
     memory: Rc<RefCell<Memory>>,
     handler: THandler,
+    tracer: Option<Tracer>,
+    breakpoints: Vec<Breakpoint>,
+    // After stopping at a breakpoint, the next run starts by running that instruction
+    resume_pc: Option<usize>,
 }
 impl<THandler: EventHandler> Cpu<THandler> {
     pub fn new(memory: Rc<RefCell<Memory>>, handler: THandler) -> Self {
@@ -106,8 +127,12 @@ impl<THandler: EventHandler> Cpu<THandler> {
             pc: state.pc,
             registers: state.registers,
             sys_registers: state.sys_registers,
+            cache: state.cache,
             memory,
             handler,
+            tracer: None,
+            breakpoints: vec![],
+            resume_pc: None,
         }
     }
     pub fn init(&mut self) {
@@ -122,6 +147,7 @@ impl<THandler: EventHandler> Cpu<THandler> {
             pc: self.pc,
             registers: self.registers,
             sys_registers: self.sys_registers,
+            cache: self.cache.clone(),
         }
     }
 
@@ -132,10 +158,13 @@ impl<THandler: EventHandler> Cpu<THandler> {
         self.pc = state.pc;
         self.registers.copy_from_slice(&state.registers);
         self.sys_registers.copy_from_slice(&state.sys_registers);
+        self.cache = state.cache.clone();
+        self.resume_pc = None;
     }
 
     pub fn run(&mut self, target_cycle: u64) -> Result<CpuProcessingResult> {
         let mut event;
+        let mut debug_hit = None;
         while !self.halted {
             let mut process = CpuProcess {
                 pc: self.pc,
@@ -144,53 +173,94 @@ impl<THandler: EventHandler> Cpu<THandler> {
                 halted: self.halted,
                 registers: &mut self.registers,
                 sys_registers: &mut self.sys_registers,
+                cache: &mut self.cache,
                 event: None,
                 exception: None,
                 memory: self.memory.borrow_mut(),
+                tracer: self.tracer.as_mut(),
+                breakpoints: &self.breakpoints,
+                resume_pc: self.resume_pc,
+                watching: false,
+                debug_hit: None,
             };
             process.run(target_cycle);
             self.pc = process.pc;
             self.cycle = process.cycle;
             self.bitstring_cycle = process.bitstring_cycle;
             self.halted = process.halted;
+            self.resume_pc = process.resume_pc;
             event = process.event;
             let exception = process.exception;
+            debug_hit = process.debug_hit;
 
             drop(process);
 
             if let Some(event) = event {
-                if self.handler.handle(event, self.cycle)? {
+                if self.handler.handle(event, self.cycle)? && debug_hit.is_none() {
                     continue;
                 }
             }
             if let Some(exception) = exception {
                 self.raise_exception(exception);
-                continue;
+                if debug_hit.is_none() {
+                    continue;
+                }
             }
             break;
         }
 
+        if let Some(DebugHit::Breakpoint { pc }) = debug_hit {
+            self.resume_pc = Some(pc);
+        }
         // Make sure that we simulate time passing, even if the CPU is halted.
         // This is safe because as long as the CPU is halted, we know that the next interrupt
         // won't happen until at least target_cycle.
-        self.cycle = self.cycle.max(target_cycle);
+        // A debugger stopped us on purpose though, so leave the clock where it is.
+        if debug_hit.is_none() {
+            self.cycle = self.cycle.max(target_cycle);
+        }
+
+        Ok(CpuProcessingResult {
+            cycle: self.cycle,
+            debug_hit,
+        })
+    }
+
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
 
-        Ok(CpuProcessingResult { cycle: self.cycle })
+    pub fn replace_tracer(&mut self, tracer: Option<Tracer>) -> Option<Tracer> {
+        std::mem::replace(&mut self.tracer, tracer)
     }
-    pub fn raise_exception(&mut self, exception: Exception) {
+
+    pub fn tracer(&self) -> Option<&Tracer> {
+        self.tracer.as_ref()
+    }
+
+    pub fn replace_breakpoints(&mut self, breakpoints: Vec<Breakpoint>) -> Vec<Breakpoint> {
+        std::mem::replace(&mut self.breakpoints, breakpoints)
+    }
+
+    pub fn breakpoints_mut(&mut self) -> &mut Vec<Breakpoint> {
+        &mut self.breakpoints
+    }
+
+    // Returns whether the CPU took the exception, since interrupts can be ignored
+    pub fn raise_exception(&mut self, exception: Exception) -> bool {
         let mut psw = self.sys_registers[PSW];
 
         // Extra logic for interrupts
         if let ExceptionCategory::Interrupt { level } = exception.category {
             // if interrupts have been disabled, do nothing
             if (psw & INTERRUPTS_DISABLED_MASK) != 0 {
-                return;
+                return false;
             }
 
             // if the current interrupt is more important, do nothing
             let current_level = ((psw & INTERRUPT_LEVEL) >> 16) as u8;
             if current_level > level {
-                return;
+                return false;
             }
 
             // Store the interrupt level in PSW
@@ -199,7 +269,23 @@ impl<THandler: EventHandler> Cpu<THandler> {
 
             // If the CPU was halted, unhalt it
             self.halted = false;
-        } else if psw & NMI_PENDING_FLAG != 0 {
+        }
+
+        if let Some(tracer) = &mut self.tracer {
+            let interrupt_level = match exception.category {
+                ExceptionCategory::Interrupt { level } => Some(level),
+                ExceptionCategory::Error => None,
+            };
+            tracer.record(TraceEntry::Exception {
+                cycle: self.cycle,
+                pc: self.pc,
+                code: exception.code,
+                handler: exception.handler,
+                interrupt_level,
+            });
+        }
+
+        if matches!(exception.category, ExceptionCategory::Error) && psw & NMI_PENDING_FLAG != 0 {
             // If we hit a fatal exception, write some state to VRAM and give up
             let code = 0xffff0000 | (exception.code as u32);
             let pc = self.pc as u32;
@@ -216,7 +302,7 @@ impl<THandler: EventHandler> Cpu<THandler> {
             memory.write_word(0x00000008, pc);
 
             self.halted = true;
-            return;
+            return true;
         }
 
         let mut ecr = self.sys_registers[ECR];
@@ -247,11 +333,13 @@ impl<THandler: EventHandler> Cpu<THandler> {
         psw |= INTERRUPT_DISABLE_FLAG;
         psw &= !ADDRESS_TRAP_ENABLE_FLAG;
         self.sys_registers[PSW] = psw;
+        true
     }
 }
 
 pub struct CpuProcessingResult {
     pub cycle: u64,
+    pub debug_hit: Option<DebugHit>,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -266,13 +354,13 @@ pub trait EventHandler {
     fn handle(&mut self, event: Event, cycle: u64) -> Result<bool>;
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ExceptionCategory {
     Interrupt { level: u8 },
     Error,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Exception {
     code: u16,
     handler: usize,
@@ -303,130 +391,196 @@ struct CpuProcess<'a> {
     halted: bool,
     registers: &'a mut [u32; 32],
     sys_registers: &'a mut [u32; 32],
+    cache: &'a mut InstructionCache,
     event: Option<Event>,
     exception: Option<Exception>,
     memory: RefMut<'a, Memory>,
+    tracer: Option<&'a mut Tracer>,
+    breakpoints: &'a [Breakpoint],
+    resume_pc: Option<usize>,
+    watching: bool,
+    debug_hit: Option<DebugHit>,
 }
 impl<'a> CpuProcess<'a> {
     pub fn run(&mut self, target_cycle: u64) {
+        self.watching = self.memory.start_watching();
         while self.cycle < target_cycle
             && self.event.is_none()
             && self.exception.is_none()
+            && self.debug_hit.is_none()
             && !self.halted
         {
-            let instr = self.read_pc();
-            let opcode = (instr >> 10) & 0x003F;
-            if instr & 0xe000 == 0x8000 {
-                self.bcond(instr);
-                continue;
+            let pc = self.pc;
+            if !self.breakpoints.is_empty() && self.at_breakpoint() {
+                self.debug_hit = Some(DebugHit::Breakpoint { pc });
+                break;
             }
-            match opcode {
-                0b010000 => self.mov_i(instr),
-                0b000000 => self.mov_r(instr),
-                0b101111 => self.movhi(instr),
-                0b101000 => self.movea(instr),
-
-                0b110000 => self.ld_b(instr),
-                0b110001 => self.ld_h(instr),
-                0b110011 => self.ld_w(instr),
-                0b111000 => self.in_b(instr),
-                0b111001 => self.in_h(instr),
-                0b111011 => self.in_w(instr),
-
-                0b110100 => self.st_b(instr),
-                0b110101 => self.st_h(instr),
-                0b110111 => self.st_w(instr),
-                // OUT.x match ST.x
-                0b111100 => self.st_b(instr),
-                0b111101 => self.st_h(instr),
-                0b111111 => self.st_w(instr),
-
-                0b010001 => self.add_i(instr),
-                0b000001 => self.add_r(instr),
-                0b101001 => self.addi(instr),
-                0b010011 => self.cmp_i(instr),
-                0b000011 => self.cmp_r(instr),
-                0b000010 => self.sub(instr),
-                0b001000 => self.mul(instr),
-                0b001010 => self.mulu(instr),
-                0b001001 => self.div(instr),
-                0b001011 => self.divu(instr),
-
-                0b001101 => self.and(instr),
-                0b101101 => self.andi(instr),
-                0b001111 => self.not(instr),
-                0b001100 => self.or(instr),
-                0b101100 => self.ori(instr),
-                0b001110 => self.xor(instr),
-                0b101110 => self.xori(instr),
-
-                0b010111 => self.sar_i(instr),
-                0b000111 => self.sar_r(instr),
-                0b010100 => self.shl_i(instr),
-                0b000100 => self.shl_r(instr),
-                0b010101 => self.shr_i(instr),
-                0b000101 => self.shr_r(instr),
-
-                0b101011 => self.jal(instr),
-                0b000110 => self.jmp(instr),
-                0b101010 => self.jr(instr),
-
-                0b111010 => self.caxi(instr),
-                0b010010 => self.setf(instr),
-
-                0b011010 => self.halt(),
-
-                0b011100 => self.ldsr(instr),
-                0b011101 => self.stsr(instr),
-
-                0b011110 => self.sei(),
-                0b010110 => self.cli(),
-                0b011000 => self.trap(instr),
-                0b011001 => self.reti(),
-
-                0b011111 => self.bitstring_operation(instr),
-
-                0b111110 => {
-                    // format 7 opcodes are format 1 with a subopcode suffix
-                    let subopcode = (self.read_pc() >> 10) & 0x3f;
-                    match subopcode {
-                        0b000000 => self.cmpf_s(instr),
-                        0b000010 => self.cvt_ws(instr),
-                        0b000011 => self.cvt_sw(instr),
-                        0b000100 => self.addf_s(instr),
-                        0b000101 => self.subf_s(instr),
-                        0b000110 => self.mulf_s(instr),
-                        0b000111 => self.divf_s(instr),
-                        0b001011 => self.trnc_sw(instr),
-                        0b001100 => self.mpyhw(instr),
-                        0b001010 => self.rev(instr),
-                        0b001000 => self.xb(instr),
-                        0b001001 => self.xh(instr),
-                        _ => {
-                            // Invalid opcode
-                            self.pc -= 4;
-                            log::warn!(
-                                "Invalid subopcode 0b{:06b} at 0x{:08x}",
-                                subopcode,
-                                self.pc
-                            );
-                            self.exception = Some(Exception::error(0xff90, 0xffffff90));
-                        }
-                    }
+
+            // Keep the untraced path free of any tracing work
+            if self.tracer.is_some() {
+                self.execute_traced();
+            } else {
+                self.execute();
+            }
+
+            if self.watching {
+                if let Some((address, access, value)) = self.memory.take_watch_hit() {
+                    self.debug_hit = Some(DebugHit::Watchpoint {
+                        pc,
+                        address,
+                        access,
+                        value,
+                    });
                 }
+            }
+        }
+        self.memory.stop_watching();
+    }
 
-                _ => {
-                    // Invalid opcode
-                    self.pc -= 2;
-                    log::warn!("Invalid opcode 0b{:06b} at 0x{:08x}", opcode, self.pc);
-                    self.exception = Some(Exception::error(0xff90, 0xffffff90));
+    fn at_breakpoint(&mut self) -> bool {
+        // Don't stop at the breakpoint we just stopped at, or we'd never get past it
+        if self.resume_pc.take() == Some(self.pc) {
+            return false;
+        }
+        self.breakpoints
+            .iter()
+            .any(|breakpoint| breakpoint.matches(self.pc, self.registers))
+    }
+
+    fn execute_traced(&mut self) {
+        let pc = self.pc;
+        let cycle = self.cycle;
+        let opcode = [
+            self.memory.fetch_halfword(pc),
+            self.memory.fetch_halfword(pc.wrapping_add(2)),
+        ];
+        let before = [*self.registers, *self.sys_registers];
+        self.execute();
+        let after = [*self.registers, *self.sys_registers];
+        if let Some(tracer) = &mut self.tracer {
+            tracer.record_instruction(cycle, pc, opcode, &before, &after);
+        }
+    }
+
+    fn execute(&mut self) {
+        let instr = self.read_pc();
+        let opcode = (instr >> 10) & 0x003F;
+        if instr & 0xe000 == 0x8000 {
+            self.bcond(instr);
+            return;
+        }
+        match opcode {
+            0b010000 => self.mov_i(instr),
+            0b000000 => self.mov_r(instr),
+            0b101111 => self.movhi(instr),
+            0b101000 => self.movea(instr),
+
+            0b110000 => self.ld_b(instr),
+            0b110001 => self.ld_h(instr),
+            0b110011 => self.ld_w(instr),
+            0b111000 => self.in_b(instr),
+            0b111001 => self.in_h(instr),
+            0b111011 => self.in_w(instr),
+
+            0b110100 => self.st_b(instr),
+            0b110101 => self.st_h(instr),
+            0b110111 => self.st_w(instr),
+            // OUT.x match ST.x
+            0b111100 => self.st_b(instr),
+            0b111101 => self.st_h(instr),
+            0b111111 => self.st_w(instr),
+
+            0b010001 => self.add_i(instr),
+            0b000001 => self.add_r(instr),
+            0b101001 => self.addi(instr),
+            0b010011 => self.cmp_i(instr),
+            0b000011 => self.cmp_r(instr),
+            0b000010 => self.sub(instr),
+            0b001000 => self.mul(instr),
+            0b001010 => self.mulu(instr),
+            0b001001 => self.div(instr),
+            0b001011 => self.divu(instr),
+
+            0b001101 => self.and(instr),
+            0b101101 => self.andi(instr),
+            0b001111 => self.not(instr),
+            0b001100 => self.or(instr),
+            0b101100 => self.ori(instr),
+            0b001110 => self.xor(instr),
+            0b101110 => self.xori(instr),
+
+            0b010111 => self.sar_i(instr),
+            0b000111 => self.sar_r(instr),
+            0b010100 => self.shl_i(instr),
+            0b000100 => self.shl_r(instr),
+            0b010101 => self.shr_i(instr),
+            0b000101 => self.shr_r(instr),
+
+            0b101011 => self.jal(instr),
+            0b000110 => self.jmp(instr),
+            0b101010 => self.jr(instr),
+
+            0b111010 => self.caxi(instr),
+            0b010010 => self.setf(instr),
+
+            0b011010 => self.halt(),
+
+            0b011100 => self.ldsr(instr),
+            0b011101 => self.stsr(instr),
+
+            0b011110 => self.sei(),
+            0b010110 => self.cli(),
+            0b011000 => self.trap(instr),
+            0b011001 => self.reti(),
+
+            0b011111 => self.bitstring_operation(instr),
+
+            0b111110 => {
+                // format 7 opcodes are format 1 with a subopcode suffix
+                let subopcode = (self.read_pc() >> 10) & 0x3f;
+                match subopcode {
+                    0b000000 => self.cmpf_s(instr),
+                    0b000010 => self.cvt_ws(instr),
+                    0b000011 => self.cvt_sw(instr),
+                    0b000100 => self.addf_s(instr),
+                    0b000101 => self.subf_s(instr),
+                    0b000110 => self.mulf_s(instr),
+                    0b000111 => self.divf_s(instr),
+                    0b001011 => self.trnc_sw(instr),
+                    0b001100 => self.mpyhw(instr),
+                    0b001010 => self.rev(instr),
+                    0b001000 => self.xb(instr),
+                    0b001001 => self.xh(instr),
+                    _ => {
+                        // Invalid opcode
+                        self.pc -= 4;
+                        log::warn!("Invalid subopcode 0b{:06b} at 0x{:08x}", subopcode, self.pc);
+                        self.exception = Some(Exception::error(0xff90, 0xffffff90));
+                    }
                 }
-            };
+            }
+
+            _ => {
+                // Invalid opcode
+                self.pc -= 2;
+                log::warn!("Invalid opcode 0b{:06b} at 0x{:08x}", opcode, self.pc);
+                self.exception = Some(Exception::error(0xff90, 0xffffff90));
+            }
         }
     }
 
     fn read_pc(&mut self) -> u16 {
-        let result = self.memory.read_halfword(self.pc);
+        // Cache hits are free, but everything else waits on the bus
+        let result = if self.sys_registers[CHCW] & CACHE_ENABLE_FLAG != 0 {
+            let (result, hit) = self.cache.fetch(&self.memory, self.pc);
+            if !hit {
+                self.cycle += cache::fill_cycles(&self.memory, self.pc);
+            }
+            result
+        } else {
+            self.cycle += self.memory.wait_cycles(self.pc, 2);
+            self.memory.fetch_halfword(self.pc)
+        };
         self.pc += 2;
         result
     }
@@ -456,25 +610,25 @@ impl<'a> CpuProcess<'a> {
         let (reg2, reg1, disp) = self.parse_format_vi_opcode(instr);
         let address = (self.registers[reg1] as i32).wrapping_add(disp) as usize;
         self.set_register(reg2, self.memory.read_byte(address) as i8 as u32);
-        self.cycle += 5;
+        self.cycle += 5 + self.memory.wait_cycles(address, 1);
     }
     fn ld_h(&mut self, instr: u16) {
         let (reg2, reg1, disp) = self.parse_format_vi_opcode(instr);
         let address = (self.registers[reg1] as i32).wrapping_add(disp) as usize & 0xfffffffe;
         self.set_register(reg2, self.memory.read_halfword(address) as i16 as u32);
-        self.cycle += 5;
+        self.cycle += 5 + self.memory.wait_cycles(address, 2);
     }
     fn ld_w(&mut self, instr: u16) {
         let (reg2, reg1, disp) = self.parse_format_vi_opcode(instr);
         let address = (self.registers[reg1] as i32).wrapping_add(disp) as usize & 0xfffffffc;
         self.set_register(reg2, self.memory.read_word(address));
-        self.cycle += 5;
+        self.cycle += 5 + self.memory.wait_cycles(address, 4);
     }
     fn in_b(&mut self, instr: u16) {
         let (reg2, reg1, disp) = self.parse_format_vi_opcode(instr);
         let address = (self.registers[reg1] as i32).wrapping_add(disp) as usize;
         self.set_register(reg2, (self.memory.read_byte(address) as u32) & 0x000000ff);
-        self.cycle += 5;
+        self.cycle += 5 + self.memory.wait_cycles(address, 1);
     }
     fn in_h(&mut self, instr: u16) {
         let (reg2, reg1, disp) = self.parse_format_vi_opcode(instr);
@@ -483,20 +637,20 @@ impl<'a> CpuProcess<'a> {
             reg2,
             (self.memory.read_halfword(address) as u32) & 0x0000ffff,
         );
-        self.cycle += 5;
+        self.cycle += 5 + self.memory.wait_cycles(address, 2);
     }
     fn in_w(&mut self, instr: u16) {
         let (reg2, reg1, disp) = self.parse_format_vi_opcode(instr);
         let address = (self.registers[reg1] as i32).wrapping_add(disp) as usize & 0xfffffffc;
         self.set_register(reg2, self.memory.read_word(address));
-        self.cycle += 5;
+        self.cycle += 5 + self.memory.wait_cycles(address, 4);
     }
 
     fn st_b(&mut self, instr: u16) {
         let (reg2, reg1, disp) = self.parse_format_vi_opcode(instr);
         let address = (self.registers[reg1] as i32).wrapping_add(disp) as usize;
         self.event = self.memory.write_byte(address, self.registers[reg2] as u8);
-        self.cycle += 4;
+        self.cycle += 4 + self.memory.wait_cycles(address, 1);
     }
     fn st_h(&mut self, instr: u16) {
         let (reg2, reg1, disp) = self.parse_format_vi_opcode(instr);
@@ -504,13 +658,13 @@ impl<'a> CpuProcess<'a> {
         self.event = self
             .memory
             .write_halfword(address, self.registers[reg2] as u16);
-        self.cycle += 4;
+        self.cycle += 4 + self.memory.wait_cycles(address, 2);
     }
     fn st_w(&mut self, instr: u16) {
         let (reg2, reg1, disp) = self.parse_format_vi_opcode(instr);
         let address = (self.registers[reg1] as i32).wrapping_add(disp) as usize & 0xfffffffc;
         self.event = self.memory.write_word(address, self.registers[reg2]);
-        self.cycle += 4;
+        self.cycle += 4 + self.memory.wait_cycles(address, 4);
     }
 
     fn add_r(&mut self, instr: u16) {
@@ -618,17 +772,16 @@ impl<'a> CpuProcess<'a> {
         let (reg2, reg1) = self.parse_format_i_opcode(instr);
         let dividend = self.registers[reg2];
         let divisor = self.registers[reg1];
-        if divisor == 0 {
-            // trap for divide by 0
-            self.pc -= 2;
-            log::warn!("DIVU by 0 at 0x{:08x}", self.pc);
-            self.exception = Some(Exception::error(0xff80, 0xffffff80));
-        } else {
-            let quotient = dividend / divisor;
+        if let Some(quotient) = dividend.checked_div(divisor) {
             let remainder = dividend % divisor;
             self.set_register(30, remainder);
             self.set_register(reg2, quotient);
             self.update_psw_flags(quotient == 0, sign_bit(quotient), false);
+        } else {
+            // trap for divide by 0
+            self.pc -= 2;
+            log::warn!("DIVU by 0 at 0x{:08x}", self.pc);
+            self.exception = Some(Exception::error(0xff80, 0xffffff80));
         }
         self.cycle += 36;
     }
@@ -811,7 +964,7 @@ impl<'a> CpuProcess<'a> {
             self.memory.write_word(address, exchange);
         }
         self.set_register(reg2, value);
-        self.cycle += 26;
+        self.cycle += 26 + self.memory.wait_cycles(address, 4) * 2;
     }
 
     fn halt(&mut self) {
@@ -827,10 +980,25 @@ impl<'a> CpuProcess<'a> {
         }
         match reg_id {
             4 | 6..=23 | 26..=28 | 30 => (),
+            CHCW => self.control_cache(value),
             id => self.sys_registers[id] = value,
         }
         self.cycle += 8;
     }
+    // Writing CHCW can clear, dump or restore the cache, but only the enable bit sticks
+    fn control_cache(&mut self, value: u32) {
+        let address = (value & CACHE_SPILL_ADDRESS) as usize;
+        if value & CACHE_DUMP_FLAG != 0 {
+            self.cache.dump(&mut self.memory, address);
+        } else if value & CACHE_RESTORE_FLAG != 0 {
+            self.cache.restore(&self.memory, address);
+        } else if value & CACHE_CLEAR_FLAG != 0 {
+            let first = (value >> 20) as usize;
+            let count = ((value >> 8) & 0xfff) as usize;
+            self.cache.clear(first, count);
+        }
+        self.sys_registers[CHCW] = value & CACHE_ENABLE_FLAG;
+    }
     fn stsr(&mut self, instr: u16) {
         let (reg2, reg_id) = self.parse_format_ii_opcode(instr);
         let reg_id = (reg_id & 0x1f) as usize;
@@ -1311,8 +1479,10 @@ impl<'a> CpuProcess<'a> {
 #[cfg(test)]
 #[rustfmt::skip]
 mod tests {
-    use crate::emulator::cpu::{Cpu, PSW, CARRY_FLAG, SIGN_FLAG, OVERFLOW_FLAG, ZERO_FLAG, Exception, EX_PENDING_FLAG, INTERRUPT_DISABLE_FLAG, EIPC, EIPSW, NMI_PENDING_FLAG, EventHandler, Event, ECR, FEPC, FEPSW, FLOAT_ZERO_DIV_FLAG, FLOAT_INVALID_FLAG, FLOAT_RESERVED_OP_FLAG, FLOAT_OVERFLOW_FLAG};
+    use crate::emulator::cpu::{Cpu, PSW, CHCW, CACHE_ENABLE_FLAG, CARRY_FLAG, SIGN_FLAG, OVERFLOW_FLAG, ZERO_FLAG, Exception, EX_PENDING_FLAG, INTERRUPT_DISABLE_FLAG, EIPC, EIPSW, NMI_PENDING_FLAG, EventHandler, Event, ECR, FEPC, FEPSW, FLOAT_ZERO_DIV_FLAG, FLOAT_INVALID_FLAG, FLOAT_RESERVED_OP_FLAG, FLOAT_OVERFLOW_FLAG};
+    use crate::emulator::debug::{Access, Breakpoint, DebugHit, Watchpoint};
     use crate::emulator::memory::Memory;
+    use crate::emulator::trace::{Register, TraceEntry, Tracer};
     use anyhow::Result;
     use std::cell::RefCell;
     use std::rc::Rc;
@@ -1443,6 +1613,17 @@ mod tests {
         (cpu, memory)
     }
 
+    // Runs its code from a warm instruction cache, so only the instructions themselves take time
+    fn warm_rom(instructions: Vec<Vec<u8>>) -> (Cpu<NoopEventHandler>, Rc<RefCell<Memory>>) {
+        let size: usize = instructions.iter().map(|instr| instr.len()).sum();
+        let (mut cpu, memory) = rom(instructions);
+        cpu.sys_registers[CHCW] = CACHE_ENABLE_FLAG;
+        for address in (cpu.pc..cpu.pc + size).step_by(2) {
+            cpu.cache.fetch(&memory.borrow(), address);
+        }
+        (cpu, memory)
+    }
+
     fn add_interrupt_handler(memory: &mut Memory, mut address: usize, instructions: Vec<Vec<u8>>) {
         for instr in instructions {
             for byte in instr {
@@ -1474,7 +1655,7 @@ mod tests {
         cpu.run(1).unwrap();
         assert_eq!(cpu.pc, 0x07000004);
         assert_eq!(cpu.registers[31], 0x07000000);
-        cpu.run(2).unwrap();
+        cpu.run(10).unwrap();
         assert_eq!(cpu.registers[31], 0x07000420);
     }
 
@@ -1507,7 +1688,7 @@ mod tests {
             movea(31, 31, 0x0420),
             jmp(31),
         ]);
-        cpu.run(5).unwrap();
+        cpu.run(15).unwrap();
         assert_eq!(cpu.pc, 0x07000420);
     }
 
@@ -1519,7 +1700,7 @@ mod tests {
             ld_b(31, 30, 16),
         ]);
         memory.borrow_mut().write_byte(0x07000052, 69);
-        cpu.run(7).unwrap();
+        cpu.run(19).unwrap();
         assert_eq!(cpu.registers[31], 69);
     }
 
@@ -1531,7 +1712,7 @@ mod tests {
             ld_b(31, 30, -16),
         ]);
         memory.borrow_mut().write_byte(0x07000032, 0xfe);
-        cpu.run(7).unwrap();
+        cpu.run(19).unwrap();
         assert_eq!(cpu.registers[31] as i32, -2);
     }
 
@@ -1543,7 +1724,7 @@ mod tests {
             in_b(31, 30, -16),
         ]);
         memory.borrow_mut().write_byte(0x07000032, 0xfe);
-        cpu.run(7).unwrap();
+        cpu.run(19).unwrap();
         assert_eq!(cpu.registers[31], 0x000000fe);
     }
 
@@ -1555,7 +1736,7 @@ mod tests {
             ld_w(12, 10, 2),
         ]);
         memory.borrow_mut().write_word(0x05000000, 0x12345678);
-        cpu.run(11).unwrap();
+        cpu.run(23).unwrap();
         assert_eq!(cpu.registers[11], 0x5678);
         assert_eq!(cpu.registers[12], 0x12345678);
     }
@@ -1568,7 +1749,7 @@ mod tests {
             movea(31, 0, 0x0069),
             st_b(31, 30, 16),
         ]);
-        cpu.run(7).unwrap();
+        cpu.run(23).unwrap();
         assert_eq!(memory.borrow().read_byte(0x07000052), 0x69);
     }
 
@@ -1580,7 +1761,7 @@ mod tests {
             movea(31, 0, -2i16 as u16),
             st_b(31, 30, -16),
         ]);
-        cpu.run(7).unwrap();
+        cpu.run(23).unwrap();
         assert_eq!(memory.borrow().read_byte(0x07000032) as i8, -2);
     }
 
@@ -1594,7 +1775,7 @@ mod tests {
             st_w(11, 10, 10),
         ]);
         memory.borrow_mut().write_word(0x05000000, 0x12345678);
-        cpu.run(13).unwrap();
+        cpu.run(33).unwrap();
         assert_eq!(memory.borrow().read_halfword(0x05000000), 0x5678);
         assert_eq!(memory.borrow().read_word(0x05000008), 0x12345678);
     }
@@ -1607,7 +1788,7 @@ mod tests {
             movea(31, 0, 257),
             st_b(31, 30, 16),
         ]);
-        cpu.run(7).unwrap();
+        cpu.run(23).unwrap();
         assert_eq!(memory.borrow().read_byte(0x07000052), 1);
     }
 
@@ -1617,7 +1798,7 @@ mod tests {
             movea(29, 0, 4),
             addi(31, 29, 5)
         ]);
-        cpu.run(2).unwrap();
+        cpu.run(10).unwrap();
         assert_eq!(cpu.registers[31], 9);
         assert_eq!(cpu.sys_registers[PSW] & 0xf, 0);
     }
@@ -1633,7 +1814,7 @@ mod tests {
             // i32::MAX + 1 == i32.min
             addi(31, 29, 1),
         ]);
-        cpu.run(4).unwrap();
+        cpu.run(18).unwrap();
         assert_eq!(cpu.registers[29] as i32, i32::MAX);
         assert_eq!(cpu.registers[31] as i32, i32::MIN);
         assert_eq!(cpu.sys_registers[PSW] & 0xf, OVERFLOW_FLAG | SIGN_FLAG);
@@ -1645,7 +1826,7 @@ mod tests {
             movea(29, 0, 0xffff),
             addi(31, 29, 1)
         ]);
-        cpu.run(2).unwrap();
+        cpu.run(10).unwrap();
         assert_eq!(cpu.registers[31], 0);
         assert_eq!(cpu.sys_registers[PSW] & 0xf, ZERO_FLAG | CARRY_FLAG);
     }
@@ -1657,7 +1838,7 @@ mod tests {
             movea(30, 0, 5),
             sub(31,30),
         ]);
-        cpu.run(3).unwrap();
+        cpu.run(13).unwrap();
         assert_eq!(cpu.registers[31] as i32, -1);
         assert_eq!(cpu.sys_registers[PSW] & 0xf, CARRY_FLAG | SIGN_FLAG);
     }
@@ -1672,7 +1853,7 @@ mod tests {
             // i32::MIN - 1 == i32.MAX
             sub(31, 30),
         ]);
-        cpu.run(3).unwrap();
+        cpu.run(13).unwrap();
         assert_eq!(cpu.registers[31] as i32, i32::MAX);
         assert_eq!(cpu.sys_registers[PSW] & 0xf, OVERFLOW_FLAG);
     }
@@ -1683,7 +1864,7 @@ mod tests {
             movea(29, 0, 1),
             sub(31, 29),
         ]);
-        cpu.run(2).unwrap();
+        cpu.run(8).unwrap();
         assert_eq!(cpu.registers[31], u32::MAX);
         assert_eq!(cpu.sys_registers[PSW] & 0xf, SIGN_FLAG | CARRY_FLAG);
     }
@@ -1695,7 +1876,7 @@ mod tests {
             movea(30, 0, 5),
             cmp_r(31,30),
         ]);
-        cpu.run(3).unwrap();
+        cpu.run(13).unwrap();
         assert_eq!(cpu.registers[31], 4);
         assert_eq!(cpu.sys_registers[PSW] & 0xf, CARRY_FLAG | SIGN_FLAG);
     }
@@ -1709,11 +1890,11 @@ mod tests {
             mulu(11, 10),
             mul(12, 11),
         ]);
-        cpu.run(16).unwrap();
+        cpu.run(30).unwrap();
         assert_eq!(cpu.registers[11], 18);
         assert_eq!(cpu.registers[30], 0);
 
-        cpu.run(29).unwrap();
+        cpu.run(45).unwrap();
         assert_eq!(cpu.registers[12] as i32, -72);
         assert_eq!(cpu.registers[30] as i32, -1);
     }
@@ -1740,8 +1921,8 @@ mod tests {
     fn errors_on_divide_by_zero() {
         let (mut cpu, memory) = rom(vec![
             ldsr(0, PSW), // clear PSW to clear the NMI_PENDING flag
-            movea(10, 0, 1 as u16),
-            movea(11, 0, 0 as u16),
+            movea(10, 0, 1),
+            movea(11, 0, 0),
             div(10, 11),
             divu(10, 11),
             movea(13, 0, 5),
@@ -1759,17 +1940,17 @@ mod tests {
         ]);
 
         // let the first divide-by-0 error
-        cpu.run(76).unwrap();
+        cpu.run(98).unwrap();
         assert_eq!(cpu.pc, 0x0700000c);
         assert_eq!(cpu.registers[13], 1);
 
         // let the second divide-by-0 error
-        cpu.run(140).unwrap();
+        cpu.run(174).unwrap();
         assert_eq!(cpu.pc, 0x0700000e);
         assert_eq!(cpu.registers[13], 2);
 
         // ensure normal execution has resumed
-        cpu.run(141).unwrap();
+        cpu.run(179).unwrap();
         assert_eq!(cpu.pc, 0x07000012);
         assert_eq!(cpu.registers[13], 5);
     }
@@ -1784,7 +1965,7 @@ mod tests {
             movea(1, 0, 1),
             movea(2, 0, 1),
         ]);
-        cpu.run(7).unwrap();
+        cpu.run(23).unwrap();
         assert_eq!(cpu.registers[1], 0);
         assert_eq!(cpu.registers[2], 1);
     }
@@ -1799,7 +1980,7 @@ mod tests {
             movea(1, 0, 1),
             movea(2, 0, 1),
         ]);
-        cpu.run(5).unwrap();
+        cpu.run(21).unwrap();
         assert_eq!(cpu.registers[1], 1);
         assert_eq!(cpu.registers[2], 0);
     }
@@ -1812,7 +1993,7 @@ mod tests {
             cmp_r(31, 30),
             setf(1, 6),
         ]);
-        cpu.run(4).unwrap();
+        cpu.run(16).unwrap();
         assert_eq!(cpu.registers[1], 1);
     }
 
@@ -1853,7 +2034,7 @@ mod tests {
             movhi(31, 0, 0x8000),
             shl_i(31, 1),
         ]);
-        cpu.run(2).unwrap();
+        cpu.run(8).unwrap();
         assert_eq!(cpu.registers[31], 0);
         assert_eq!(cpu.sys_registers[PSW] & 0xf, CARRY_FLAG | ZERO_FLAG);
     }
@@ -1864,7 +2045,7 @@ mod tests {
             movhi(31, 0, 0x8000),
             shr_i(31, 1),
         ]);
-        cpu.run(2).unwrap();
+        cpu.run(8).unwrap();
         assert_eq!(cpu.registers[31], 0x40000000);
         assert_eq!(cpu.sys_registers[PSW] & 0xf, 0);
     }
@@ -1875,7 +2056,7 @@ mod tests {
             movhi(31, 0, 0x8000),
             sar_i(31, 1),
         ]);
-        cpu.run(2).unwrap();
+        cpu.run(8).unwrap();
         assert_eq!(cpu.registers[31], 0xc0000000);
         assert_eq!(cpu.sys_registers[PSW] & 0xf, SIGN_FLAG);
     }
@@ -1897,7 +2078,7 @@ mod tests {
             mov_r(27, 29),
             xor(27, 30),
         ]);
-        cpu.run(9).unwrap();
+        cpu.run(29).unwrap();
         assert_eq!(cpu.registers[30], 0xfffff0f0);
         assert_eq!(cpu.registers[29], 0xffffffff);
         assert_eq!(cpu.registers[28], 0x0f0f);
@@ -1910,7 +2091,7 @@ mod tests {
             movea(10, 0, 0x1082),
             andi(11, 10, 0xffff),
         ]);
-        cpu.run(2).unwrap();
+        cpu.run(10).unwrap();
         assert_eq!(cpu.registers[11], 0x1082);
     }
 
@@ -1920,7 +2101,7 @@ mod tests {
             movea(10, 0, 0x1082),
             ori(11, 10, 0xffff),
         ]);
-        cpu.run(2).unwrap();
+        cpu.run(10).unwrap();
         assert_eq!(cpu.registers[11], 0xffff);
     }
 
@@ -1930,7 +2111,7 @@ mod tests {
             movea(10, 0, 0x1082),
             xori(11, 10, 0xffff),
         ]);
-        cpu.run(2).unwrap();
+        cpu.run(10).unwrap();
         assert_eq!(cpu.registers[11], 0xef7d);
     }
 
@@ -1946,6 +2127,109 @@ mod tests {
         assert_eq!(cpu.registers[30], 0x00000040);
     }
 
+    fn step(cpu: &mut Cpu<NoopEventHandler>) -> u64 {
+        let start = cpu.cycle;
+        cpu.run(start + 1).unwrap();
+        cpu.cycle - start
+    }
+
+    #[test]
+    fn instruction_cache_hits_skip_the_fill() {
+        let (mut cpu, _memory) = rom(vec![
+            movea(31, 0, 0x0002),
+            ldsr(31, CHCW),
+            add_i(10, 1),
+            bcond(5, -2),
+        ]);
+        // Until the cache is on, every halfword fetched from ROM waits on the bus
+        assert_eq!(step(&mut cpu), 1 + 4);
+        assert_eq!(step(&mut cpu), 8 + 2);
+        assert_eq!(cpu.sys_registers[CHCW], 0x00000002);
+
+        // The first time around, every subblock has to be filled
+        assert_eq!(step(&mut cpu), 1 + 4);
+        assert_eq!(step(&mut cpu), 3 + 4);
+        assert_eq!(step(&mut cpu), 1);
+        assert_eq!(step(&mut cpu), 3);
+        assert_eq!(cpu.registers[10], 2);
+    }
+
+    #[test]
+    fn chcw_dumps_and_clears_the_instruction_cache() {
+        let (mut cpu, memory) = rom(vec![
+            movea(31, 0, 0x0002),
+            ldsr(31, CHCW),
+            movhi(30, 0, 0x0500),
+            movea(30, 30, 0x0012),
+            ldsr(30, CHCW),
+            movhi(29, 0, 0x0030),
+            movea(29, 29, 0x0103),
+            ldsr(29, CHCW),
+            add_i(10, 1),
+        ]);
+        for _ in 0..5 {
+            step(&mut cpu);
+        }
+        {
+            // The dump went to 0x05000000, with the movhi at 0x07000006 in entry 0
+            let memory = memory.borrow();
+            assert_eq!(memory.read_halfword(0x05000006), 0xbfc0);
+            assert_eq!(memory.read_halfword(0x05000008), 0x0500);
+            assert_eq!(memory.read_word(0x05000400 + 4), 0x07000000 | 0x3);
+        }
+        assert_eq!(cpu.sys_registers[CHCW], 0x00000002);
+
+        // Clearing entry 3 means the add has to be fetched again
+        for _ in 0..3 {
+            step(&mut cpu);
+        }
+        assert_eq!(step(&mut cpu), 1 + 4);
+        assert_eq!(cpu.registers[10], 1);
+    }
+
+    #[test]
+    fn cached_loops_run_faster_than_uncached_loops() {
+        let run_loop = |chcw: u16| {
+            let (mut cpu, _memory) = rom(vec![
+                movea(31, 0, chcw),
+                ldsr(31, CHCW),
+                movea(10, 0, 100),
+                add_i(10, 0x1f),
+                bcond(10, -2),
+            ]);
+            let cycles: u64 = (0..3 + 2 * 100).map(|_| step(&mut cpu)).sum();
+            assert_eq!(cpu.registers[10], 0);
+            assert_eq!(cpu.pc, 0x0700000e);
+            cycles
+        };
+        let uncached = run_loop(0x0000);
+        let cached = run_loop(0x0002);
+        assert!(cached < uncached, "{} cycles cached, {} uncached", cached, uncached);
+    }
+
+    #[test]
+    fn loads_from_rom_wait_longer_than_loads_from_wram() {
+        let (mut cpu, memory) = warm_rom(vec![
+            movhi(10, 0, 0x0700),
+            movhi(11, 0, 0x0500),
+            ld_w(12, 10, 0),
+            ld_w(12, 11, 0),
+            ld_w(12, 10, 0),
+            ld_h(12, 10, 0),
+        ]);
+        step(&mut cpu);
+        step(&mut cpu);
+        // Two trips over the 16-bit bus, with two wait states each by default
+        assert_eq!(step(&mut cpu), 5 + 4);
+        assert_eq!(step(&mut cpu), 5);
+
+        // WCR can speed the ROM up to one wait state
+        memory.borrow_mut().write_byte(0x02000024, 0x01);
+        memory.borrow_mut().update_wait_control();
+        assert_eq!(step(&mut cpu), 5 + 2);
+        assert_eq!(step(&mut cpu), 5 + 1);
+    }
+
     #[test]
     fn can_run_bitstring_operations() {
         let (mut cpu, memory) = rom(vec![
@@ -1968,9 +2252,10 @@ mod tests {
             orbsu(),
         ]);
 
-        let setup_cycles = 28;
-        let first_cycle = setup_cycles + 49;
-        let final_cycle = first_cycle + 12;
+        // Every fetch from ROM waits two cycles per halfword
+        let setup_cycles = 28 + 30 * 2;
+        let first_cycle = setup_cycles + 2 + 49;
+        let final_cycle = first_cycle + 2 + 12;
         let bitstring_op_pc = 0x0700003c;
 
         cpu.run(setup_cycles).unwrap();
@@ -2019,9 +2304,9 @@ mod tests {
             sch0bsd(),
         ]);
 
-        let setup_cycles = 15;
-        let first_cycle = setup_cycles + 51;
-        let final_cycle = first_cycle + 3;
+        let setup_cycles = 15 + 17 * 2;
+        let first_cycle = setup_cycles + 2 + 51;
+        let final_cycle = first_cycle + 2 + 3;
         let bitstring_op_pc = 0x07000022;
 
         cpu.run(setup_cycles).unwrap();
@@ -2066,9 +2351,9 @@ mod tests {
             sch1bsu(),
         ]);
 
-        let setup_cycles = 13;
-        let first_cycle = setup_cycles + 51;
-        let final_cycle = first_cycle + 3;
+        let setup_cycles = 13 + 14 * 2;
+        let first_cycle = setup_cycles + 2 + 51;
+        let final_cycle = first_cycle + 2 + 3;
         let bitstring_op_pc = 0x0700001c;
 
         cpu.run(setup_cycles).unwrap();
@@ -2145,7 +2430,7 @@ mod tests {
             divf_s(12, 11),
             cvt_sw(14, 12),
         ]);
-        cpu.run(134).unwrap();
+        cpu.run(166).unwrap();
         assert_eq!(f32::from_bits(cpu.registers[11]), 3.0);
         assert_eq!(f32::from_bits(cpu.registers[12]), 17.0 / 3.0);
         assert_eq!(cpu.registers[13], 17);
@@ -2268,7 +2553,7 @@ mod tests {
             xb(11),
             xh(12),
         ]);
-        cpu.run(33).unwrap();
+        cpu.run(57).unwrap();
         assert_eq!(cpu.registers[10], 0x1e6a2c48);
         assert_eq!(cpu.registers[11], 0x12347856);
         assert_eq!(cpu.registers[12], 0x56781234);
@@ -2298,9 +2583,9 @@ mod tests {
         assert_eq!(cpu.registers[31], 2);
 
         // Run another 10 cycles for RETI
-        cpu.run(11).unwrap();
+        cpu.run(17).unwrap();
         assert_eq!(cpu.pc, 0x07000000);
-        cpu.run(12).unwrap();
+        cpu.run(22).unwrap();
         assert_eq!(cpu.registers[31], 1);
     }
 
@@ -2324,12 +2609,12 @@ mod tests {
 
         cpu.sys_registers[PSW] = EX_PENDING_FLAG;
         cpu.raise_exception(interrupt);
-        cpu.run(2).unwrap();
+        cpu.run(10).unwrap();
         assert_eq!(cpu.registers[31], 2);
 
         cpu.sys_registers[PSW] = NMI_PENDING_FLAG;
         cpu.raise_exception(interrupt);
-        cpu.run(3).unwrap();
+        cpu.run(15).unwrap();
         assert_eq!(cpu.registers[31], 3);
     }
 
@@ -2374,11 +2659,11 @@ mod tests {
             reti(),
         ]);
 
-        cpu.run(36).unwrap();
+        cpu.run(54).unwrap();
         assert_eq!(cpu.registers[31], 1);
         assert_eq!(cpu.registers[30], 0);
 
-        cpu.run(37).unwrap();
+        cpu.run(59).unwrap();
         assert_eq!(cpu.registers[30], 2);
     }
 
@@ -2396,17 +2681,17 @@ mod tests {
             reti(),
         ]);
 
-        cpu.run(23).unwrap();
+        cpu.run(27).unwrap();
         // Assert we're in the interrupt handler
         assert_eq!(cpu.pc, 0xffffffb0);
         assert_eq!(cpu.sys_registers[ECR], 0x0000ffb3);
 
-        cpu.run(34).unwrap();
+        cpu.run(44).unwrap();
         // Assert we're back in normal code
         assert_eq!(cpu.pc, 0x07000004);
         assert_eq!(cpu.registers[31], 1);
 
-        cpu.run(35).unwrap();
+        cpu.run(49).unwrap();
         assert_eq!(cpu.pc, 0x07000008);
         assert_eq!(cpu.registers[30], 2);
     }
@@ -2447,11 +2732,11 @@ mod tests {
 
         cpu.raise_exception(Exception::interrupt(0xfe40, 4));
 
-        let res = cpu.run(61).unwrap();
-        assert_eq!(res.cycle, 61);
+        let res = cpu.run(67).unwrap();
+        assert_eq!(res.cycle, 67);
         assert_eq!(cpu.registers[31], 2);
 
-        cpu.run(62).unwrap();
+        cpu.run(72).unwrap();
         assert_eq!(cpu.registers[31], 3);
     }
 
@@ -2490,4 +2775,81 @@ mod tests {
         assert_eq!(memory.borrow().read_word(0x00000004), cpu.sys_registers[PSW]);
         assert_eq!(memory.borrow().read_word(0x00000008), cpu.pc as u32);
     }
+
+    #[test]
+    fn traces_instructions_and_exceptions() {
+        let (mut cpu, _memory) = rom(vec![
+            ldsr(0, PSW), // clear PSW to clear the NMI_PENDING flag
+            movea(30, 0, 2),
+            trap(0x13),
+        ]);
+        cpu.replace_tracer(Some(Tracer::new(16)));
+        cpu.run(22).unwrap();
+
+        let entries: Vec<&TraceEntry> = cpu.tracer().unwrap().entries().collect();
+        assert_eq!(entries.len(), 4);
+        match entries[1] {
+            TraceEntry::Instruction { pc, opcode, changes, .. } => {
+                assert_eq!(*pc, 0x07000002);
+                assert_eq!(*opcode, [0xa3c0, 0x0002]);
+                assert_eq!(changes, &[(Register::General(30), 2)]);
+            }
+            entry => panic!("Unexpected entry {}", entry),
+        }
+        match entries[3] {
+            TraceEntry::Exception { pc, code, handler, interrupt_level, .. } => {
+                assert_eq!(*pc, 0x07000008);
+                assert_eq!(*code, 0xffb3);
+                assert_eq!(*handler, 0xffffffb0);
+                assert_eq!(*interrupt_level, None);
+            }
+            entry => panic!("Unexpected entry {}", entry),
+        }
+    }
+
+    #[test]
+    fn stops_at_breakpoints() {
+        let (mut cpu, _memory) = rom(vec![
+            movea(1, 0, 1),
+            movea(2, 0, 2),
+            movea(3, 0, 3),
+        ]);
+        cpu.breakpoints_mut().push(Breakpoint::at(0x07000004));
+        // This one's condition never holds
+        cpu.breakpoints_mut().push(Breakpoint::at(0x07000008).when_register_equals(2, 5));
+
+        let result = cpu.run(100).unwrap();
+        assert_eq!(result.debug_hit, Some(DebugHit::Breakpoint { pc: 0x07000004 }));
+        assert!(result.cycle < 100);
+        assert_eq!(cpu.pc, 0x07000004);
+        assert_eq!(cpu.registers[1], 1);
+        assert_eq!(cpu.registers[2], 0);
+
+        // Resuming runs the instruction we stopped at
+        let result = cpu.run(100).unwrap();
+        assert_eq!(result.debug_hit, None);
+        assert_eq!(cpu.registers[2], 2);
+        assert_eq!(cpu.registers[3], 3);
+    }
+
+    #[test]
+    fn stops_after_watched_accesses() {
+        let (mut cpu, memory) = rom(vec![
+            movhi(2, 0, 0x0500),
+            movea(1, 0, 7),
+            st_w(1, 2, 4),
+            ld_w(3, 2, 4),
+        ]);
+        memory.borrow_mut().watchpoints_mut().push(Watchpoint::writes(0x05000004..0x05000008));
+        let result = cpu.run(100).unwrap();
+        assert_eq!(result.debug_hit, Some(DebugHit::Watchpoint { pc: 0x07000008, address: 0x05000004, access: Access::Write, value: 7 }));
+        assert_eq!(cpu.pc, 0x0700000c);
+
+        // Instruction fetches don't count as reads
+        memory.borrow_mut().watchpoints_mut().push(Watchpoint::reads(0x07000000..0x07000100));
+        memory.borrow_mut().watchpoints_mut().push(Watchpoint::reads(0x05000006..0x05000007).when_value_equals(7));
+        let result = cpu.run(100).unwrap();
+        assert_eq!(result.debug_hit, Some(DebugHit::Watchpoint { pc: 0x0700000c, address: 0x05000004, access: Access::Read, value: 7 }));
+        assert_eq!(cpu.registers[3], 7);
+    }
 }
@@ -0,0 +1,147 @@
+use crate::emulator::memory::Memory;
+use serde_derive::{Deserialize, Serialize};
+
+// 128 entries of 8 bytes, each split into two 4-byte subblocks which fill separately
+pub const ENTRIES: usize = 128;
+const ENTRY_SIZE: usize = 8;
+const SUBBLOCK_SIZE: usize = 4;
+// Where the tags start in a dump, right after the instruction data
+const DUMP_TAG_OFFSET: usize = ENTRIES * ENTRY_SIZE;
+
+// A fill reads a whole subblock, which is two trips over the 16-bit bus
+pub fn fill_cycles(memory: &Memory, address: usize) -> u64 {
+    memory.wait_cycles(address & !(SUBBLOCK_SIZE - 1), SUBBLOCK_SIZE)
+}
+
+// The V810's direct-mapped 1KB instruction cache. Only instruction fetches go through it,
+// so code which writes over itself has to clear the cache before running the new code.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InstructionCache {
+    // Bits 31-10 of the address each entry holds
+    tags: Vec<u32>,
+    // One bit per subblock
+    valid: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    data: Vec<u8>,
+}
+impl Default for InstructionCache {
+    fn default() -> Self {
+        Self {
+            tags: vec![0; ENTRIES],
+            valid: vec![0; ENTRIES],
+            data: vec![0; ENTRIES * ENTRY_SIZE],
+        }
+    }
+}
+impl InstructionCache {
+    // Returns the halfword, and whether it was already cached
+    pub fn fetch(&mut self, memory: &Memory, address: usize) -> (u16, bool) {
+        let entry = (address / ENTRY_SIZE) % ENTRIES;
+        let subblock = (address / SUBBLOCK_SIZE) & 1;
+        let tag = (address >> 10) as u32;
+        let hit = self.tags[entry] == tag && self.valid[entry] & (1 << subblock) != 0;
+        if !hit {
+            if self.tags[entry] != tag {
+                self.tags[entry] = tag;
+                self.valid[entry] = 0;
+            }
+            let start = address & !(SUBBLOCK_SIZE - 1);
+            let offset = entry * ENTRY_SIZE + subblock * SUBBLOCK_SIZE;
+            for index in 0..SUBBLOCK_SIZE / 2 {
+                let halfword = memory.fetch_halfword(start + index * 2);
+                self.data[offset + index * 2..offset + index * 2 + 2]
+                    .copy_from_slice(&halfword.to_le_bytes());
+            }
+            self.valid[entry] |= 1 << subblock;
+        }
+        let offset = entry * ENTRY_SIZE + (address & (ENTRY_SIZE - 2));
+        let halfword = u16::from_le_bytes([self.data[offset], self.data[offset + 1]]);
+        (halfword, hit)
+    }
+
+    // Invalidates count entries, starting from first
+    pub fn clear(&mut self, first: usize, count: usize) {
+        let end = (first + count).min(ENTRIES);
+        for entry in first.min(end)..end {
+            self.valid[entry] = 0;
+        }
+    }
+
+    // Writes all 1KB of instructions, then a word per entry with its tag in bits 31-10
+    // and its subblocks' valid bits in bits 1-0
+    pub fn dump(&self, memory: &mut Memory, address: usize) {
+        for (index, byte) in self.data.iter().enumerate() {
+            memory.write_byte(address + index, *byte);
+        }
+        for entry in 0..ENTRIES {
+            let word = (self.tags[entry] << 10) | self.valid[entry] as u32;
+            memory.write_word(address + DUMP_TAG_OFFSET + entry * 4, word);
+        }
+    }
+
+    // The reverse of dump
+    pub fn restore(&mut self, memory: &Memory, address: usize) {
+        for (index, byte) in self.data.iter_mut().enumerate() {
+            *byte = memory.read_byte(address + index);
+        }
+        for entry in 0..ENTRIES {
+            let word = memory.read_word(address + DUMP_TAG_OFFSET + entry * 4);
+            self.tags[entry] = word >> 10;
+            self.valid[entry] = (word & 0x3) as u8;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::cpu::cache::InstructionCache;
+    use crate::emulator::memory::Memory;
+
+    fn memory_with_code() -> Memory {
+        let mut memory = Memory::new();
+        for index in 0..8 {
+            memory.write_halfword(0x05000000 + index * 2, 0x1000 + index as u16);
+        }
+        memory
+    }
+
+    #[test]
+    fn fills_a_subblock_at_a_time() {
+        let mut memory = memory_with_code();
+        let mut cache = InstructionCache::default();
+        assert_eq!(cache.fetch(&memory, 0x05000002), (0x1001, false));
+        assert_eq!(cache.fetch(&memory, 0x05000000), (0x1000, true));
+        assert_eq!(cache.fetch(&memory, 0x05000004), (0x1002, false));
+
+        // Memory can change under the cache without it noticing
+        memory.write_halfword(0x05000000, 0xffff);
+        assert_eq!(cache.fetch(&memory, 0x05000000), (0x1000, true));
+        cache.clear(0, 1);
+        assert_eq!(cache.fetch(&memory, 0x05000000), (0xffff, false));
+    }
+
+    #[test]
+    fn evicts_entries_with_the_same_index() {
+        let memory = memory_with_code();
+        let mut cache = InstructionCache::default();
+        cache.fetch(&memory, 0x05000000);
+        assert_eq!(cache.fetch(&memory, 0x05000400), (0, false));
+        assert_eq!(cache.fetch(&memory, 0x05000000), (0x1000, false));
+    }
+
+    #[test]
+    fn dumps_and_restores() {
+        let mut memory = memory_with_code();
+        let mut cache = InstructionCache::default();
+        cache.fetch(&memory, 0x05000006);
+        cache.dump(&mut memory, 0x05001000);
+        assert_eq!(memory.read_halfword(0x05001006), 0x1003);
+        assert_eq!(memory.read_word(0x05001400), 0x05000000 | 0x2);
+
+        let mut restored = InstructionCache::default();
+        restored.restore(&memory, 0x05001000);
+        assert_eq!(restored.fetch(&memory, 0x05000004), (0x1002, true));
+        assert_eq!(restored.fetch(&memory, 0x05000000), (0x1000, false));
+    }
+}
@@ -0,0 +1,127 @@
+use std::ops::Range;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+// Stops the CPU before it runs the instruction at an address
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub address: usize,
+    // Only stop if this general purpose register holds this value
+    pub register_equals: Option<(usize, u32)>,
+}
+impl Breakpoint {
+    pub fn at(address: usize) -> Self {
+        Self {
+            address,
+            register_equals: None,
+        }
+    }
+
+    pub fn when_register_equals(mut self, register: usize, value: u32) -> Self {
+        self.register_equals = Some((register, value));
+        self
+    }
+
+    pub fn matches(&self, pc: usize, registers: &[u32; 32]) -> bool {
+        self.address == pc
+            && self
+                .register_equals
+                .is_none_or(|(register, value)| registers[register] == value)
+    }
+}
+
+// Stops the CPU after an instruction reads or writes anywhere in a range of addresses.
+// Instruction fetches don't count as reads.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub range: Range<usize>,
+    pub read: bool,
+    pub write: bool,
+    // Only stop if this value was read or written
+    pub value_equals: Option<u32>,
+}
+impl Watchpoint {
+    pub fn reads(range: Range<usize>) -> Self {
+        Self::new(range, true, false)
+    }
+
+    pub fn writes(range: Range<usize>) -> Self {
+        Self::new(range, false, true)
+    }
+
+    pub fn accesses(range: Range<usize>) -> Self {
+        Self::new(range, true, true)
+    }
+
+    fn new(range: Range<usize>, read: bool, write: bool) -> Self {
+        Self {
+            range,
+            read,
+            write,
+            value_equals: None,
+        }
+    }
+
+    pub fn when_value_equals(mut self, value: u32) -> Self {
+        self.value_equals = Some(value);
+        self
+    }
+
+    pub fn matches(&self, address: usize, size: usize, access: Access, value: u32) -> bool {
+        let watching = match access {
+            Access::Read => self.read,
+            Access::Write => self.write,
+        };
+        watching
+            && address < self.range.end
+            && self.range.start < address + size
+            && self.value_equals.is_none_or(|expected| expected == value)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DebugHit {
+    // The CPU stopped before running the instruction at pc
+    Breakpoint {
+        pc: usize,
+    },
+    // The instruction at pc accessed memory. The CPU stopped right after it.
+    Watchpoint {
+        pc: usize,
+        address: usize,
+        access: Access,
+        value: u32,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::debug::{Access, Breakpoint, Watchpoint};
+
+    #[test]
+    fn breakpoints_can_check_registers() {
+        let mut registers = [0; 32];
+        let breakpoint = Breakpoint::at(0x07000010).when_register_equals(10, 3);
+        assert!(!breakpoint.matches(0x07000010, &registers));
+        registers[10] = 3;
+        assert!(breakpoint.matches(0x07000010, &registers));
+        assert!(!breakpoint.matches(0x07000012, &registers));
+    }
+
+    #[test]
+    fn watchpoints_match_overlapping_accesses() {
+        let watchpoint = Watchpoint::writes(0x05000010..0x05000014);
+        assert!(watchpoint.matches(0x0500000c, 8, Access::Write, 0));
+        assert!(watchpoint.matches(0x05000013, 1, Access::Write, 0));
+        assert!(!watchpoint.matches(0x05000014, 4, Access::Write, 0));
+        assert!(!watchpoint.matches(0x05000010, 4, Access::Read, 0));
+
+        let watchpoint = Watchpoint::accesses(0x05000010..0x05000014).when_value_equals(7);
+        assert!(watchpoint.matches(0x05000010, 4, Access::Read, 7));
+        assert!(!watchpoint.matches(0x05000010, 4, Access::Write, 8));
+    }
+}
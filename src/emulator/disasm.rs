@@ -0,0 +1,312 @@
+use std::fmt;
+
+use super::memory::Memory;
+
+const CONDITIONS: [&str; 16] = [
+    "v", "c", "z", "nh", "n", "t", "lt", "le", "nv", "nc", "nz", "h", "p", "f", "ge", "gt",
+];
+
+pub const SYS_REGISTERS: [&str; 32] = [
+    "eipc", "eipsw", "fepc", "fepsw", "ecr", "psw", "pir", "tkcw", "sr8", "sr9", "sr10", "sr11",
+    "sr12", "sr13", "sr14", "sr15", "sr16", "sr17", "sr18", "sr19", "sr20", "sr21", "sr22", "sr23",
+    "chcw", "adtre", "sr26", "sr27", "sr28", "sr29", "sr30", "sr31",
+];
+
+const BITSTRING_OPS: [&str; 16] = [
+    "sch0bsu", "sch0bsd", "sch1bsu", "sch1bsd", "", "", "", "", "orbsu", "andbsu", "xorbsu",
+    "movbsu", "ornbsu", "andnbsu", "xornbsu", "notbsu",
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Instruction {
+    pub address: usize,
+    // In bytes, so the next instruction is at address + size
+    pub size: usize,
+    pub text: String,
+}
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:08x}  {}", self.address, self.text)
+    }
+}
+
+// Disassembles code which was loaded at the given address.
+// Anything which isn't a valid instruction comes out as data.
+pub fn disassemble(bytes: &[u8], address: usize) -> Vec<Instruction> {
+    let mut instructions = vec![];
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let instruction =
+            disassemble_one(&bytes[offset..], address.wrapping_add(offset) & 0xffffffff);
+        offset += instruction.size;
+        instructions.push(instruction);
+    }
+    instructions
+}
+
+// Disassembles every instruction starting in the range, reading past the end to finish the last one
+pub fn disassemble_memory(memory: &Memory, start: usize, end: usize) -> Vec<Instruction> {
+    let bytes: Vec<u8> = (start..end.saturating_add(2))
+        .map(|address| memory.read_byte(address))
+        .collect();
+    let mut instructions = disassemble(&bytes, start);
+    instructions.retain(|instruction| instruction.address < end);
+    instructions
+}
+
+pub fn disassemble_one(bytes: &[u8], address: usize) -> Instruction {
+    let halfword = |index: usize| {
+        bytes
+            .get(index * 2..index * 2 + 2)
+            .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+    };
+    let Some(instr) = halfword(0) else {
+        return Instruction {
+            address,
+            size: bytes.len().min(1),
+            text: format!(".byte 0x{:02x}", bytes.first().copied().unwrap_or(0)),
+        };
+    };
+    let data = || Instruction {
+        address,
+        size: 2,
+        text: format!(".hword 0x{:04x}", instr),
+    };
+
+    let opcode = (instr >> 10) & 0x3f;
+    let reg2 = (instr >> 5) & 0x1f;
+    let reg1 = instr & 0x1f;
+    let imm5 = (instr as i16).wrapping_shl(11).wrapping_shr(11);
+
+    // Formats I-III fit in one halfword
+    let text = if instr & 0xe000 == 0x8000 {
+        Some(bcond(instr, address))
+    } else {
+        match opcode {
+            0b000000 => Some(format!("mov r{}, r{}", reg1, reg2)),
+            0b000001 => Some(format!("add r{}, r{}", reg1, reg2)),
+            0b000010 => Some(format!("sub r{}, r{}", reg1, reg2)),
+            0b000011 => Some(format!("cmp r{}, r{}", reg1, reg2)),
+            0b000100 => Some(format!("shl r{}, r{}", reg1, reg2)),
+            0b000101 => Some(format!("shr r{}, r{}", reg1, reg2)),
+            0b000110 => Some(format!("jmp [r{}]", reg1)),
+            0b000111 => Some(format!("sar r{}, r{}", reg1, reg2)),
+            0b001000 => Some(format!("mul r{}, r{}", reg1, reg2)),
+            0b001001 => Some(format!("div r{}, r{}", reg1, reg2)),
+            0b001010 => Some(format!("mulu r{}, r{}", reg1, reg2)),
+            0b001011 => Some(format!("divu r{}, r{}", reg1, reg2)),
+            0b001100 => Some(format!("or r{}, r{}", reg1, reg2)),
+            0b001101 => Some(format!("and r{}, r{}", reg1, reg2)),
+            0b001110 => Some(format!("xor r{}, r{}", reg1, reg2)),
+            0b001111 => Some(format!("not r{}, r{}", reg1, reg2)),
+
+            0b010000 => Some(format!("mov {}, r{}", imm5, reg2)),
+            0b010001 => Some(format!("add {}, r{}", imm5, reg2)),
+            0b010010 => Some(format!(
+                "setf {}, r{}",
+                CONDITIONS[reg1 as usize & 0xf],
+                reg2
+            )),
+            0b010011 => Some(format!("cmp {}, r{}", imm5, reg2)),
+            0b010100 => Some(format!("shl {}, r{}", reg1, reg2)),
+            0b010101 => Some(format!("shr {}, r{}", reg1, reg2)),
+            0b010110 => Some("cli".to_string()),
+            0b010111 => Some(format!("sar {}, r{}", reg1, reg2)),
+            0b011000 => Some(format!("trap {}", reg1)),
+            0b011001 => Some("reti".to_string()),
+            0b011010 => Some("halt".to_string()),
+            0b011100 => Some(format!("ldsr r{}, {}", reg2, SYS_REGISTERS[reg1 as usize])),
+            0b011101 => Some(format!("stsr {}, r{}", SYS_REGISTERS[reg1 as usize], reg2)),
+            0b011110 => Some("sei".to_string()),
+            0b011111 => BITSTRING_OPS
+                .get(reg1 as usize)
+                .filter(|name| !name.is_empty())
+                .map(|name| name.to_string()),
+            _ => None,
+        }
+    };
+    if let Some(text) = text {
+        return Instruction {
+            address,
+            size: 2,
+            text,
+        };
+    }
+
+    // Formats IV-VII need a second halfword
+    let Some(extra) = halfword(1) else {
+        return data();
+    };
+    let imm16 = extra;
+    let disp16 = extra as i16;
+    let text = match opcode {
+        0b101010 | 0b101011 => {
+            let disp = (instr as i32).wrapping_shl(22).wrapping_shr(6) | extra as i32;
+            let name = if opcode == 0b101010 { "jr" } else { "jal" };
+            Some(format!("{} 0x{:08x}", name, relative(address, disp)))
+        }
+
+        0b101000 => Some(format!("movea 0x{:04x}, r{}, r{}", imm16, reg1, reg2)),
+        0b101001 => Some(format!("addi {}, r{}, r{}", disp16, reg1, reg2)),
+        0b101100 => Some(format!("ori 0x{:04x}, r{}, r{}", imm16, reg1, reg2)),
+        0b101101 => Some(format!("andi 0x{:04x}, r{}, r{}", imm16, reg1, reg2)),
+        0b101110 => Some(format!("xori 0x{:04x}, r{}, r{}", imm16, reg1, reg2)),
+        0b101111 => Some(format!("movhi 0x{:04x}, r{}, r{}", imm16, reg1, reg2)),
+
+        0b110000 => Some(format!("ld.b {}[r{}], r{}", disp16, reg1, reg2)),
+        0b110001 => Some(format!("ld.h {}[r{}], r{}", disp16, reg1, reg2)),
+        0b110011 => Some(format!("ld.w {}[r{}], r{}", disp16, reg1, reg2)),
+        0b110100 => Some(format!("st.b r{}, {}[r{}]", reg2, disp16, reg1)),
+        0b110101 => Some(format!("st.h r{}, {}[r{}]", reg2, disp16, reg1)),
+        0b110111 => Some(format!("st.w r{}, {}[r{}]", reg2, disp16, reg1)),
+        0b111000 => Some(format!("in.b {}[r{}], r{}", disp16, reg1, reg2)),
+        0b111001 => Some(format!("in.h {}[r{}], r{}", disp16, reg1, reg2)),
+        0b111010 => Some(format!("caxi {}[r{}], r{}", disp16, reg1, reg2)),
+        0b111011 => Some(format!("in.w {}[r{}], r{}", disp16, reg1, reg2)),
+        0b111100 => Some(format!("out.b r{}, {}[r{}]", reg2, disp16, reg1)),
+        0b111101 => Some(format!("out.h r{}, {}[r{}]", reg2, disp16, reg1)),
+        0b111111 => Some(format!("out.w r{}, {}[r{}]", reg2, disp16, reg1)),
+
+        // format 7 opcodes are format 1 with a subopcode suffix
+        0b111110 => match (extra >> 10) & 0x3f {
+            0b000000 => Some(format!("cmpf.s r{}, r{}", reg1, reg2)),
+            0b000010 => Some(format!("cvt.ws r{}, r{}", reg1, reg2)),
+            0b000011 => Some(format!("cvt.sw r{}, r{}", reg1, reg2)),
+            0b000100 => Some(format!("addf.s r{}, r{}", reg1, reg2)),
+            0b000101 => Some(format!("subf.s r{}, r{}", reg1, reg2)),
+            0b000110 => Some(format!("mulf.s r{}, r{}", reg1, reg2)),
+            0b000111 => Some(format!("divf.s r{}, r{}", reg1, reg2)),
+            0b001000 => Some(format!("xb r{}", reg2)),
+            0b001001 => Some(format!("xh r{}", reg2)),
+            0b001010 => Some(format!("rev r{}, r{}", reg1, reg2)),
+            0b001011 => Some(format!("trnc.sw r{}, r{}", reg1, reg2)),
+            0b001100 => Some(format!("mpyhw r{}, r{}", reg1, reg2)),
+            _ => None,
+        },
+        _ => None,
+    };
+    match text {
+        Some(text) => Instruction {
+            address,
+            size: 4,
+            text,
+        },
+        None => data(),
+    }
+}
+
+fn bcond(instr: u16, address: usize) -> String {
+    let cond = ((instr >> 9) & 0x0f) as usize;
+    let disp = (instr as i16 & 0x01ff).wrapping_shl(7).wrapping_shr(7) as i32;
+    let target = relative(address, disp);
+    match CONDITIONS[cond] {
+        "t" => format!("br 0x{:08x}", target),
+        "f" => "nop".to_string(),
+        name => format!("b{} 0x{:08x}", name, target),
+    }
+}
+
+// Jumps are relative to the start of the instruction
+fn relative(address: usize, disp: i32) -> u32 {
+    (address as u32).wrapping_add(disp as u32) & 0xfffffffe
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::disasm::{disassemble, disassemble_memory, disassemble_one};
+    use crate::emulator::memory::Memory;
+
+    fn text(bytes: &[u8]) -> String {
+        disassemble_one(bytes, 0x07000000).text
+    }
+
+    #[test]
+    fn disassembles_register_formats() {
+        // mov r1, r2
+        assert_eq!(text(&[0x41, 0x00]), "mov r1, r2");
+        // add -1, r3
+        assert_eq!(text(&[0x7f, 0x44]), "add -1, r3");
+        // jmp [r31]
+        assert_eq!(text(&[0x1f, 0x18]), "jmp [r31]");
+        assert_eq!(text(&[0x25, 0x70]), "ldsr r1, psw");
+        assert_eq!(text(&[0x38, 0x74]), "stsr chcw, r1");
+        assert_eq!(text(&[0x42, 0x48]), "setf z, r2");
+        assert_eq!(text(&[0x00, 0x68]), "halt");
+        assert_eq!(text(&[0x05, 0x60]), "trap 5");
+    }
+
+    #[test]
+    fn disassembles_branches_and_jumps() {
+        // bnz -4
+        assert_eq!(text(&[0xfc, 0x95]), "bnz 0x06fffffc");
+        // br +16
+        assert_eq!(text(&[0x10, 0x8a]), "br 0x07000010");
+        assert_eq!(text(&[0x00, 0x9a]), "nop");
+        // jal +0x100
+        assert_eq!(text(&[0x00, 0xac, 0x00, 0x01]), "jal 0x07000100");
+        // jr -2
+        assert_eq!(text(&[0xff, 0xab, 0xfe, 0xff]), "jr 0x06fffffe");
+    }
+
+    #[test]
+    fn disassembles_immediates_and_memory_access() {
+        assert_eq!(text(&[0x40, 0xbc, 0x00, 0x05]), "movhi 0x0500, r0, r2");
+        assert_eq!(text(&[0x22, 0xa4, 0xfc, 0xff]), "addi -4, r2, r1");
+        assert_eq!(text(&[0x23, 0xcc, 0x08, 0x00]), "ld.w 8[r3], r1");
+        assert_eq!(text(&[0x23, 0xd0, 0xfe, 0xff]), "st.b r1, -2[r3]");
+        assert_eq!(text(&[0x23, 0xe8, 0x00, 0x00]), "caxi 0[r3], r1");
+        assert_eq!(text(&[0x23, 0xfc, 0x10, 0x00]), "out.w r1, 16[r3]");
+    }
+
+    #[test]
+    fn disassembles_bitstring_and_float_ops() {
+        assert_eq!(text(&[0x01, 0x7c]), "sch0bsd");
+        assert_eq!(text(&[0x0b, 0x7c]), "movbsu");
+        assert_eq!(text(&[0x22, 0xf8, 0x00, 0x10]), "addf.s r2, r1");
+        assert_eq!(text(&[0x22, 0xf8, 0x00, 0x2c]), "trnc.sw r2, r1");
+        assert_eq!(text(&[0x22, 0xf8, 0x00, 0x30]), "mpyhw r2, r1");
+        assert_eq!(text(&[0x22, 0xf8, 0x00, 0x28]), "rev r2, r1");
+        assert_eq!(text(&[0x20, 0xf8, 0x00, 0x20]), "xb r1");
+        assert_eq!(text(&[0x20, 0xf8, 0x00, 0x24]), "xh r1");
+    }
+
+    #[test]
+    fn treats_invalid_instructions_as_data() {
+        assert_eq!(text(&[0x00, 0x6c]), ".hword 0x6c00");
+        // Bitstring subopcodes 4-7 aren't used
+        assert_eq!(text(&[0x04, 0x7c]), ".hword 0x7c04");
+        assert_eq!(text(&[0x22, 0xf8, 0x00, 0xfc]), ".hword 0xf822");
+        // A long instruction cut off at the end of the input
+        assert_eq!(text(&[0x23, 0xcc]), ".hword 0xcc23");
+        assert_eq!(text(&[0x12]), ".byte 0x12");
+    }
+
+    #[test]
+    fn walks_through_mixed_lengths() {
+        let code = [0x41, 0x00, 0x40, 0xbc, 0x00, 0x05, 0x00, 0x68];
+        let listing: Vec<String> = disassemble(&code, 0xfffffff8)
+            .iter()
+            .map(|instruction| instruction.to_string())
+            .collect();
+        assert_eq!(
+            listing,
+            [
+                "fffffff8  mov r1, r2",
+                "fffffffa  movhi 0x0500, r0, r2",
+                "fffffffe  halt",
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_from_memory() {
+        let mut memory = Memory::new();
+        // The second instruction starts inside the range but ends past it
+        for (offset, byte) in [0x41, 0x00, 0x40, 0xbc, 0x00, 0x05].into_iter().enumerate() {
+            memory.write_byte(0x05000000 + offset, byte);
+        }
+        let instructions = disassemble_memory(&memory, 0x05000000, 0x05000004);
+        assert_eq!(instructions.len(), 2);
+        assert_eq!(instructions[1].text, "movhi 0x0500, r0, r2");
+    }
+}
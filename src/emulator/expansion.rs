@@ -0,0 +1,115 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+// Where the expansion area starts, and how much address space it has
+pub const EXPANSION_START: usize = 0x04000000;
+pub const EXPANSION_SIZE: usize = 0x01000000;
+
+// Hardware on the game pak which answers reads and writes to the expansion area (0x04000000-0x04ffffff),
+// and can raise the game pak interrupt. Addresses are offsets from the start of the area.
+// Memory is shared with the drawing threads, so expansions have to be Sync, even though the drawing threads' Memory never holds one.
+// Expansions aren't part of save states, so they keep running straight through loads and rewinds.
+// They're paused while run-ahead looks into the future, so those frames can read them but not change them.
+pub trait Expansion: Send + Sync {
+    fn read_byte(&self, offset: usize) -> u8;
+    fn write_byte(&mut self, offset: usize, value: u8);
+
+    fn read_halfword(&self, offset: usize) -> u16 {
+        u16::from_le_bytes([self.read_byte(offset), self.read_byte(offset + 1)])
+    }
+    fn read_word(&self, offset: usize) -> u32 {
+        self.read_halfword(offset) as u32 | (self.read_halfword(offset + 2) as u32) << 16
+    }
+    fn write_halfword(&mut self, offset: usize, value: u16) {
+        let [low, high] = value.to_le_bytes();
+        self.write_byte(offset, low);
+        self.write_byte(offset + 1, high);
+    }
+    fn write_word(&mut self, offset: usize, value: u32) {
+        self.write_halfword(offset, value as u16);
+        self.write_halfword(offset + 2, (value >> 16) as u16);
+    }
+
+    // Catch up to the given cycle. Loading a state or rewinding can send it backwards,
+    // so the cycle can be lower than the last one seen.
+    fn run(&mut self, _cycle: u64) {}
+    // When the expansion next wants to do something on its own, like raise an interrupt
+    fn next_event(&self) -> u64 {
+        u64::MAX
+    }
+    // Whether the game pak interrupt is being held
+    fn interrupt_requested(&self) -> bool {
+        false
+    }
+    // The CPU just took the game pak interrupt, so a held line should drop until there's a reason to raise it again
+    fn acknowledge_interrupt(&mut self) {}
+}
+
+// Plain RAM mirrored across the expansion area, like a dev cart's extra memory.
+// Whoever built it can raise and lower the interrupt line from outside, and the CPU taking the interrupt lowers it.
+pub struct MemoryExpansion {
+    data: Vec<u8>,
+    mask: usize,
+    interrupt: Arc<AtomicBool>,
+}
+impl MemoryExpansion {
+    // size must be a power of two
+    pub fn new(size: usize) -> Self {
+        assert!(size.is_power_of_two() && size <= EXPANSION_SIZE);
+        Self {
+            data: vec![0; size],
+            mask: size - 1,
+            interrupt: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn interrupt_line(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.interrupt)
+    }
+}
+impl Expansion for MemoryExpansion {
+    fn read_byte(&self, offset: usize) -> u8 {
+        self.data[offset & self.mask]
+    }
+    fn write_byte(&mut self, offset: usize, value: u8) {
+        self.data[offset & self.mask] = value;
+    }
+    fn interrupt_requested(&self) -> bool {
+        self.interrupt.load(Ordering::Relaxed)
+    }
+    fn acknowledge_interrupt(&mut self) {
+        self.interrupt.store(false, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::expansion::{Expansion, MemoryExpansion};
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn mirrors_its_memory() {
+        let mut expansion = MemoryExpansion::new(0x100);
+        expansion.write_word(0x10, 0x12345678);
+        assert_eq!(expansion.read_word(0x110), 0x12345678);
+        assert_eq!(expansion.read_halfword(0x12), 0x1234);
+        assert_eq!(expansion.read_byte(0x13), 0x12);
+    }
+
+    #[test]
+    fn raises_interrupts_from_outside() {
+        let expansion = MemoryExpansion::new(0x100);
+        let line = expansion.interrupt_line();
+        assert!(!expansion.interrupt_requested());
+        line.store(true, Ordering::Relaxed);
+        assert!(expansion.interrupt_requested());
+    }
+
+    #[test]
+    fn lowers_its_interrupt_once_acknowledged() {
+        let mut expansion = MemoryExpansion::new(0x100);
+        expansion.interrupt_line().store(true, Ordering::Relaxed);
+        expansion.acknowledge_interrupt();
+        assert!(!expansion.interrupt_requested());
+    }
+}
@@ -0,0 +1,227 @@
+pub mod archive;
+pub mod patch;
+
+use anyhow::{anyhow, Result};
+use encoding_rs::SHIFT_JIS;
+use serde_derive::{Deserialize, Serialize};
+
+// The ROM region is 16Mb
+pub const MAX_ROM_SIZE: usize = 0x01000000;
+
+// The header lives just before the interrupt vectors, 0x220 bytes before the end of ROM
+const HEADER_ADDRESS: usize = 0xfffffde0;
+const TITLE_LENGTH: usize = 20;
+const MAKER_CODE_OFFSET: usize = 0x19;
+const GAME_CODE_OFFSET: usize = 0x1b;
+const VERSION_OFFSET: usize = 0x1f;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GameInfo {
+    pub title: String,
+    pub maker_code: String,
+    pub game_code: String,
+    pub version: u8,
+    pub crc32: u32,
+    pub sha1: [u8; 20],
+}
+impl GameInfo {
+    // Expects the ROM image as dumped, before it's mirrored, so the hashes match the file
+    pub fn parse(rom: &[u8]) -> Self {
+        // Read the header the way the CPU would, so tiny and trimmed ROMs just mirror into it
+        let size = rom.len().next_power_of_two();
+        let header: Vec<u8> = (0..0x20)
+            .map(|offset| {
+                rom[mirrored_index(rom.len(), size, (HEADER_ADDRESS + offset) & (size - 1))]
+            })
+            .collect();
+
+        let (title, _, _) = SHIFT_JIS.decode(&header[..TITLE_LENGTH]);
+        let title = title
+            .trim_end_matches(|c: char| c == '\0' || c.is_whitespace())
+            .to_string();
+
+        GameInfo {
+            title,
+            maker_code: read_ascii(&header[MAKER_CODE_OFFSET..GAME_CODE_OFFSET]),
+            game_code: read_ascii(&header[GAME_CODE_OFFSET..VERSION_OFFSET]),
+            version: header[VERSION_OFFSET],
+            crc32: crc32fast::hash(rom),
+            sha1: sha1_smol::Sha1::from(rom).digest().bytes(),
+        }
+    }
+
+    // A stable identifier for this exact ROM image
+    pub fn sha1_hex(&self) -> String {
+        sha1_hex(&self.sha1)
+    }
+}
+
+// Unpacks a ROM file (which may be zipped), and mirrors it up to a size the memory map can hold
+pub fn prepare_rom(data: &[u8]) -> Result<Vec<u8>> {
+    let rom = unpack_rom(data)?;
+    check_rom_size(rom.len())?;
+    Ok(mirror_rom(&rom))
+}
+
+pub fn unpack_rom(data: &[u8]) -> Result<Vec<u8>> {
+    if archive::is_zip(data) {
+        archive::extract_rom(data)
+    } else {
+        Ok(data.to_vec())
+    }
+}
+
+pub fn check_rom_size(size: usize) -> Result<()> {
+    if size == 0 {
+        return Err(anyhow!("ROM is empty"));
+    }
+    if size > MAX_ROM_SIZE {
+        return Err(anyhow!("ROM size must be <= 16Mb"));
+    }
+    Ok(())
+}
+
+// Trimmed dumps and homebrew aren't always a power of two in size.
+// On a real cartridge, the highest address line picks between a chip holding the first half of the image
+// and smaller chip(s) holding the rest, which ignore the address lines they don't have.
+// So the leftover part gets mirrored across the top half, recursively.
+pub fn mirror_rom(rom: &[u8]) -> Vec<u8> {
+    let size = rom.len().next_power_of_two();
+    let mut mirrored = Vec::with_capacity(size);
+    mirror_into(rom, size, &mut mirrored);
+    mirrored
+}
+
+fn mirror_into(rom: &[u8], size: usize, out: &mut Vec<u8>) {
+    if rom.len().is_power_of_two() {
+        for _ in 0..size / rom.len() {
+            out.extend_from_slice(rom);
+        }
+        return;
+    }
+    let half = size / 2;
+    if rom.len() <= half {
+        let start = out.len();
+        mirror_into(rom, half, out);
+        out.extend_from_within(start..);
+        return;
+    }
+    out.extend_from_slice(&rom[..half]);
+    mirror_into(&rom[half..], half, out);
+}
+
+// Where a byte of the mirrored image comes from in the original, without building the whole image
+fn mirrored_index(len: usize, size: usize, offset: usize) -> usize {
+    if len.is_power_of_two() {
+        return offset % len;
+    }
+    let half = size / 2;
+    if len <= half {
+        mirrored_index(len, half, offset % half)
+    } else if offset < half {
+        offset
+    } else {
+        half + mirrored_index(len - half, half, offset - half)
+    }
+}
+
+pub fn sha1_hex(sha1: &[u8; 20]) -> String {
+    sha1.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn read_ascii(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .filter(|b| b.is_ascii_graphic())
+        .map(|&b| b as char)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::game_pak::{mirror_rom, mirrored_index, prepare_rom, GameInfo};
+
+    fn rom_with_header(size: usize, header: &[u8]) -> Vec<u8> {
+        let mut rom = vec![0; size];
+        let start = size - 0x220;
+        rom[start..start + header.len()].copy_from_slice(header);
+        rom
+    }
+
+    #[test]
+    fn parses_header() {
+        let mut header = b"VIRTUAL BOY WARIO   ".to_vec();
+        header.extend_from_slice(&[0; 5]);
+        header.extend_from_slice(b"01VWCJ");
+        header.push(1);
+        let info = GameInfo::parse(&rom_with_header(0x1000, &header));
+        assert_eq!(info.title, "VIRTUAL BOY WARIO");
+        assert_eq!(info.maker_code, "01");
+        assert_eq!(info.game_code, "VWCJ");
+        assert_eq!(info.version, 1);
+    }
+
+    #[test]
+    fn decodes_shift_jis_titles() {
+        // "テスト" in Shift-JIS
+        let mut header = vec![0x83, 0x65, 0x83, 0x58, 0x83, 0x67];
+        header.resize(0x20, 0);
+        let info = GameInfo::parse(&rom_with_header(0x1000, &header));
+        assert_eq!(info.title, "テスト");
+    }
+
+    #[test]
+    fn hashes_rom() {
+        let info = GameInfo::parse(b"abcd");
+        assert_eq!(info.crc32, 0xed82cd11);
+        assert_eq!(info.sha1_hex(), "81fe8bfe87576c3ecb22426f8e57847382917acf");
+    }
+
+    #[test]
+    fn mirrors_tiny_roms_into_header() {
+        let info = GameInfo::parse(&[0x41, 0x42, 0x43, 0x44]);
+        assert_eq!(info.title, "ABCDABCDABCDABCDABCD");
+    }
+
+    #[test]
+    fn mirrors_trimmed_roms_like_address_decoding() {
+        // 3 bytes: a 2 byte chip and a 1 byte chip
+        assert_eq!(mirror_rom(&[1, 2, 3]), [1, 2, 3, 3]);
+        // 5 bytes: a 4 byte chip and a 1 byte chip
+        assert_eq!(mirror_rom(&[1, 2, 3, 4, 5]), [1, 2, 3, 4, 5, 5, 5, 5]);
+        // 6 bytes: a 4 byte chip and a 2 byte chip
+        assert_eq!(mirror_rom(&[1, 2, 3, 4, 5, 6]), [1, 2, 3, 4, 5, 6, 5, 6]);
+        // 7 bytes: 4, 2 and 1 byte chips
+        assert_eq!(mirror_rom(&[1, 2, 3, 4, 5, 6, 7]), [1, 2, 3, 4, 5, 6, 7, 7]);
+        assert_eq!(mirror_rom(&[1, 2, 3, 4]), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn finds_mirrored_bytes_in_the_original() {
+        for len in 1..20 {
+            let rom: Vec<u8> = (0..len as u8).collect();
+            let mirrored = mirror_rom(&rom);
+            for (offset, byte) in mirrored.iter().enumerate() {
+                assert_eq!(rom[mirrored_index(len, mirrored.len(), offset)], *byte);
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_vectors_at_the_end_of_trimmed_roms() {
+        let mut rom = vec![0; 0x180000];
+        let end = rom.len();
+        rom[end - 16..].copy_from_slice(&[0xaa; 16]);
+        let mirrored = prepare_rom(&rom).unwrap();
+        assert_eq!(mirrored.len(), 0x200000);
+        assert_eq!(mirrored[0x200000 - 16..], [0xaa; 16]);
+    }
+
+    #[test]
+    fn rejects_oversized_roms() {
+        let err = prepare_rom(&vec![0; 0x01000001]).unwrap_err();
+        assert_eq!(err.to_string(), "ROM size must be <= 16Mb");
+        let err = prepare_rom(&[]).unwrap_err();
+        assert_eq!(err.to_string(), "ROM is empty");
+    }
+}
@@ -0,0 +1,74 @@
+use anyhow::{anyhow, Result};
+use std::io::{Cursor, Read};
+use zip::ZipArchive;
+
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+
+pub fn is_zip(data: &[u8]) -> bool {
+    data.starts_with(ZIP_MAGIC)
+}
+
+// Pulls the first .vb file out of a zip archive
+pub fn extract_rom(data: &[u8]) -> Result<Vec<u8>> {
+    let mut archive = ZipArchive::new(Cursor::new(data))
+        .map_err(|err| anyhow!("Could not read zip archive: {}", err))?;
+    let index = (0..archive.len())
+        .find(|&index| {
+            archive.name_for_index(index).is_some_and(|name| {
+                !name.ends_with('/') && name.to_ascii_lowercase().ends_with(".vb")
+            })
+        })
+        .ok_or_else(|| anyhow!("Zip archive does not contain a .vb file"))?;
+
+    let mut file = archive
+        .by_index(index)
+        .map_err(|err| anyhow!("Could not read zip archive: {}", err))?;
+    let name = file.name().to_string();
+    let mut rom = Vec::with_capacity(file.size() as usize);
+    file.read_to_end(&mut rom)
+        .map_err(|err| anyhow!("Could not extract {} from zip archive: {}", name, err))?;
+    Ok(rom)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::game_pak::archive::{extract_rom, is_zip};
+    use std::io::{Cursor, Write};
+    use zip::write::SimpleFileOptions;
+    use zip::{CompressionMethod, ZipWriter};
+
+    fn zip(files: &[(&str, &[u8])], method: CompressionMethod) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(vec![]));
+        let options = SimpleFileOptions::default().compression_method(method);
+        for (name, data) in files {
+            writer.start_file(*name, options).unwrap();
+            writer.write_all(data).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn extracts_stored_rom() {
+        let data = zip(
+            &[("readme.txt", b"hi"), ("Game.VB", &[1, 2, 3, 4])],
+            CompressionMethod::Stored,
+        );
+        assert!(is_zip(&data));
+        assert_eq!(extract_rom(&data).unwrap(), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn extracts_deflated_rom() {
+        let rom = vec![0x5a; 0x1000];
+        let data = zip(&[("game.vb", &rom)], CompressionMethod::Deflated);
+        assert!(data.len() < rom.len());
+        assert_eq!(extract_rom(&data).unwrap(), rom);
+    }
+
+    #[test]
+    fn requires_a_vb_file() {
+        let data = zip(&[("game.gb", &[0; 4])], CompressionMethod::Stored);
+        let err = extract_rom(&data).unwrap_err();
+        assert_eq!(err.to_string(), "Zip archive does not contain a .vb file");
+    }
+}
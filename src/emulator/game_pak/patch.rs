@@ -0,0 +1,445 @@
+use crate::emulator::game_pak::{check_rom_size, MAX_ROM_SIZE};
+use anyhow::{anyhow, Result};
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const BPS_MAGIC: &[u8] = b"BPS1";
+// Source, target and patch checksums
+const BPS_FOOTER_SIZE: usize = 12;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PatchFormat {
+    Ips,
+    Bps,
+}
+
+// A soft patch, applied to the ROM while it's being loaded.
+// The name is only used to say which patch was at fault when something goes wrong.
+#[derive(Clone, Debug)]
+pub struct Patch {
+    name: String,
+    format: PatchFormat,
+    data: Vec<u8>,
+}
+impl Patch {
+    pub fn new(name: impl Into<String>, data: Vec<u8>) -> Result<Self> {
+        let name = name.into();
+        let format = if data.starts_with(IPS_MAGIC) {
+            PatchFormat::Ips
+        } else if data.starts_with(BPS_MAGIC) {
+            PatchFormat::Bps
+        } else {
+            return Err(anyhow!("Patch {}: not an IPS or BPS patch", name));
+        };
+        Ok(Self { name, format, data })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn format(&self) -> PatchFormat {
+        self.format
+    }
+
+    pub fn apply(&self, rom: &[u8]) -> Result<Vec<u8>> {
+        let result = match self.format {
+            PatchFormat::Ips => apply_ips(&self.data, rom),
+            PatchFormat::Bps => apply_bps(&self.data, rom),
+        };
+        let patched = result.map_err(|reason| anyhow!("Patch {}: {}", self.name, reason))?;
+        check_rom_size(patched.len())
+            .map_err(|reason| anyhow!("Patch {}: patched ROM is invalid: {}", self.name, reason))?;
+        Ok(patched)
+    }
+}
+
+// Patches are applied in order, each one to the output of the last
+pub fn apply_patches(rom: &[u8], patches: &[Patch]) -> Result<Vec<u8>> {
+    let mut rom = rom.to_vec();
+    for patch in patches {
+        rom = patch.apply(&rom)?;
+    }
+    Ok(rom)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], offset: usize) -> Self {
+        Self { data, offset }
+    }
+
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8]> {
+        let end = self.offset + count;
+        if end > self.data.len() {
+            return Err(anyhow!("unexpected end of patch at offset {}", self.offset));
+        }
+        let bytes = &self.data[self.offset..end];
+        self.offset = end;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn big_endian(&mut self, count: usize) -> Result<usize> {
+        let bytes = self.bytes(count)?;
+        Ok(bytes.iter().fold(0, |acc, &b| (acc << 8) | b as usize))
+    }
+
+    // BPS numbers are little-endian base-128, with an implicit +1 on every continuation byte
+    fn varint(&mut self) -> Result<usize> {
+        let mut value: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let byte = self.byte()?;
+            value = (byte as usize & 0x7f)
+                .checked_mul(shift)
+                .and_then(|v| v.checked_add(value))
+                .ok_or_else(|| anyhow!("number too large at offset {}", self.offset))?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift
+                .checked_shl(7)
+                .filter(|s| *s != 0)
+                .ok_or_else(|| anyhow!("number too large at offset {}", self.offset))?;
+            value = value
+                .checked_add(shift)
+                .ok_or_else(|| anyhow!("number too large at offset {}", self.offset))?;
+        }
+    }
+}
+
+fn apply_ips(patch: &[u8], rom: &[u8]) -> Result<Vec<u8>> {
+    let mut target = rom.to_vec();
+    let mut reader = Reader::new(patch, IPS_MAGIC.len());
+    loop {
+        if patch[reader.offset..].starts_with(IPS_EOF) {
+            reader.bytes(IPS_EOF.len())?;
+            break;
+        }
+        let offset = reader.big_endian(3)?;
+        let size = reader.big_endian(2)?;
+        let (data, size) = if size == 0 {
+            // Run-length encoded record
+            let size = reader.big_endian(2)?;
+            (None, size)
+        } else {
+            (Some(reader.bytes(size)?), size)
+        };
+        if target.len() < offset + size {
+            target.resize(offset + size, 0);
+        }
+        match data {
+            Some(data) => target[offset..offset + size].copy_from_slice(data),
+            None => target[offset..offset + size].fill(reader.byte()?),
+        }
+    }
+    // Some patches end with the size to truncate the ROM to
+    if patch.len() - reader.offset >= 3 {
+        let size = reader.big_endian(3)?;
+        target.truncate(size);
+    }
+    Ok(target)
+}
+
+fn apply_bps(patch: &[u8], rom: &[u8]) -> Result<Vec<u8>> {
+    if patch.len() < BPS_MAGIC.len() + BPS_FOOTER_SIZE {
+        return Err(anyhow!("patch is truncated"));
+    }
+    let footer_start = patch.len() - BPS_FOOTER_SIZE;
+    let checksum =
+        |offset: usize| u32::from_le_bytes(patch[offset..offset + 4].try_into().unwrap());
+    let source_checksum = checksum(footer_start);
+    let target_checksum = checksum(footer_start + 4);
+    let patch_checksum = checksum(footer_start + 8);
+
+    if crc32fast::hash(&patch[..footer_start + 8]) != patch_checksum {
+        return Err(anyhow!("patch file is corrupt (checksum mismatch)"));
+    }
+    if crc32fast::hash(rom) != source_checksum {
+        return Err(anyhow!(
+            "patch is for a different ROM (expected CRC32 {:08x}, got {:08x})",
+            source_checksum,
+            crc32fast::hash(rom)
+        ));
+    }
+
+    let mut reader = Reader::new(&patch[..footer_start], BPS_MAGIC.len());
+    let source_size = reader.varint()?;
+    let target_size = reader.varint()?;
+    let metadata_size = reader.varint()?;
+    reader.bytes(metadata_size)?;
+    if source_size != rom.len() {
+        return Err(anyhow!(
+            "patch expects a {} byte ROM, but this one is {} byte(s)",
+            source_size,
+            rom.len()
+        ));
+    }
+
+    // Don't trust the patch with how much to allocate
+    if target_size > MAX_ROM_SIZE {
+        return Err(anyhow!(
+            "patch promises a {} byte ROM, but ROMs can be at most {} bytes",
+            target_size,
+            MAX_ROM_SIZE
+        ));
+    }
+
+    let mut target = Vec::with_capacity(target_size);
+    let mut source_offset: usize = 0;
+    let mut target_offset: usize = 0;
+    while reader.offset < footer_start {
+        let data = reader.varint()?;
+        let length = (data >> 2) + 1;
+        if target
+            .len()
+            .checked_add(length)
+            .is_none_or(|end| end > target_size)
+        {
+            return Err(anyhow!("patch writes past the end of the target ROM"));
+        }
+        match data & 3 {
+            // SourceRead
+            0 => {
+                let start = target.len();
+                let bytes = rom
+                    .get(start..start + length)
+                    .ok_or_else(|| anyhow!("patch reads past the end of the source ROM"))?;
+                target.extend_from_slice(bytes);
+            }
+            // TargetRead
+            1 => target.extend_from_slice(reader.bytes(length)?),
+            // SourceCopy
+            2 => {
+                source_offset = relative_offset(source_offset, reader.varint()?)?;
+                let end = source_offset
+                    .checked_add(length)
+                    .ok_or_else(|| anyhow!("patch reads past the end of the source ROM"))?;
+                let bytes = rom
+                    .get(source_offset..end)
+                    .ok_or_else(|| anyhow!("patch reads past the end of the source ROM"))?;
+                target.extend_from_slice(bytes);
+                source_offset = end;
+            }
+            // TargetCopy, which can overlap the bytes it's writing
+            _ => {
+                target_offset = relative_offset(target_offset, reader.varint()?)?;
+                for _ in 0..length {
+                    let byte = *target
+                        .get(target_offset)
+                        .ok_or_else(|| anyhow!("patch copies from past the end of the target"))?;
+                    target.push(byte);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+
+    if target.len() != target_size {
+        return Err(anyhow!(
+            "patch produced {} byte(s), but promised {}",
+            target.len(),
+            target_size
+        ));
+    }
+    if crc32fast::hash(&target) != target_checksum {
+        return Err(anyhow!("patched ROM failed its checksum"));
+    }
+    Ok(target)
+}
+
+// The low bit is the sign, the rest is the distance
+fn relative_offset(offset: usize, data: usize) -> Result<usize> {
+    let distance = data >> 1;
+    let result = if data & 1 != 0 {
+        offset.checked_sub(distance)
+    } else {
+        offset.checked_add(distance)
+    };
+    result.ok_or_else(|| anyhow!("patch copies from before the start of the ROM"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::game_pak::patch::{apply_patches, Patch, PatchFormat};
+
+    fn bps_varint(mut value: usize, out: &mut Vec<u8>) {
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(byte | 0x80);
+                return;
+            }
+            out.push(byte);
+            value -= 1;
+        }
+    }
+
+    fn bps(source: &[u8], target: &[u8], actions: &[u8]) -> Vec<u8> {
+        bps_with_target_size(source, target, target.len(), actions)
+    }
+
+    // For patches which lie about how big the target is
+    fn bps_with_target_size(
+        source: &[u8],
+        target: &[u8],
+        target_size: usize,
+        actions: &[u8],
+    ) -> Vec<u8> {
+        let mut patch = b"BPS1".to_vec();
+        bps_varint(source.len(), &mut patch);
+        bps_varint(target_size, &mut patch);
+        bps_varint(0, &mut patch);
+        patch.extend_from_slice(actions);
+        patch.extend_from_slice(&crc32fast::hash(source).to_le_bytes());
+        patch.extend_from_slice(&crc32fast::hash(target).to_le_bytes());
+        patch.extend_from_slice(&crc32fast::hash(&patch).to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn detects_format() {
+        let ips = Patch::new("a.ips", b"PATCHEOF".to_vec()).unwrap();
+        assert_eq!(ips.format(), PatchFormat::Ips);
+        let err = Patch::new("a.txt", b"hello".to_vec()).unwrap_err();
+        assert_eq!(err.to_string(), "Patch a.txt: not an IPS or BPS patch");
+    }
+
+    #[test]
+    fn applies_ips_records() {
+        let mut data = b"PATCH".to_vec();
+        // Write two bytes at offset 1
+        data.extend_from_slice(&[0, 0, 1, 0, 2, 0xaa, 0xbb]);
+        // Fill offset 4..7 with 0xcc
+        data.extend_from_slice(&[0, 0, 4, 0, 0, 0, 3, 0xcc]);
+        data.extend_from_slice(b"EOF");
+        let patch = Patch::new("test.ips", data).unwrap();
+        let rom = patch.apply(&[0; 8]).unwrap();
+        assert_eq!(rom, [0, 0xaa, 0xbb, 0, 0xcc, 0xcc, 0xcc, 0]);
+    }
+
+    #[test]
+    fn rechecks_rom_size_after_patching() {
+        // Truncates the ROM to nothing
+        let data = b"PATCHEOF\0\0\0".to_vec();
+        let patch = Patch::new("empty.ips", data).unwrap();
+        let err = patch.apply(&[0; 8]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Patch empty.ips: patched ROM is invalid: ROM is empty"
+        );
+    }
+
+    #[test]
+    fn applies_bps_actions() {
+        let source = [1, 2, 3, 4, 5, 6, 7, 8];
+        let target = [1, 2, 9, 9, 9, 9, 3, 4];
+        let mut actions = vec![];
+        // SourceRead 2
+        bps_varint(1 << 2, &mut actions);
+        // TargetRead 1
+        bps_varint(1, &mut actions);
+        actions.push(9);
+        // TargetCopy 3 from offset 2
+        bps_varint((2 << 2) | 3, &mut actions);
+        bps_varint(2 << 1, &mut actions);
+        // SourceCopy 2 from offset 2
+        bps_varint((1 << 2) | 2, &mut actions);
+        bps_varint(2 << 1, &mut actions);
+
+        let patch = Patch::new("fix.bps", bps(&source, &target, &actions)).unwrap();
+        assert_eq!(patch.format(), PatchFormat::Bps);
+        assert_eq!(apply_patches(&source, &[patch]).unwrap(), target);
+    }
+
+    #[test]
+    fn rejects_bps_for_wrong_rom() {
+        let mut actions = vec![];
+        bps_varint(3 << 2, &mut actions);
+        let patch = Patch::new("fix.bps", bps(&[0; 4], &[0; 4], &actions)).unwrap();
+        let err = patch.apply(&[1; 4]).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Patch fix.bps: patch is for a different ROM"));
+    }
+
+    #[test]
+    fn rejects_corrupt_bps() {
+        let mut actions = vec![];
+        bps_varint(3 << 2, &mut actions);
+        let mut data = bps(&[0; 4], &[0; 4], &actions);
+        data[5] ^= 0xff;
+        let err = Patch::new("fix.bps", data)
+            .unwrap()
+            .apply(&[0; 4])
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Patch fix.bps: patch file is corrupt (checksum mismatch)"
+        );
+    }
+
+    #[test]
+    fn rejects_bps_with_huge_target() {
+        let mut actions = vec![];
+        bps_varint(3 << 2, &mut actions);
+        let data = bps_with_target_size(&[0; 4], &[0; 4], usize::MAX >> 8, &actions);
+        let err = Patch::new("huge.bps", data)
+            .unwrap()
+            .apply(&[0; 4])
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Patch huge.bps: patch promises a"));
+    }
+
+    #[test]
+    fn rejects_bps_with_long_varints() {
+        // The source size never ends, and overflows before the continuation bits run out
+        let mut data = b"BPS1".to_vec();
+        data.extend_from_slice(&[0x7f; 10]);
+        data.push(0x80);
+        data.extend_from_slice(&crc32fast::hash(&[0; 4]).to_le_bytes());
+        data.extend_from_slice(&crc32fast::hash(&[0; 4]).to_le_bytes());
+        data.extend_from_slice(&crc32fast::hash(&data).to_le_bytes());
+        let err = Patch::new("long.bps", data)
+            .unwrap()
+            .apply(&[0; 4])
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Patch long.bps: number too large"));
+    }
+
+    #[test]
+    fn rejects_bps_copies_past_the_end() {
+        // SourceCopy a huge length from a huge offset
+        let mut actions = vec![];
+        bps_varint(((usize::MAX >> 3) << 2) | 2, &mut actions);
+        bps_varint((usize::MAX >> 2) << 1, &mut actions);
+        let patch = Patch::new("far.bps", bps(&[0; 4], &[0; 4], &actions)).unwrap();
+        let err = patch.apply(&[0; 4]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Patch far.bps: patch writes past the end of the target ROM"
+        );
+
+        let mut actions = vec![];
+        bps_varint((3 << 2) | 2, &mut actions);
+        bps_varint((usize::MAX >> 1) << 1, &mut actions);
+        let patch = Patch::new("far.bps", bps(&[0; 4], &[0; 4], &actions)).unwrap();
+        let err = patch.apply(&[0; 4]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Patch far.bps: patch reads past the end of the source ROM"
+        );
+    }
+}
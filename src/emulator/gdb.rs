@@ -0,0 +1,429 @@
+use anyhow::{anyhow, Result};
+use log::{debug, info};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use super::debug::{Access, Breakpoint, DebugHit, Watchpoint};
+use super::Emulator;
+
+// GDB sees r0-r31, then the 32 system registers in order, then the PC
+const GPR_COUNT: usize = 32;
+const SYS_REGISTER_COUNT: usize = 32;
+const PC_REGISTER: usize = GPR_COUNT + SYS_REGISTER_COUNT;
+const REGISTER_COUNT: usize = PC_REGISTER + 1;
+
+// How long to run between checks for the client asking us to stop
+const INTERRUPT_CHECK_CYCLES: u64 = 200_000;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+// Waits for one GDB client to connect, and lets it drive the emulator until it detaches
+pub fn listen(emulator: &mut Emulator, address: impl ToSocketAddrs) -> Result<()> {
+    let listener = TcpListener::bind(address)?;
+    info!("Waiting for GDB on {}", listener.local_addr()?);
+    let (stream, peer) = listener.accept()?;
+    info!("GDB connected from {}", peer);
+    GdbStub::new(emulator).serve(stream)
+}
+
+enum Resume {
+    Step,
+    Continue,
+}
+
+pub struct GdbStub<'a> {
+    emulator: &'a mut Emulator,
+    no_ack: bool,
+}
+impl<'a> GdbStub<'a> {
+    pub fn new(emulator: &'a mut Emulator) -> Self {
+        Self {
+            emulator,
+            no_ack: false,
+        }
+    }
+
+    pub fn serve(&mut self, mut stream: TcpStream) -> Result<()> {
+        stream.set_nodelay(true)?;
+        let result = self.serve_packets(&mut stream);
+        // GDB's breakpoints and watchpoints shouldn't outlive the session
+        self.emulator.clear_debug_points();
+        result
+    }
+
+    fn serve_packets(&mut self, stream: &mut TcpStream) -> Result<()> {
+        while let Some(packet) = self.read_packet(stream)? {
+            debug!("gdb <- {}", packet);
+            let reply = match packet.as_bytes().first() {
+                Some(b's') => self.resume(stream, &packet[1..], Resume::Step)?,
+                Some(b'c') => self.resume(stream, &packet[1..], Resume::Continue)?,
+                Some(b'D') => {
+                    self.write_packet(stream, "OK")?;
+                    return Ok(());
+                }
+                Some(b'k') => return Ok(()),
+                _ => self.handle_packet(&packet),
+            };
+            debug!("gdb -> {}", reply);
+            self.write_packet(stream, &reply)?;
+        }
+        Ok(())
+    }
+
+    // Handles everything which doesn't need to run the emulator
+    pub fn handle_packet(&mut self, packet: &str) -> String {
+        self.try_handle_packet(packet).unwrap_or_else(|err| {
+            debug!("Bad gdb packet {}: {}", packet, err);
+            "E01".to_string()
+        })
+    }
+
+    fn try_handle_packet(&mut self, packet: &str) -> Result<String> {
+        let (command, args) = packet.split_at(packet.len().min(1));
+        let reply = match command {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => (0..REGISTER_COUNT)
+                .map(|index| encode_hex(&self.register(index).to_le_bytes()))
+                .collect(),
+            "G" => {
+                let bytes = decode_hex(args)?;
+                for (index, value) in bytes.chunks_exact(4).enumerate().take(REGISTER_COUNT) {
+                    self.set_register(index, u32::from_le_bytes(value.try_into()?));
+                }
+                "OK".to_string()
+            }
+            "p" => {
+                let index = parse_hex(args)?;
+                encode_hex(&self.register(index).to_le_bytes())
+            }
+            "P" => {
+                let (index, value) = split(args, '=')?;
+                let value: [u8; 4] = decode_hex(value)?
+                    .try_into()
+                    .map_err(|_| anyhow!("Registers are 4 bytes"))?;
+                self.set_register(parse_hex(index)?, u32::from_le_bytes(value));
+                "OK".to_string()
+            }
+            "m" => {
+                let (address, length) = split(args, ',')?;
+                let (address, length) = (parse_hex(address)?, parse_hex(length)?);
+                let memory = self.emulator.memory.borrow();
+                let bytes: Vec<u8> = (0..length)
+                    .map(|offset| memory.read_byte(address.wrapping_add(offset)))
+                    .collect();
+                encode_hex(&bytes)
+            }
+            "M" => {
+                let (location, data) = split(args, ':')?;
+                let (address, _) = split(location, ',')?;
+                let address = parse_hex(address)?;
+                let mut memory = self.emulator.memory.borrow_mut();
+                // Poking hardware registers from the debugger doesn't trigger their side effects
+                for (offset, byte) in decode_hex(data)?.into_iter().enumerate() {
+                    memory.write_byte(address.wrapping_add(offset), byte);
+                }
+                "OK".to_string()
+            }
+            "Z" | "z" => {
+                let mut parts = args.split(',');
+                let kind = parts.next().unwrap_or_default();
+                let address = parse_hex(parts.next().unwrap_or_default())?;
+                let length = parse_hex(parts.next().unwrap_or("1"))?.max(1);
+                let range = address..address + length;
+                let watchpoint = match kind {
+                    "0" => None,
+                    "2" => Some(Watchpoint::writes(range.clone())),
+                    "3" => Some(Watchpoint::reads(range.clone())),
+                    "4" => Some(Watchpoint::accesses(range.clone())),
+                    // No hardware breakpoints, software ones work just as well
+                    _ => return Ok(String::new()),
+                };
+                match (command, watchpoint) {
+                    ("Z", None) => self.emulator.add_breakpoint(Breakpoint::at(address)),
+                    ("Z", Some(watchpoint)) => self.emulator.add_watchpoint(watchpoint),
+                    (_, None) => self.emulator.remove_breakpoint(address),
+                    (_, Some(_)) => self.emulator.remove_watchpoint(range),
+                }
+                "OK".to_string()
+            }
+            "H" => "OK".to_string(),
+            "q" if args.starts_with("Supported") => "PacketSize=4000;QStartNoAckMode+".to_string(),
+            "q" if args == "Attached" => "1".to_string(),
+            "q" if args == "C" => "QC1".to_string(),
+            "q" if args == "fThreadInfo" => "m1".to_string(),
+            "q" if args == "sThreadInfo" => "l".to_string(),
+            "Q" if args == "StartNoAckMode" => {
+                self.no_ack = true;
+                "OK".to_string()
+            }
+            // An empty reply tells GDB we don't support something
+            _ => String::new(),
+        };
+        Ok(reply)
+    }
+
+    fn register(&self, index: usize) -> u32 {
+        let cpu = &self.emulator.cpu;
+        match index {
+            0..GPR_COUNT => cpu.registers[index],
+            GPR_COUNT..PC_REGISTER => cpu.sys_registers[index - GPR_COUNT],
+            PC_REGISTER => cpu.pc as u32,
+            _ => 0,
+        }
+    }
+
+    fn set_register(&mut self, index: usize, value: u32) {
+        let cpu = &mut self.emulator.cpu;
+        match index {
+            // r0 is always zero
+            0 => {}
+            1..GPR_COUNT => cpu.registers[index] = value,
+            GPR_COUNT..PC_REGISTER => cpu.sys_registers[index - GPR_COUNT] = value,
+            PC_REGISTER => cpu.pc = value as usize,
+            _ => {}
+        }
+    }
+
+    fn resume(&mut self, stream: &mut TcpStream, address: &str, resume: Resume) -> Result<String> {
+        if !address.is_empty() {
+            self.emulator.cpu.pc = parse_hex(address)?;
+        }
+        match resume {
+            Resume::Step => {
+                let debug_hit = self.emulator.step_instruction()?;
+                Ok(stop_reply(debug_hit))
+            }
+            Resume::Continue => self.run_until_stopped(stream),
+        }
+    }
+
+    fn run_until_stopped(&mut self, stream: &mut TcpStream) -> Result<String> {
+        loop {
+            let target_cycle = self.emulator.cycle + INTERRUPT_CHECK_CYCLES;
+            if let Some(debug_hit) = self.emulator.run_until(target_cycle)?.debug_hit {
+                return Ok(stop_reply(Some(debug_hit)));
+            }
+            if interrupted(stream)? {
+                return Ok(format!("S{:02x}", SIGINT));
+            }
+        }
+    }
+
+    // Returns None once the client hangs up
+    fn read_packet(&mut self, stream: &mut TcpStream) -> Result<Option<String>> {
+        loop {
+            match read_byte(stream)? {
+                None => return Ok(None),
+                Some(b'$') => {}
+                // Acks, and interrupts while we're already stopped
+                Some(_) => continue,
+            }
+            let mut data = vec![];
+            loop {
+                match read_byte(stream)? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                }
+            }
+            let mut checksum = [0; 2];
+            stream.read_exact(&mut checksum)?;
+            let valid = u8::from_str_radix(std::str::from_utf8(&checksum)?, 16)
+                .is_ok_and(|checksum| checksum == packet_checksum(&data));
+            if !self.no_ack {
+                stream.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                return Ok(Some(String::from_utf8(data)?));
+            }
+        }
+    }
+
+    fn write_packet(&self, stream: &mut TcpStream, data: &str) -> Result<()> {
+        let packet = format!("${}#{:02x}", data, packet_checksum(data.as_bytes()));
+        stream.write_all(packet.as_bytes())?;
+        Ok(())
+    }
+}
+
+fn stop_reply(debug_hit: Option<DebugHit>) -> String {
+    match debug_hit {
+        Some(DebugHit::Watchpoint {
+            address, access, ..
+        }) => {
+            let kind = match access {
+                Access::Read => "rwatch",
+                Access::Write => "watch",
+            };
+            format!("T{:02x}{}:{:x};", SIGTRAP, kind, address)
+        }
+        _ => format!("S{:02x}", SIGTRAP),
+    }
+}
+
+fn read_byte(stream: &mut TcpStream) -> Result<Option<u8>> {
+    let mut byte = [0];
+    match stream.read(&mut byte)? {
+        0 => Ok(None),
+        _ => Ok(Some(byte[0])),
+    }
+}
+
+// Checks whether the client sent a break (0x03) without waiting for one
+fn interrupted(stream: &mut TcpStream) -> Result<bool> {
+    stream.set_nonblocking(true)?;
+    let mut byte = [0];
+    let result = stream.read(&mut byte);
+    stream.set_nonblocking(false)?;
+    match result {
+        Ok(1) => Ok(byte[0] == 0x03),
+        // The client hung up, so stop and let the next read notice
+        Ok(_) => Ok(true),
+        Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn packet_checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn split(args: &str, separator: char) -> Result<(&str, &str)> {
+    args.split_once(separator)
+        .ok_or_else(|| anyhow!("Expected '{}' in {}", separator, args))
+}
+
+fn parse_hex(value: &str) -> Result<usize> {
+    Ok(usize::from_str_radix(value, 16)?)
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return Err(anyhow!("Odd number of hex digits"));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| Ok(u8::from_str_radix(&hex[index..index + 2], 16)?))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::debug::Watchpoint;
+    use crate::emulator::gdb::{packet_checksum, GdbStub};
+    use crate::emulator::Emulator;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    fn emulator() -> Emulator {
+        let mut emulator = Emulator::builder().threaded_drawing(false).build();
+        // A ROM full of "mov r0, r0"
+        emulator.load_game_pak(&[0; 0x400], &[0; 0x100]).unwrap();
+        emulator
+    }
+
+    #[test]
+    fn reads_and_writes_registers() {
+        let mut emulator = emulator();
+        let mut stub = GdbStub::new(&mut emulator);
+        assert_eq!(stub.handle_packet("P3=78563412"), "OK");
+        assert_eq!(stub.handle_packet("p3"), "78563412");
+        // r0 stays zero
+        stub.handle_packet("P0=01000000");
+        assert_eq!(stub.handle_packet("p0"), "00000000");
+
+        let registers = stub.handle_packet("g");
+        assert_eq!(registers.len(), 65 * 8);
+        // The PC starts at the reset vector
+        assert_eq!(&registers[64 * 8..], "f0ffffff");
+    }
+
+    #[test]
+    fn reads_and_writes_memory() {
+        let mut emulator = emulator();
+        let mut stub = GdbStub::new(&mut emulator);
+        assert_eq!(stub.handle_packet("M5000000,4:deadbeef"), "OK");
+        assert_eq!(stub.handle_packet("m5000000,4"), "deadbeef");
+        assert_eq!(stub.handle_packet("m5000000"), "E01");
+    }
+
+    #[test]
+    fn ignores_unsupported_packets() {
+        let mut emulator = emulator();
+        let mut stub = GdbStub::new(&mut emulator);
+        assert_eq!(stub.handle_packet("vMustReplyEmpty"), "");
+        assert_eq!(stub.handle_packet("Z1,5000000,2"), "");
+    }
+
+    #[test]
+    fn sets_and_clears_watchpoints() {
+        let mut emulator = emulator();
+        let mut stub = GdbStub::new(&mut emulator);
+        assert_eq!(stub.handle_packet("Z2,5000000,4"), "OK");
+        assert_eq!(
+            stub.emulator.memory.borrow_mut().watchpoints_mut(),
+            &[Watchpoint::writes(0x05000000..0x05000004)]
+        );
+        assert_eq!(stub.handle_packet("z2,5000000,4"), "OK");
+        assert!(stub
+            .emulator
+            .memory
+            .borrow_mut()
+            .watchpoints_mut()
+            .is_empty());
+    }
+
+    fn send(stream: &mut TcpStream, data: &str) -> String {
+        let packet = format!("${}#{:02x}", data, packet_checksum(data.as_bytes()));
+        stream.write_all(packet.as_bytes()).unwrap();
+        let mut ack = [0];
+        stream.read_exact(&mut ack).unwrap();
+        assert_eq!(ack[0], b'+');
+        receive(stream)
+    }
+
+    fn receive(stream: &mut TcpStream) -> String {
+        let mut reply = vec![];
+        let mut byte = [0];
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            if byte[0] == b'#' {
+                break;
+            }
+            reply.push(byte[0]);
+        }
+        let mut checksum = [0; 2];
+        stream.read_exact(&mut checksum).unwrap();
+        String::from_utf8(reply[1..].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn steps_and_stops_at_breakpoints_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            ["?", "s", "p40", "Z0,fffffff8,2", "c", "p40", "D"]
+                .map(|packet| send(&mut stream, packet))
+        });
+
+        let mut emulator = emulator();
+        let (stream, _) = listener.accept().unwrap();
+        GdbStub::new(&mut emulator).serve(stream).unwrap();
+
+        let replies = client.join().unwrap();
+        assert_eq!(
+            replies,
+            [
+                "S05", "S05", // Each "mov r0, r0" is two bytes long
+                "f2ffffff", "OK", "S05", "f8ffffff", "OK"
+            ]
+        );
+    }
+}
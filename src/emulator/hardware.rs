@@ -1,4 +1,5 @@
-use super::memory::Memory;
+use super::memory::{Memory, WCR, WCR_EXPANSION_ONE_WAIT, WCR_ROM_ONE_WAIT};
+use super::movie::{InputLog, MovieStatus};
 use crate::emulator::cpu::Exception;
 use serde_derive::{Deserialize, Serialize};
 use std::cell::RefCell;
@@ -21,6 +22,7 @@ const T_IS_ZERO: u8 = 0x02;
 const T_ENABLED: u8 = 0x01;
 
 // SCR bits
+const S_KEY_INTERRUPT_DISABLE: u8 = 0x80;
 const S_SW_INIT: u8 = 0x20;
 const S_SW_READ: u8 = 0x10;
 const S_HW_READ: u8 = 0x04;
@@ -28,8 +30,14 @@ const S_HW_STAT: u8 = 0x02;
 const S_HW_ABORT: u8 = 0x01;
 
 const HARDWARE_READ_CYCLES: u64 = 10240;
+// How often to check for new button presses while the key interrupt is enabled.
+// A press can go unnoticed for up to 5ms, and movies get an input for every check.
+const KEY_POLL_CYCLES: u64 = 100_000;
+// Everything but the low battery and signature bits
+const BUTTON_MASK: u16 = 0xfffc;
 
 #[derive(Serialize, Deserialize)]
+#[serde(default)]
 pub struct HardwareState {
     cycle: u64,
     next_tick: u64,
@@ -38,6 +46,9 @@ pub struct HardwareState {
     zero_flag: bool,
     interrupt_requested: bool,
     software_read_counter: Option<u8>,
+    next_key_poll: u64,
+    pressed_buttons: u16,
+    key_interrupt_requested: bool,
 }
 impl Default for HardwareState {
     fn default() -> Self {
@@ -49,6 +60,9 @@ impl Default for HardwareState {
             zero_flag: false,
             interrupt_requested: false,
             software_read_counter: None,
+            next_key_poll: u64::MAX,
+            pressed_buttons: 0,
+            key_interrupt_requested: false,
         }
     }
 }
@@ -61,8 +75,12 @@ pub struct Hardware {
     zero_flag: bool,
     interrupt_requested: bool,
     software_read_counter: Option<u8>,
+    next_key_poll: u64,
+    pressed_buttons: u16,
+    key_interrupt_requested: bool,
     memory: Rc<RefCell<Memory>>,
-    controller_state: Option<Arc<AtomicU16>>,
+    controller_state: Arc<AtomicU16>,
+    input_log: InputLog,
 }
 impl Hardware {
     pub fn new(memory: Rc<RefCell<Memory>>) -> Hardware {
@@ -75,8 +93,12 @@ impl Hardware {
             zero_flag: state.zero_flag,
             interrupt_requested: state.interrupt_requested,
             software_read_counter: state.software_read_counter,
+            next_key_poll: state.next_key_poll,
+            pressed_buttons: state.pressed_buttons,
+            key_interrupt_requested: state.key_interrupt_requested,
             memory,
-            controller_state: None,
+            controller_state: Arc::new(AtomicU16::new(0)),
+            input_log: InputLog::Inactive,
         }
     }
 
@@ -84,6 +106,10 @@ impl Hardware {
         self.load_state(&HardwareState::default());
         let mut memory = self.memory.borrow_mut();
         memory.write_byte(TCR, 0);
+        memory.write_byte(WCR, 0);
+        memory.update_wait_control();
+        // Games have to ask for the key interrupt
+        memory.write_byte(SCR, S_KEY_INTERRUPT_DISABLE);
         memory.write_halfword(TLR, 0xff);
         memory.write_halfword(THR, 0xff);
     }
@@ -97,6 +123,9 @@ impl Hardware {
             zero_flag: self.zero_flag,
             interrupt_requested: self.interrupt_requested,
             software_read_counter: self.software_read_counter,
+            next_key_poll: self.next_key_poll,
+            pressed_buttons: self.pressed_buttons,
+            key_interrupt_requested: self.key_interrupt_requested,
         }
     }
 
@@ -108,23 +137,41 @@ impl Hardware {
         self.zero_flag = state.zero_flag;
         self.interrupt_requested = state.interrupt_requested;
         self.software_read_counter = state.software_read_counter;
+        self.next_key_poll = state.next_key_poll;
+        self.pressed_buttons = state.pressed_buttons;
+        self.key_interrupt_requested = state.key_interrupt_requested;
     }
 
     pub fn claim_controller_state(&mut self) -> Arc<AtomicU16> {
-        let controller_state = Arc::new(AtomicU16::new(0));
-        self.controller_state = Some(Arc::clone(&controller_state));
-        controller_state
+        Arc::clone(&self.controller_state)
+    }
+
+    // Swaps out what's recording or replaying controller reads, and returns the old one
+    pub fn replace_input_log(&mut self, input_log: InputLog) -> InputLog {
+        std::mem::replace(&mut self.input_log, input_log)
+    }
+
+    pub fn seek_input_log(&mut self, cycle: u64) {
+        self.input_log.seek(cycle);
+    }
+
+    pub fn movie_status(&self) -> MovieStatus {
+        self.input_log.status()
     }
 
     // When is the next time that this module will do something that affects other modules?
     pub fn next_event(&self) -> u64 {
-        self.next_tick.min(self.next_controller_read)
+        self.next_tick
+            .min(self.next_controller_read)
+            .min(self.next_key_poll)
     }
 
-    // Get any unacknowledged interrupt from this module
+    // Get any unacknowledged interrupt from this module, highest level first
     pub fn active_interrupt(&self) -> Option<Exception> {
         if self.interrupt_requested {
             Some(Exception::interrupt(0xfe10, 1))
+        } else if self.key_interrupt_requested {
+            Some(Exception::interrupt(0xfe00, 0))
         } else {
             None
         }
@@ -146,8 +193,15 @@ impl Hardware {
             // A game is attempting to read controller input
             self.handle_controller_read();
         }
+        if address == WCR {
+            // Drop the bits that don't exist, and let memory know how long the game pak takes now
+            let mut memory = self.memory.borrow_mut();
+            let wcr = memory.read_byte(WCR);
+            memory.write_byte(WCR, wcr & (WCR_ROM_ONE_WAIT | WCR_EXPANSION_ONE_WAIT));
+            memory.update_wait_control();
+        }
         // The CPU only needs to stop what it's doing if an interrupt is active
-        !self.interrupt_requested
+        !self.interrupt_requested && !self.key_interrupt_requested
     }
 
     fn update_timer_settings(&mut self) {
@@ -172,10 +226,19 @@ impl Hardware {
     }
 
     fn handle_controller_read(&mut self) {
-        let mut memory = self.memory.borrow_mut();
+        let memory = Rc::clone(&self.memory);
+        let mut memory = memory.borrow_mut();
         let value = memory.read_byte(SCR);
         let mut new_value = value | S_HW_READ;
 
+        // Setting K-Int-Inh both disables and acknowledges the key interrupt
+        if value & S_KEY_INTERRUPT_DISABLE != 0 {
+            self.key_interrupt_requested = false;
+            self.next_key_poll = u64::MAX;
+        } else if self.next_key_poll == u64::MAX {
+            self.next_key_poll = self.cycle + KEY_POLL_CYCLES;
+        }
+
         // hardware reads
         if value & S_HW_ABORT != 0 {
             // hardware read was cancelled
@@ -212,7 +275,10 @@ impl Hardware {
 
     pub fn run(&mut self, target_cycle: u64) {
         while self.cycle < target_cycle {
-            self.cycle = std::cmp::min(target_cycle, self.next_tick);
+            self.cycle = target_cycle.min(self.next_tick).min(self.next_key_poll);
+            if self.cycle == self.next_key_poll {
+                self.poll_keys();
+            }
             if self.cycle == self.next_tick {
                 let old_timer_value = self.read_timer();
                 let new_timer_value = if old_timer_value == 0 {
@@ -239,7 +305,8 @@ impl Hardware {
         self.correct_tcr();
         if self.cycle >= self.next_controller_read {
             // hardware read completed
-            let mut memory = self.memory.borrow_mut();
+            let memory = Rc::clone(&self.memory);
+            let mut memory = memory.borrow_mut();
             self.read_controller(&mut memory);
 
             // Mark in SCR that we've finished reading
@@ -250,6 +317,17 @@ impl Hardware {
         }
     }
 
+    // Raise the key interrupt if any button went down since the last poll
+    fn poll_keys(&mut self) {
+        let live_state = self.controller_state.load(Ordering::Relaxed);
+        let pressed_buttons = self.input_log.sample(self.cycle, live_state) & BUTTON_MASK;
+        if pressed_buttons & !self.pressed_buttons != 0 {
+            self.key_interrupt_requested = true;
+        }
+        self.pressed_buttons = pressed_buttons;
+        self.next_key_poll = self.cycle + KEY_POLL_CYCLES;
+    }
+
     fn read_timer(&self) -> u16 {
         let memory = self.memory.borrow();
         memory.read_halfword(THR) << 8 | memory.read_halfword(TLR)
@@ -292,11 +370,9 @@ impl Hardware {
         memory.write_byte(TCR, tcr);
     }
 
-    fn read_controller(&self, memory: &mut Memory) {
-        let input_state = match self.controller_state.as_ref() {
-            Some(state) => state.load(Ordering::Relaxed),
-            None => 0x0000,
-        };
+    fn read_controller(&mut self, memory: &mut Memory) {
+        let live_state = self.controller_state.load(Ordering::Relaxed);
+        let input_state = self.input_log.sample(self.cycle, live_state);
         let sdlr = input_state & 0xff;
         let sdhr = (input_state >> 8) & 0xff;
         memory.write_halfword(SDLR, sdlr);
@@ -306,11 +382,14 @@ impl Hardware {
 
 #[cfg(test)]
 mod tests {
+    use crate::emulator::cpu::Exception;
     use crate::emulator::hardware::{
-        Hardware, HARDWARE_READ_CYCLES, SCR, SDHR, SDLR, S_HW_ABORT, S_HW_READ, S_HW_STAT,
-        S_SW_INIT, S_SW_READ, TCR, THR, TLR, T_CLEAR_ZERO, T_ENABLED, T_INTERRUPT, T_IS_ZERO,
+        Hardware, HARDWARE_READ_CYCLES, KEY_POLL_CYCLES, SCR, SDHR, SDLR, S_HW_ABORT, S_HW_READ,
+        S_HW_STAT, S_KEY_INTERRUPT_DISABLE, S_SW_INIT, S_SW_READ, TCR, THR, TLR, T_CLEAR_ZERO,
+        T_ENABLED, T_INTERRUPT, T_IS_ZERO,
     };
-    use crate::emulator::memory::Memory;
+    use crate::emulator::memory::{Memory, WCR};
+    use crate::emulator::movie::{InputLog, Movie, MovieStatus};
     use std::cell::RefCell;
     use std::rc::Rc;
     use std::sync::atomic::Ordering;
@@ -338,6 +417,19 @@ mod tests {
         (hardware, memory)
     }
 
+    #[test]
+    fn wcr_only_keeps_its_wait_bits() {
+        let (mut hardware, memory) = get_hardware();
+        assert_eq!(memory.borrow().wait_cycles(0x07000000, 4), 4);
+
+        memory.borrow_mut().write_byte(WCR, 0xff);
+        hardware.process_event(WCR);
+        assert_eq!(memory.borrow().read_byte(WCR), 0x03);
+        assert_eq!(memory.borrow().wait_cycles(0x07000000, 4), 2);
+        assert_eq!(memory.borrow().wait_cycles(0x04000000, 2), 1);
+        assert_eq!(memory.borrow().wait_cycles(0x05000000, 4), 0);
+    }
+
     #[test]
     fn does_nothing_interesting_when_timer_is_off() {
         let (mut hardware, memory) = get_hardware();
@@ -458,18 +550,24 @@ mod tests {
         state.store(0x1002, Ordering::Relaxed);
 
         // Kick off the hardware read
-        set_scr(&mut hardware, &memory, S_HW_READ);
+        set_scr(&mut hardware, &memory, S_KEY_INTERRUPT_DISABLE | S_HW_READ);
         assert_eq!(hardware.next_event(), HARDWARE_READ_CYCLES);
         assert_eq!(memory.borrow().read_byte(SDHR), 0x00);
         assert_eq!(memory.borrow().read_byte(SDLR), 0x00);
-        assert_eq!(memory.borrow().read_byte(SCR), S_HW_READ | S_HW_STAT);
+        assert_eq!(
+            memory.borrow().read_byte(SCR),
+            S_KEY_INTERRUPT_DISABLE | S_HW_READ | S_HW_STAT
+        );
 
         // Wait for the hardware read to complete
         hardware.run(hardware.next_event());
         assert_eq!(hardware.next_event(), u64::MAX);
         assert_eq!(memory.borrow().read_byte(SDHR), 0x10);
         assert_eq!(memory.borrow().read_byte(SDLR), 0x02);
-        assert_eq!(memory.borrow().read_byte(SCR), S_HW_READ);
+        assert_eq!(
+            memory.borrow().read_byte(SCR),
+            S_KEY_INTERRUPT_DISABLE | S_HW_READ
+        );
     }
 
     #[test]
@@ -481,28 +579,37 @@ mod tests {
         state.store(0x1002, Ordering::Relaxed);
 
         // Kick off the hardware read
-        set_scr(&mut hardware, &memory, S_HW_READ);
+        set_scr(&mut hardware, &memory, S_KEY_INTERRUPT_DISABLE | S_HW_READ);
         assert_eq!(hardware.next_event(), HARDWARE_READ_CYCLES);
         assert_eq!(memory.borrow().read_byte(SDHR), 0x00);
         assert_eq!(memory.borrow().read_byte(SDLR), 0x00);
-        assert_eq!(memory.borrow().read_byte(SCR), S_HW_READ | S_HW_STAT);
+        assert_eq!(
+            memory.borrow().read_byte(SCR),
+            S_KEY_INTERRUPT_DISABLE | S_HW_READ | S_HW_STAT
+        );
 
         // Partway through, kick off ANOTHER HARDWARE READ!!!
         hardware.run(HARDWARE_READ_CYCLES / 2);
-        set_scr(&mut hardware, &memory, S_HW_READ);
+        set_scr(&mut hardware, &memory, S_KEY_INTERRUPT_DISABLE | S_HW_READ);
 
         // assert we're still in the middle of the original hardware read
         assert_eq!(hardware.next_event(), HARDWARE_READ_CYCLES);
         assert_eq!(memory.borrow().read_byte(SDHR), 0x00);
         assert_eq!(memory.borrow().read_byte(SDLR), 0x00);
-        assert_eq!(memory.borrow().read_byte(SCR), S_HW_READ | S_HW_STAT);
+        assert_eq!(
+            memory.borrow().read_byte(SCR),
+            S_KEY_INTERRUPT_DISABLE | S_HW_READ | S_HW_STAT
+        );
 
         // Wait for the hardware read to complete
         hardware.run(hardware.next_event());
         assert_eq!(hardware.next_event(), u64::MAX);
         assert_eq!(memory.borrow().read_byte(SDHR), 0x10);
         assert_eq!(memory.borrow().read_byte(SDLR), 0x02);
-        assert_eq!(memory.borrow().read_byte(SCR), S_HW_READ);
+        assert_eq!(
+            memory.borrow().read_byte(SCR),
+            S_KEY_INTERRUPT_DISABLE | S_HW_READ
+        );
     }
 
     #[test]
@@ -514,21 +621,27 @@ mod tests {
         state.store(0x1002, Ordering::Relaxed);
 
         // Kick off the hardware read
-        set_scr(&mut hardware, &memory, S_HW_READ);
+        set_scr(&mut hardware, &memory, S_KEY_INTERRUPT_DISABLE | S_HW_READ);
         assert_eq!(hardware.next_event(), HARDWARE_READ_CYCLES);
         assert_eq!(memory.borrow().read_byte(SDHR), 0x00);
         assert_eq!(memory.borrow().read_byte(SDLR), 0x00);
-        assert_eq!(memory.borrow().read_byte(SCR), S_HW_READ | S_HW_STAT);
+        assert_eq!(
+            memory.borrow().read_byte(SCR),
+            S_KEY_INTERRUPT_DISABLE | S_HW_READ | S_HW_STAT
+        );
 
         // run, but abort the hardware read before it should go off
         hardware.run(HARDWARE_READ_CYCLES / 2);
-        set_scr(&mut hardware, &memory, S_HW_ABORT);
+        set_scr(&mut hardware, &memory, S_KEY_INTERRUPT_DISABLE | S_HW_ABORT);
 
         hardware.run(HARDWARE_READ_CYCLES);
         assert_eq!(hardware.next_event(), u64::MAX);
         assert_eq!(memory.borrow().read_byte(SDHR), 0x00);
         assert_eq!(memory.borrow().read_byte(SDLR), 0x00);
-        assert_eq!(memory.borrow().read_byte(SCR), S_HW_READ | S_HW_ABORT);
+        assert_eq!(
+            memory.borrow().read_byte(SCR),
+            S_KEY_INTERRUPT_DISABLE | S_HW_READ | S_HW_ABORT
+        );
     }
 
     #[test]
@@ -552,4 +665,84 @@ mod tests {
         assert_eq!(memory.borrow().read_byte(SDHR), 0x10);
         assert_eq!(memory.borrow().read_byte(SDLR), 0x02);
     }
+
+    #[test]
+    fn raises_the_key_interrupt_when_a_button_goes_down() {
+        let (mut hardware, memory) = get_hardware();
+        hardware.init();
+        let state = hardware.claim_controller_state();
+
+        // Disabled after a reset
+        state.store(0x1002, Ordering::Relaxed);
+        assert_eq!(hardware.next_event(), u64::MAX);
+        hardware.run(KEY_POLL_CYCLES * 2);
+        assert!(hardware.active_interrupt().is_none());
+
+        // Once enabled, the next poll sees start go down
+        set_scr(&mut hardware, &memory, 0);
+        hardware.run(hardware.next_event());
+        assert_eq!(
+            hardware.active_interrupt(),
+            Some(Exception::interrupt(0xfe00, 0))
+        );
+
+        // Survives a save state
+        let saved = hardware.save_state();
+        let (mut loaded, _memory) = get_hardware();
+        loaded.load_state(&saved);
+        assert!(loaded.active_interrupt().is_some());
+
+        // Setting K-Int-Inh acknowledges it
+        set_scr(&mut hardware, &memory, S_KEY_INTERRUPT_DISABLE);
+        assert!(hardware.active_interrupt().is_none());
+        assert_eq!(hardware.next_event(), u64::MAX);
+
+        // Holding start doesn't raise it again, but pressing another button does
+        set_scr(&mut hardware, &memory, 0);
+        hardware.run(hardware.next_event());
+        assert!(hardware.active_interrupt().is_none());
+        state.store(0x1006, Ordering::Relaxed);
+        hardware.run(hardware.next_event());
+        assert!(hardware.active_interrupt().is_some());
+    }
+
+    #[test]
+    fn replays_recorded_controller_reads() {
+        let movie = Movie {
+            rom_sha1: None,
+            from_power_on: true,
+            start_state: vec![],
+            inputs: vec![],
+        };
+
+        let (mut hardware, memory) = get_hardware();
+        hardware
+            .claim_controller_state()
+            .store(0x1002, Ordering::Relaxed);
+        hardware.replace_input_log(InputLog::Recording(movie));
+        set_scr(&mut hardware, &memory, S_HW_READ);
+        hardware.run(hardware.next_event());
+        let movie = match hardware.replace_input_log(InputLog::Inactive) {
+            InputLog::Recording(movie) => movie,
+            _ => panic!("Not recording"),
+        };
+        assert_eq!(movie.inputs.len(), 1);
+        assert_eq!(movie.inputs[0].cycle, HARDWARE_READ_CYCLES);
+
+        // Whatever the player is pressing now, the game sees what was recorded
+        let (mut hardware, memory) = get_hardware();
+        hardware
+            .claim_controller_state()
+            .store(0x0002, Ordering::Relaxed);
+        hardware.replace_input_log(InputLog::Playing {
+            movie,
+            next: 0,
+            desynced_at: None,
+        });
+        set_scr(&mut hardware, &memory, S_HW_READ);
+        hardware.run(hardware.next_event());
+        assert_eq!(memory.borrow().read_byte(SDHR), 0x10);
+        assert_eq!(memory.borrow().read_byte(SDLR), 0x02);
+        assert_eq!(hardware.movie_status(), MovieStatus::Finished);
+    }
 }
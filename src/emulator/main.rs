@@ -3,7 +3,7 @@ use std::cell::RefCell;
 use std::env;
 use std::io::Read;
 use vvb::emulator::memory::{Memory, Region};
-use vvb::emulator::video::drawing::DrawingProcess;
+use vvb::emulator::video::drawing::{DrawingProcess, ROW_GROUPS};
 use vvb::emulator::video::Eye;
 
 fn get_filepath() -> Result<String> {
@@ -34,7 +34,7 @@ fn main() -> Result<()> {
     let start = std::time::Instant::now();
 
     for _ in 0..ITERATIONS {
-        xp.start(memory.borrow());
+        xp.start(memory.borrow(), 0..ROW_GROUPS);
         xp.draw_eye(&mut memory.borrow_mut(), Eye::Left, left_buf_address);
         xp.draw_eye(&mut memory.borrow_mut(), Eye::Right, right_buf_address);
     }
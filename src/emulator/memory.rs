@@ -1,10 +1,13 @@
 use crate::emulator::cpu::Event;
+use crate::emulator::debug::{Access, Watchpoint};
+use crate::emulator::expansion::{Expansion, EXPANSION_START};
 use anyhow::Result;
 use log::info;
 use serde_derive::{Deserialize, Serialize};
 use std::convert::TryInto;
+use std::sync::Mutex;
 
-#[derive(Copy, Clone, Serialize, Deserialize)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Region {
     Vram = 0,
     Audio = 1,
@@ -18,6 +21,24 @@ const AUDIO_SIZE: usize = 0x00000800;
 const HARDWARE_SIZE: usize = 0x00000040;
 const DRAM_SIZE: usize = 0x00010000;
 
+// The wait control register, which sets how fast the game pak's ROM and expansion area are
+pub const WCR: usize = 0x02000024;
+pub const WCR_ROM_ONE_WAIT: u8 = 0x01;
+pub const WCR_EXPANSION_ONE_WAIT: u8 = 0x02;
+
+// How many bytes each region's bus moves per trip, and how many wait states each trip costs,
+// indexed by the top byte of the address. The game pak's areas depend on WCR.
+const BUS_TIMINGS: [(usize, u64); 8] = [
+    (2, 1), // VIP
+    (1, 1), // VSU
+    (1, 1), // Hardware
+    (2, 0), // Unmapped
+    (2, 2), // Game pak expansion
+    (2, 0), // WRAM
+    (2, 2), // Game pak RAM
+    (2, 2), // Game pak ROM
+];
+
 struct MemoryRegion {
     kind: Region,
     value: Vec<u8>,
@@ -95,6 +116,17 @@ impl MemoryRegion {
 
 pub struct Memory {
     regions: [Option<MemoryRegion>; 8],
+    watchpoints: Vec<Watchpoint>,
+    // Only the CPU's own accesses are watched, so this is only set while it runs
+    watching: bool,
+    // Behind a mutex so reads can record hits, and the drawing threads can still share VRAM
+    watch_hit: Mutex<Option<(usize, Access, u32)>>,
+    // Whatever's plugged into the game pak's expansion area
+    expansion: Option<Box<dyn Expansion>>,
+    // While paused, the expansion can still be read, but ignores writes and doesn't run
+    expansion_paused: bool,
+    // BUS_TIMINGS with WCR applied, so fetches don't have to decode it every time
+    bus_timings: [(usize, u64); 8],
 }
 impl Default for Memory {
     fn default() -> Self {
@@ -109,11 +141,17 @@ impl Memory {
                 Some(MemoryRegion::new(Region::Audio, AUDIO_SIZE)),
                 Some(MemoryRegion::new(Region::Hardware, HARDWARE_SIZE)),
                 None,
-                None, // Game Pak Expansion (never used)
+                None, // Game Pak Expansion (see expansion)
                 Some(MemoryRegion::new(Region::Dram, DRAM_SIZE)),
                 None, // Sram (loaded later)
                 None, // Rom (loaded later)
             ],
+            watchpoints: vec![],
+            watching: false,
+            watch_hit: Mutex::new(None),
+            expansion: None,
+            expansion_paused: false,
+            bus_timings: BUS_TIMINGS,
         }
     }
     pub fn vram_only() -> Self {
@@ -128,6 +166,12 @@ impl Memory {
                 None,
                 None,
             ],
+            watchpoints: vec![],
+            watching: false,
+            watch_hit: Mutex::new(None),
+            expansion: None,
+            expansion_paused: false,
+            bus_timings: BUS_TIMINGS,
         }
     }
 
@@ -158,7 +202,8 @@ impl Memory {
         *self.mut_region(Region::Rom) = None;
     }
 
-    fn init(&mut self) {
+    // Clears everything but the game pak, like turning the console off and on
+    pub fn init(&mut self) {
         if let Some(region) = self.mut_region(Region::Vram) {
             region.clear();
         }
@@ -171,42 +216,195 @@ impl Memory {
         if let Some(region) = self.mut_region(Region::Dram) {
             region.clear();
         }
+        self.update_wait_control();
     }
 
     pub fn write_byte(&mut self, address: usize, value: u8) -> Option<Event> {
+        if self.watching {
+            self.watch(address, 1, Access::Write, value as u32);
+        }
         match self.mut_region_of(address) {
             Some(region) => region.write_byte(address, value),
-            None => None,
+            None => {
+                if let Some((expansion, offset)) = self.expansion_mut(address) {
+                    expansion.write_byte(offset, value);
+                }
+                None
+            }
         }
     }
     pub fn write_halfword(&mut self, address: usize, value: u16) -> Option<Event> {
+        if self.watching {
+            self.watch(address, 2, Access::Write, value as u32);
+        }
         match self.mut_region_of(address) {
             Some(region) => region.write_halfword(address, value),
-            None => None,
+            None => {
+                if let Some((expansion, offset)) = self.expansion_mut(address) {
+                    expansion.write_halfword(offset, value);
+                }
+                None
+            }
         }
     }
     pub fn write_word(&mut self, address: usize, value: u32) -> Option<Event> {
+        if self.watching {
+            self.watch(address, 4, Access::Write, value);
+        }
         match self.mut_region_of(address) {
             Some(region) => region.write_word(address, value),
-            None => None,
+            None => {
+                if let Some((expansion, offset)) = self.expansion_mut(address) {
+                    expansion.write_word(offset, value);
+                }
+                None
+            }
         }
     }
     pub fn read_byte(&self, address: usize) -> u8 {
-        match self.get_region_of(address) {
+        let value = match self.get_region_of(address) {
             Some(region) => region.read_byte(address),
-            None => 0,
+            None => self
+                .expansion(address)
+                .map_or(0, |(expansion, offset)| expansion.read_byte(offset)),
+        };
+        if self.watching {
+            self.watch(address, 1, Access::Read, value as u32);
         }
+        value
     }
     pub fn read_halfword(&self, address: usize) -> u16 {
-        match self.get_region_of(address) {
+        let value = match self.get_region_of(address) {
             Some(region) => region.read_halfword(address),
-            None => 0,
+            None => self
+                .expansion(address)
+                .map_or(0, |(expansion, offset)| expansion.read_halfword(offset)),
+        };
+        if self.watching {
+            self.watch(address, 2, Access::Read, value as u32);
         }
+        value
     }
     pub fn read_word(&self, address: usize) -> u32 {
-        match self.get_region_of(address) {
+        let value = match self.get_region_of(address) {
             Some(region) => region.read_word(address),
-            None => 0,
+            None => self
+                .expansion(address)
+                .map_or(0, |(expansion, offset)| expansion.read_word(offset)),
+        };
+        if self.watching {
+            self.watch(address, 4, Access::Read, value);
+        }
+        value
+    }
+
+    // How many extra cycles the CPU waits on the bus to read or write size bytes.
+    // Accesses wider than a region's bus take several trips.
+    pub fn wait_cycles(&self, address: usize, size: usize) -> u64 {
+        let (width, wait_states) = self.bus_timings[(address >> 24) & 0x07];
+        size.div_ceil(width) as u64 * wait_states
+    }
+
+    // Has to be called whenever WCR changes, including when a state is loaded
+    pub fn update_wait_control(&mut self) {
+        let wcr = self
+            .get_region_of(WCR)
+            .map_or(0, |region| region.read_byte(WCR));
+        for (index, one_wait) in [(4, WCR_EXPANSION_ONE_WAIT), (7, WCR_ROM_ONE_WAIT)] {
+            self.bus_timings[index].1 = if wcr & one_wait != 0 {
+                1
+            } else {
+                BUS_TIMINGS[index].1
+            };
+        }
+    }
+
+    // Reads an instruction, which never trips a watchpoint
+    pub fn fetch_halfword(&self, address: usize) -> u16 {
+        match self.get_region_of(address) {
+            Some(region) => region.read_halfword(address),
+            None => self
+                .expansion(address)
+                .map_or(0, |(expansion, offset)| expansion.read_halfword(offset)),
+        }
+    }
+
+    // Plugs hardware into the expansion area, and returns whatever was there before
+    pub fn replace_expansion(
+        &mut self,
+        expansion: Option<Box<dyn Expansion>>,
+    ) -> Option<Box<dyn Expansion>> {
+        std::mem::replace(&mut self.expansion, expansion)
+    }
+
+    // For running frames which are going to be thrown away, since the expansion isn't part of save states
+    pub fn set_expansion_paused(&mut self, paused: bool) {
+        self.expansion_paused = paused;
+    }
+
+    pub fn run_expansion(&mut self, cycle: u64) {
+        if self.expansion_paused {
+            return;
+        }
+        if let Some(expansion) = &mut self.expansion {
+            expansion.run(cycle);
+        }
+    }
+
+    pub fn expansion_next_event(&self) -> u64 {
+        if self.expansion_paused {
+            return u64::MAX;
+        }
+        self.expansion
+            .as_ref()
+            .map_or(u64::MAX, |expansion| expansion.next_event())
+    }
+
+    pub fn expansion_interrupt_requested(&self) -> bool {
+        self.expansion
+            .as_ref()
+            .is_some_and(|expansion| expansion.interrupt_requested())
+    }
+
+    pub fn acknowledge_expansion_interrupt(&mut self) {
+        if let Some(expansion) = &mut self.expansion {
+            expansion.acknowledge_interrupt();
+        }
+    }
+
+    pub fn replace_watchpoints(&mut self, watchpoints: Vec<Watchpoint>) -> Vec<Watchpoint> {
+        std::mem::replace(&mut self.watchpoints, watchpoints)
+    }
+
+    pub fn watchpoints_mut(&mut self) -> &mut Vec<Watchpoint> {
+        &mut self.watchpoints
+    }
+
+    // Returns whether there's anything to watch
+    pub fn start_watching(&mut self) -> bool {
+        self.watching = !self.watchpoints.is_empty();
+        *self.watch_hit.get_mut().unwrap() = None;
+        self.watching
+    }
+
+    pub fn stop_watching(&mut self) {
+        self.watching = false;
+    }
+
+    // The first watched access since the last call
+    pub fn take_watch_hit(&self) -> Option<(usize, Access, u32)> {
+        self.watch_hit.lock().unwrap().take()
+    }
+
+    fn watch(&self, address: usize, size: usize, access: Access, value: u32) {
+        let mut watch_hit = self.watch_hit.lock().unwrap();
+        if watch_hit.is_none()
+            && self
+                .watchpoints
+                .iter()
+                .any(|watchpoint| watchpoint.matches(address, size, access, value))
+        {
+            *watch_hit = Some((address, access, value));
         }
     }
 
@@ -228,6 +426,25 @@ impl Memory {
     fn mut_region(&mut self, region: Region) -> &mut Option<MemoryRegion> {
         &mut self.regions[region as usize]
     }
+    fn expansion(&self, address: usize) -> Option<(&dyn Expansion, usize)> {
+        if (address >> 24) & 0x07 != 4 {
+            return None;
+        }
+        let offset = address.wrapping_sub(EXPANSION_START) & 0x00ffffff;
+        self.expansion
+            .as_deref()
+            .map(|expansion| (expansion, offset))
+    }
+    fn expansion_mut(&mut self, address: usize) -> Option<(&mut dyn Expansion, usize)> {
+        if (address >> 24) & 0x07 != 4 || self.expansion_paused {
+            return None;
+        }
+        let offset = address.wrapping_sub(EXPANSION_START) & 0x00ffffff;
+        match &mut self.expansion {
+            Some(expansion) => Some((expansion.as_mut(), offset)),
+            None => None,
+        }
+    }
     fn get_region_of(&self, address: usize) -> Option<&MemoryRegion> {
         let index = (address >> 24) & 0x07;
         self.regions[index].as_ref()
@@ -240,6 +457,7 @@ impl Memory {
 
 #[cfg(test)]
 mod tests {
+    use crate::emulator::expansion::MemoryExpansion;
     use crate::emulator::memory::Memory;
 
     #[test]
@@ -365,4 +583,17 @@ mod tests {
         assert_eq!(memory.read_word(0x06000004), 0);
         assert_eq!(memory.read_word(0x06002000), 0x12345678);
     }
+
+    #[test]
+    fn paused_expansions_ignore_writes() {
+        let mut memory = Memory::new();
+        memory.replace_expansion(Some(Box::new(MemoryExpansion::new(0x100))));
+        memory.write_word(0x04000000, 0x12345678);
+        memory.set_expansion_paused(true);
+        memory.write_word(0x04000000, 0x9abcdef0);
+        assert_eq!(memory.read_word(0x04000000), 0x12345678);
+        memory.set_expansion_paused(false);
+        memory.write_word(0x04000000, 0x9abcdef0);
+        assert_eq!(memory.read_word(0x04000000), 0x9abcdef0);
+    }
 }
@@ -1,40 +1,94 @@
 pub mod audio;
 use audio::{AudioController, AudioPlayer};
+pub mod cheat;
+use cheat::CheatList;
 mod cpu;
-use cpu::{Cpu, Event, EventHandler};
+use cpu::{Cpu, Event, EventHandler, Exception};
+pub mod debug;
+use debug::{Breakpoint, DebugHit, Watchpoint};
+pub mod disasm;
+pub mod expansion;
+use expansion::Expansion;
+pub mod game_pak;
+pub mod gdb;
+use game_pak::patch::{self, Patch};
+use game_pak::GameInfo;
 mod hardware;
 use hardware::Hardware;
 pub mod memory;
 use memory::{Memory, Region};
+pub mod movie;
+use movie::{InputLog, Movie, MovieStatus};
+mod rewind;
+use rewind::Rewind;
+pub mod search;
+use search::{RamSearch, SearchFilter, SearchResult, SearchWidth};
 mod state;
-use state::{GlobalState, SaveStateData};
+use state::{FastState, GlobalState, SaveStateData};
+pub use state::{SaveStateMetadata, Thumbnail};
+pub mod trace;
+use trace::Tracer;
 pub mod video;
-use video::{Eye, FrameBufferConsumers, Video};
+use video::inspect::{self, Image, ObjectAttributes, Palette, WorldAttributes};
+use video::{Eye, FrameBufferConsumers, Video, CYCLES_PER_FRAME};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use log::{debug, info};
 use std::cell::RefCell;
 use std::cmp;
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
 use std::rc::Rc;
-use std::sync::atomic::AtomicU16;
+use std::sync::atomic::{AtomicU16, Ordering};
 use std::sync::Arc;
 
-pub struct Emulator {
-    cycle: u64,
-    tick_calls: u64,
-    memory: Rc<RefCell<Memory>>,
-    cpu: Cpu<EmulatorEventHandler>,
-    audio: Rc<RefCell<AudioController>>,
-    video: Rc<RefCell<Video>>,
-    hardware: Rc<RefCell<Hardware>>,
+pub struct EmulatorBuilder {
+    threaded_drawing: bool,
+    audio_buffer_size: usize,
+    controller_state: u16,
 }
-unsafe impl Send for Emulator {} // Never actually sent to other threads so it's fine
-impl Emulator {
-    fn new() -> Emulator {
+impl Default for EmulatorBuilder {
+    fn default() -> Self {
+        Self {
+            threaded_drawing: true,
+            audio_buffer_size: DEFAULT_AUDIO_BUFFER_SIZE,
+            controller_state: 0,
+        }
+    }
+}
+impl EmulatorBuilder {
+    // Draw each eye on its own worker thread (the default),
+    // or draw everything on the thread which calls Emulator::tick
+    pub fn threaded_drawing(mut self, threaded_drawing: bool) -> Self {
+        self.threaded_drawing = threaded_drawing;
+        self
+    }
+
+    // How many frames of audio the player can hold before the emulator has to wait for it
+    pub fn audio_buffer_size(mut self, audio_buffer_size: usize) -> Self {
+        self.audio_buffer_size = audio_buffer_size;
+        self
+    }
+
+    // The controller state which games see until someone updates it
+    pub fn controller_state(mut self, controller_state: u16) -> Self {
+        self.controller_state = controller_state;
+        self
+    }
+
+    pub fn build(self) -> Emulator {
         let memory = Rc::new(RefCell::new(Memory::new()));
         let audio = Rc::new(RefCell::new(AudioController::new(Rc::clone(&memory))));
-        let video = Rc::new(RefCell::new(Video::new(Rc::clone(&memory))));
+        let video = Rc::new(RefCell::new(Video::new(
+            Rc::clone(&memory),
+            self.threaded_drawing,
+        )));
         let hardware = Rc::new(RefCell::new(Hardware::new(Rc::clone(&memory))));
+        hardware
+            .borrow_mut()
+            .claim_controller_state()
+            .store(self.controller_state, Ordering::Relaxed);
         let handler = EmulatorEventHandler {
             audio: Rc::clone(&audio),
             video: Rc::clone(&video),
@@ -49,36 +103,158 @@ impl Emulator {
             audio,
             video,
             hardware,
+            audio_buffer_size: self.audio_buffer_size,
+            game_info: None,
+            rewind: None,
+            cheats: CheatList::default(),
+            run_ahead_frames: 0,
+            speed: 1.,
+            frame_skipping: true,
         }
     }
+}
+
+// What happened during a call to run_frame
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameResult {
+    pub cycles: u64,
+    pub frames_drawn: u64,
+    pub audio_samples: u64,
+    pub cpu_halted: bool,
+    // Set if the frame was cut short by a breakpoint or watchpoint
+    pub debug_hit: Option<DebugHit>,
+}
+
+struct RunResult {
+    cpu_halted: bool,
+    debug_hit: Option<DebugHit>,
+}
+
+// Raised by hardware in the game pak's expansion area
+const GAME_PAK_INTERRUPT: u16 = 0xfe30;
+
+// Enough room for a few frames of audio between drains
+const DEFAULT_AUDIO_BUFFER_SIZE: usize = 4;
+
+// Slow motion and fast-forward limits for set_speed
+pub const MIN_SPEED: f32 = 0.25;
+pub const MAX_SPEED: f32 = 8.;
+
+// The memory regions which save states cover. The game pak's ROM never changes.
+const SAVED_REGIONS: [Region; 5] = [
+    Region::Vram,
+    Region::Audio,
+    Region::Hardware,
+    Region::Dram,
+    Region::Sram,
+];
+
+pub struct Emulator {
+    cycle: u64,
+    tick_calls: u64,
+    memory: Rc<RefCell<Memory>>,
+    cpu: Cpu<EmulatorEventHandler>,
+    audio: Rc<RefCell<AudioController>>,
+    video: Rc<RefCell<Video>>,
+    hardware: Rc<RefCell<Hardware>>,
+    audio_buffer_size: usize,
+    game_info: Option<GameInfo>,
+    rewind: Option<Rewind>,
+    cheats: CheatList,
+    run_ahead_frames: u64,
+    speed: f32,
+    frame_skipping: bool,
+}
+unsafe impl Send for Emulator {} // Never actually sent to other threads so it's fine
+impl Default for Emulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Emulator {
+    pub fn new() -> Emulator {
+        Self::builder().build()
+    }
+
+    pub fn builder() -> EmulatorBuilder {
+        EmulatorBuilder::default()
+    }
 
     pub fn claim_frame_buffer_consumers(&mut self) -> FrameBufferConsumers {
         self.video.borrow_mut().claim_frame_buffer_consumers()
     }
 
-    pub fn claim_audio_player(&mut self, buffer_size: usize, volume: f32) -> AudioPlayer {
-        self.audio.borrow_mut().claim_player(volume, buffer_size)
+    // The player holds as many frames of audio as the builder's audio_buffer_size
+    pub fn claim_audio_player(&mut self, volume: f32) -> AudioPlayer {
+        self.audio
+            .borrow_mut()
+            .claim_player(volume, self.audio_buffer_size)
+    }
+
+    // For frontends which only learn the buffer size after building the emulator
+    pub fn set_audio_buffer_size(&mut self, audio_buffer_size: usize) {
+        self.audio_buffer_size = audio_buffer_size;
     }
 
     pub fn claim_controller_state(&mut self) -> Arc<AtomicU16> {
         self.hardware.borrow_mut().claim_controller_state()
     }
 
-    pub fn load_game_pak(&mut self, rom: &[u8], sram: &[u8]) -> Result<()> {
-        self.memory.borrow_mut().load_game_pak(rom, sram)?;
+    // Accepts zipped ROMs, and ROMs which aren't a power of two in size
+    pub fn load_game_pak(&mut self, rom: &[u8], sram: &[u8]) -> Result<GameInfo> {
+        let rom = game_pak::unpack_rom(rom)?;
+        game_pak::check_rom_size(rom.len())?;
+        // Identify the ROM by its own bytes, not the mirrored image
+        let game_info = GameInfo::parse(&rom);
+        let rom = game_pak::mirror_rom(&rom);
+        self.memory.borrow_mut().load_game_pak(&rom, sram)?;
+        info!(
+            "Loaded \"{}\" ({}{}, v1.{}, sha1 {})",
+            game_info.title,
+            game_info.maker_code,
+            game_info.game_code,
+            game_info.version,
+            game_info.sha1_hex()
+        );
+        self.game_info = Some(game_info.clone());
         self.reset();
         info!("Game pak successfully loaded!");
-        Ok(())
+        Ok(game_info)
+    }
+
+    // Soft-patches the ROM before loading it, so the file on disk never changes
+    pub fn load_patched_game_pak(
+        &mut self,
+        rom: &[u8],
+        patches: &[Patch],
+        sram: &[u8],
+    ) -> Result<GameInfo> {
+        let rom = game_pak::unpack_rom(rom)?;
+        let rom = patch::apply_patches(&rom, patches)?;
+        for patch in patches {
+            info!("Applied patch {}", patch.name());
+        }
+        self.load_game_pak(&rom, sram)
     }
 
     pub fn unload_game_pak(&mut self) {
         self.memory.borrow_mut().unload_game_pak();
+        self.game_info = None;
         self.reset();
     }
 
+    // Details about the currently loaded game pak, if there is one
+    pub fn game_info(&self) -> Option<&GameInfo> {
+        self.game_info.as_ref()
+    }
+
     pub fn reset(&mut self) {
         self.cycle = 0;
         self.tick_calls = 0;
+        if let Some(rewind) = &mut self.rewind {
+            rewind.clear();
+        }
+        self.cheats.rearm();
         info!("Resetting CPU module...");
         self.cpu.init();
         info!("Resetting audio module...");
@@ -109,14 +285,22 @@ impl Emulator {
     }
 
     pub fn save_state(&self, filename: &str) -> Result<()> {
-        const REGIONS: [Region; 5] = [
-            Region::Vram,
-            Region::Audio,
-            Region::Hardware,
-            Region::Dram,
-            Region::Sram,
-        ];
+        fs::write(filename, self.save_state_to_vec(true)?)?;
+        Ok(())
+    }
 
+    pub fn save_state_to_vec(&self, include_thumbnail: bool) -> Result<Vec<u8>> {
+        let thumbnail = include_thumbnail
+            .then(|| Thumbnail::from_frame(&self.video.borrow().capture_frame(Eye::Left)));
+        let metadata = SaveStateMetadata::new(
+            self.game_info.as_ref().map(|info| info.sha1),
+            self.cycle,
+            thumbnail,
+        );
+        state::save_state(&metadata, &self.save_state_data())
+    }
+
+    fn save_state_data(&self) -> Vec<SaveStateData> {
         let memory = self.memory.borrow();
         let video = self.video.borrow();
         let hardware = self.hardware.borrow();
@@ -126,32 +310,61 @@ impl Emulator {
             cycle: self.cycle,
             tick_calls: self.tick_calls,
         })];
-        let memory_state = REGIONS.iter().copied().map(|region| {
-            SaveStateData::Memory(region, memory.read_region(region).unwrap().to_vec())
+        // Without a game pak there's no SRAM to save
+        let memory_state = SAVED_REGIONS.iter().copied().filter_map(|region| {
+            let data = memory.read_region(region)?.to_vec();
+            Some(SaveStateData::Memory(region, data))
         });
         data.extend(memory_state);
         data.push(SaveStateData::Cpu(Box::new(self.cpu.save_state())));
         data.push(SaveStateData::Audio(Box::new(audio.save_state())));
         data.push(SaveStateData::Video(video.save_state()));
         data.push(SaveStateData::Hardware(hardware.save_state()));
-
-        state::save_state(filename, &data)
+        data
     }
 
     pub fn load_state(&mut self, filename: &str) -> Result<()> {
+        self.load_state_from_slice(&fs::read(filename)?)
+    }
+
+    pub fn load_state_from_slice(&mut self, bytes: &[u8]) -> Result<()> {
+        let state = state::load_state(bytes)?;
+        if let Some(metadata) = &state.metadata {
+            self.check_state_rom(metadata)?;
+        }
+
         let mut memory = self.memory.borrow_mut();
+        // Check every region fits before changing anything, since older states don't say which game they're for
+        for datum in &state.data {
+            if let SaveStateData::Memory(region, data) = datum {
+                match memory.read_region(*region) {
+                    Some(target) if target.len() == data.len() => {}
+                    Some(target) => {
+                        return Err(anyhow!(
+                            "This state has {} bytes of {:?}, but the game pak has {}",
+                            data.len(),
+                            region,
+                            target.len()
+                        ))
+                    }
+                    None => return Err(anyhow!("This state has {:?}, but none is loaded", region)),
+                }
+            }
+        }
+
         let mut video = self.video.borrow_mut();
         let mut hardware = self.hardware.borrow_mut();
         let mut audio = self.audio.borrow_mut();
-        let data = state::load_state(filename)?;
-        for datum in data {
+        for datum in state.data {
             match datum {
                 SaveStateData::Global(state) => {
                     self.cycle = state.cycle;
                     self.tick_calls = state.tick_calls;
                 }
                 SaveStateData::Memory(region, data) => {
-                    memory.write_region(region).unwrap().copy_from_slice(&data)
+                    if let Some(target) = memory.write_region(region) {
+                        target.copy_from_slice(&data);
+                    }
                 }
                 SaveStateData::Cpu(state) => self.cpu.load_state(&state),
                 SaveStateData::Audio(state) => audio.load_state(&state),
@@ -159,11 +372,79 @@ impl Emulator {
                 SaveStateData::Hardware(state) => hardware.load_state(&state),
             }
         }
+        memory.update_wait_control();
+        hardware.seek_input_log(self.cycle);
         Ok(())
     }
 
-    pub fn tick(&mut self, nanoseconds: u64) -> Result<()> {
-        let cycles = nanoseconds / 50;
+    // A much faster way to save and load states, for when they never need to leave memory
+    pub fn save_fast_state(&self) -> FastState {
+        let memory = self.memory.borrow();
+        let video = self.video.borrow();
+        FastState {
+            global: GlobalState {
+                cycle: self.cycle,
+                tick_calls: self.tick_calls,
+            },
+            memory: SAVED_REGIONS
+                .iter()
+                .filter_map(|&region| Some((region, memory.read_region(region)?.to_vec())))
+                .collect(),
+            cpu: self.cpu.save_state(),
+            video: video.save_state(),
+            drawn_frames: video.save_drawn_frames(),
+            hardware: self.hardware.borrow().save_state(),
+            audio: Box::new(self.audio.borrow().save_state()),
+            cheats_applied: self.cheats.applied(),
+        }
+    }
+
+    pub fn load_fast_state(&mut self, state: &FastState) {
+        self.cycle = state.global.cycle;
+        self.tick_calls = state.global.tick_calls;
+        let mut memory = self.memory.borrow_mut();
+        for (region, data) in &state.memory {
+            if let Some(target) = memory.write_region(*region) {
+                target.copy_from_slice(data);
+            }
+        }
+        memory.update_wait_control();
+        self.cpu.load_state(&state.cpu);
+        let mut video = self.video.borrow_mut();
+        video.load_state(&state.video);
+        video.load_drawn_frames(&state.drawn_frames);
+        let mut hardware = self.hardware.borrow_mut();
+        hardware.load_state(&state.hardware);
+        hardware.seek_input_log(self.cycle);
+        self.audio.borrow_mut().load_state(&state.audio);
+        self.cheats.restore_applied(&state.cheats_applied);
+    }
+
+    // Reads a state's metadata without loading it. States from older versions have none.
+    pub fn read_state_metadata(bytes: &[u8]) -> Result<Option<SaveStateMetadata>> {
+        state::read_metadata(bytes)
+    }
+
+    fn check_state_rom(&self, metadata: &SaveStateMetadata) -> Result<()> {
+        let loaded = self.game_info.as_ref().map(|info| info.sha1);
+        if metadata.rom_sha1 == loaded {
+            return Ok(());
+        }
+        let describe = |sha1: Option<[u8; 20]>| match sha1 {
+            Some(sha1) => game_pak::sha1_hex(&sha1),
+            None => "no game pak".to_string(),
+        };
+        Err(anyhow!(
+            "This state was saved with a different ROM (saved with {}, but {} is loaded)",
+            describe(metadata.rom_sha1),
+            describe(loaded)
+        ))
+    }
+
+    // Runs the emulator for the given amount of real time, scaled by the current speed.
+    // Stops early if the CPU hits a breakpoint or watchpoint, and returns which one.
+    pub fn tick(&mut self, nanoseconds: u64) -> Result<Option<DebugHit>> {
+        let cycles = (nanoseconds as f64 * self.speed as f64 / 50.) as u64;
         let target_cycle = self.cycle + cycles;
 
         // Log average tick size every 5 seconds
@@ -176,40 +457,399 @@ impl Emulator {
             debug!("Cycles per tick: {}", target_cycle / self.tick_calls);
         }
 
+        let debug_hit = if self.run_ahead_frames == 0 {
+            self.run_until(target_cycle)?.debug_hit
+        } else {
+            self.run_ahead(target_cycle)?
+        };
+
+        self.capture_rewind_snapshot();
+        Ok(debug_hit)
+    }
+
+    // Plugs hardware into the game pak's expansion area, and returns whatever was plugged in before
+    pub fn attach_expansion(
+        &mut self,
+        expansion: Box<dyn Expansion>,
+    ) -> Option<Box<dyn Expansion>> {
+        self.memory.borrow_mut().replace_expansion(Some(expansion))
+    }
+
+    pub fn detach_expansion(&mut self) -> Option<Box<dyn Expansion>> {
+        self.memory.borrow_mut().replace_expansion(None)
+    }
+
+    // Snapshots DRAM and SRAM to start looking for where the game keeps something
+    pub fn start_ram_search(&self, width: SearchWidth, signed: bool) -> RamSearch {
+        RamSearch::new(&self.memory.borrow(), width, signed)
+    }
+
+    pub fn filter_ram_search(&self, search: &mut RamSearch, filter: SearchFilter) {
+        search.filter(&self.memory.borrow(), filter);
+    }
+
+    pub fn ram_search_results(
+        &self,
+        search: &RamSearch,
+        start: usize,
+        count: usize,
+    ) -> Result<Vec<SearchResult>> {
+        search.results(&self.memory.borrow(), start, count)
+    }
+
+    // Replaces every cheat at once, e.g. when loading the list for a game
+    pub fn set_cheats(&mut self, cheats: CheatList) {
+        self.cheats = cheats;
+    }
+
+    pub fn cheats(&self) -> &CheatList {
+        &self.cheats
+    }
+
+    pub fn cheats_mut(&mut self) -> &mut CheatList {
+        &mut self.cheats
+    }
+
+    // Shows frames from slightly in the future, so input seems to take effect sooner
+    pub fn set_run_ahead(&mut self, frames: u64) {
+        self.run_ahead_frames = frames;
+    }
+
+    fn run_ahead(&mut self, target_cycle: u64) -> Result<Option<DebugHit>> {
+        // Emulate up to now without showing anything, since those frames are already out of date
+        self.video.borrow_mut().set_sending_frames(false);
+        let result = self.run_until(target_cycle);
+        self.video.borrow_mut().set_sending_frames(true);
+        if let Some(debug_hit) = result?.debug_hit {
+            return Ok(Some(debug_hit));
+        }
+
+        // Then emulate a few frames into the future, as if the player kept holding the same buttons,
+        // and only show those. Nobody should hear that audio, and movies, traces, debuggers and expansions shouldn't see them.
+        let state = self.save_fast_state();
+        let input_log = self
+            .hardware
+            .borrow_mut()
+            .replace_input_log(InputLog::Inactive);
+        let tracer = self.cpu.replace_tracer(None);
+        let breakpoints = self.cpu.replace_breakpoints(vec![]);
+        let watchpoints = self.memory.borrow_mut().replace_watchpoints(vec![]);
+        self.memory.borrow_mut().set_expansion_paused(true);
+        self.audio.borrow_mut().set_playing(false);
+        let result = self.run_until(self.cycle + self.run_ahead_frames * CYCLES_PER_FRAME);
+        self.audio.borrow_mut().set_playing(true);
+        self.memory.borrow_mut().set_expansion_paused(false);
+        self.memory.borrow_mut().replace_watchpoints(watchpoints);
+        self.cpu.replace_breakpoints(breakpoints);
+        self.cpu.replace_tracer(tracer);
+        self.hardware.borrow_mut().replace_input_log(input_log);
+
+        // And now back to the present
+        self.load_fast_state(&state);
+        result.map(|_| None)
+    }
+
+    // 1 is normal speed, 2 is twice as fast, 0.5 is half speed.
+    // Audio is sped up or slowed down to match, so the audio player never runs dry or overflows.
+    pub fn set_speed(&mut self, speed: f32) -> Result<()> {
+        if !(MIN_SPEED..=MAX_SPEED).contains(&speed) {
+            return Err(anyhow!(
+                "Speed must be between {}x and {}x",
+                MIN_SPEED,
+                MAX_SPEED
+            ));
+        }
+        self.speed = speed;
+        self.audio.borrow_mut().set_playback_speed(speed);
+        self.update_frame_skip();
+        Ok(())
+    }
+
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    // When fast-forwarding, only build the frames a display could actually show
+    pub fn set_frame_skipping(&mut self, enabled: bool) {
+        self.frame_skipping = enabled;
+        self.update_frame_skip();
+    }
+
+    fn update_frame_skip(&mut self) {
+        let frame_skip = if self.frame_skipping && self.speed > 1. {
+            self.speed.ceil() as u64 - 1
+        } else {
+            0
+        };
+        self.video.borrow_mut().set_frame_skip(frame_skip);
+    }
+
+    // Runs until the VIP starts the next display frame, for frame advance
+    pub fn run_frame(&mut self) -> Result<FrameResult> {
+        let start_cycle = self.cycle;
+        let (frames_drawn, audio_samples) = self.output_counts();
+        // A frame always starts well before this, it's just a backstop
+        let target_cycle = self.cycle + 2 * CYCLES_PER_FRAME;
+        let RunResult {
+            cpu_halted,
+            debug_hit,
+        } = self.run_until_or_frame_start(target_cycle, true)?;
+        self.capture_rewind_snapshot();
+
+        let (new_frames_drawn, new_audio_samples) = self.output_counts();
+        Ok(FrameResult {
+            cycles: self.cycle - start_cycle,
+            frames_drawn: new_frames_drawn - frames_drawn,
+            audio_samples: new_audio_samples - audio_samples,
+            cpu_halted,
+            debug_hit,
+        })
+    }
+
+    // Runs exactly one CPU instruction, for debuggers
+    pub fn step_instruction(&mut self) -> Result<Option<DebugHit>> {
+        Ok(self.run_until(self.cycle + 1)?.debug_hit)
+    }
+
+    fn output_counts(&self) -> (u64, u64) {
+        (
+            self.video.borrow().frames_drawn(),
+            self.audio.borrow().samples_produced(),
+        )
+    }
+
+    // Stops early if the CPU hits a breakpoint or watchpoint
+    fn run_until(&mut self, target_cycle: u64) -> Result<RunResult> {
+        self.run_until_or_frame_start(target_cycle, false)
+    }
+
+    // Video events happen at least every 1ms, so the CPU never runs far past FRAMESTART
+    fn run_until_or_frame_start(
+        &mut self,
+        target_cycle: u64,
+        stop_at_frame_start: bool,
+    ) -> Result<RunResult> {
+        let frames_started = self.video.borrow().frames_started();
+        let mut cpu_halted = false;
         while self.cycle < target_cycle {
             // Find how long we can run before something interesting happens
-            let next_event_cycle = cmp::min(
-                target_cycle,
-                cmp::min(
-                    self.hardware.borrow().next_event(),
-                    self.video.borrow().next_event(),
-                ),
-            );
+            let mut next_event_cycle = target_cycle
+                .min(self.hardware.borrow().next_event())
+                .min(self.video.borrow().next_event())
+                .min(self.memory.borrow().expansion_next_event());
+            // Cheats go in right at the frame boundary, wherever ticks happen to start and end
+            if !self.cheats.is_empty() {
+                let next_frame_cycle = (self.cycle / CYCLES_PER_FRAME + 1) * CYCLES_PER_FRAME;
+                next_event_cycle = cmp::min(next_event_cycle, next_frame_cycle);
+            }
 
             // Run the CPU for at least that many cycles
             // (specifically, until next_event_cycle + however long it takes to finish the current op)
             // This is safe as long as it doesn't START a new op AFTER that interesting cycle
             let cpu_result = self.cpu.run(next_event_cycle)?;
+            cpu_halted |= self.cpu.halted();
 
             // Have the other components catch up
             let cpu_cycle = cpu_result.cycle;
             self.audio.borrow_mut().run(cpu_cycle);
             self.video.borrow_mut().run(cpu_cycle)?;
             self.hardware.borrow_mut().run(cpu_cycle);
+            self.memory.borrow_mut().run_expansion(cpu_cycle);
+
+            if cpu_cycle / CYCLES_PER_FRAME != self.cycle / CYCLES_PER_FRAME {
+                self.cheats.apply(&mut self.memory.borrow_mut());
+            }
+
+            // Leave any interrupts for when the debugger lets us continue
+            if cpu_result.debug_hit.is_some() {
+                self.cycle = cpu_cycle;
+                return Ok(RunResult {
+                    cpu_halted,
+                    debug_hit: cpu_result.debug_hit,
+                });
+            }
 
             // Components are caught up and their events are handled, now apply any pending interrupts
             if let Some(exception) = self.video.borrow().active_interrupt() {
                 self.cpu.raise_exception(exception);
             }
+            // The game pak holds its interrupt until it's told the CPU took it
+            if self.memory.borrow().expansion_interrupt_requested()
+                && self
+                    .cpu
+                    .raise_exception(Exception::interrupt(GAME_PAK_INTERRUPT, 3))
+            {
+                self.memory.borrow_mut().acknowledge_expansion_interrupt();
+            }
             if let Some(exception) = self.hardware.borrow().active_interrupt() {
                 self.cpu.raise_exception(exception);
             }
 
             self.cycle = cpu_cycle;
+            if stop_at_frame_start && self.video.borrow().frames_started() != frames_started {
+                break;
+            }
+        }
+
+        Ok(RunResult {
+            cpu_halted,
+            debug_hit: None,
+        })
+    }
+
+    fn capture_rewind_snapshot(&mut self) {
+        if self
+            .rewind
+            .as_ref()
+            .is_some_and(|rewind| rewind.should_capture(self.cycle))
+        {
+            let state = self.save_fast_state();
+            if let Some(rewind) = &mut self.rewind {
+                rewind.push(state);
+            }
+        }
+    }
+
+    // Starts recording every controller read, from either power-on or the current state
+    pub fn record_movie(&mut self, from_power_on: bool) -> Result<()> {
+        if from_power_on {
+            self.memory.borrow_mut().init();
+            self.reset();
         }
+        let movie = Movie {
+            rom_sha1: self.game_info.as_ref().map(|info| info.sha1),
+            from_power_on,
+            start_state: self.save_state_to_vec(false)?,
+            inputs: vec![],
+        };
+        self.hardware
+            .borrow_mut()
+            .replace_input_log(InputLog::Recording(movie));
         Ok(())
     }
 
+    // Jumps to the movie's starting point, and feeds the game its recorded input from then on
+    pub fn play_movie(&mut self, movie: Movie) -> Result<()> {
+        let loaded = self.game_info.as_ref().map(|info| info.sha1);
+        if movie.rom_sha1 != loaded {
+            return Err(anyhow!("This movie was recorded with a different ROM"));
+        }
+        self.load_state_from_slice(&movie.start_state)?;
+        self.hardware
+            .borrow_mut()
+            .replace_input_log(InputLog::Playing {
+                movie,
+                next: 0,
+                desynced_at: None,
+            });
+        Ok(())
+    }
+
+    // Stops recording or playback, and returns the movie if one was being recorded
+    pub fn stop_movie(&mut self) -> Option<Movie> {
+        match self
+            .hardware
+            .borrow_mut()
+            .replace_input_log(InputLog::Inactive)
+        {
+            InputLog::Recording(movie) => Some(movie),
+            _ => None,
+        }
+    }
+
+    pub fn movie_status(&self) -> MovieStatus {
+        self.hardware.borrow().movie_status()
+    }
+
+    // Snapshots the emulator every few frames while it runs, keeping as many as fit in the budget
+    pub fn enable_rewind(&mut self, frames_per_snapshot: u64, memory_budget: usize) {
+        self.rewind = Some(Rewind::new(frames_per_snapshot, memory_budget));
+    }
+
+    pub fn disable_rewind(&mut self) {
+        self.rewind = None;
+    }
+
+    // Goes back to the previous snapshot. Returns false if there's no history left.
+    pub fn rewind_one_step(&mut self) -> Result<bool> {
+        let Some(mut rewind) = self.rewind.take() else {
+            return Ok(false);
+        };
+        let result = match rewind.step_back(self.cycle) {
+            Ok(Some(state)) => {
+                self.load_fast_state(state);
+                Ok(true)
+            }
+            Ok(None) => Ok(false),
+            Err(err) => Err(err),
+        };
+        self.rewind = Some(rewind);
+        result
+    }
+
+    // Remembers the last `capacity` instructions, exceptions and interrupts
+    pub fn enable_trace(&mut self, capacity: usize) {
+        self.cpu.replace_tracer(Some(Tracer::new(capacity)));
+    }
+
+    pub fn disable_trace(&mut self) {
+        self.cpu.replace_tracer(None);
+    }
+
+    pub fn trace(&self) -> Option<&Tracer> {
+        self.cpu.tracer()
+    }
+
+    pub fn dump_trace(&self, path: impl AsRef<Path>) -> Result<()> {
+        match self.cpu.tracer() {
+            Some(tracer) => tracer.dump(path),
+            None => Err(anyhow!("Tracing is not enabled")),
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        self.cpu.breakpoints_mut().push(breakpoint);
+    }
+
+    pub fn remove_breakpoint(&mut self, address: usize) {
+        self.cpu
+            .breakpoints_mut()
+            .retain(|breakpoint| breakpoint.address != address);
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.memory.borrow_mut().watchpoints_mut().push(watchpoint);
+    }
+
+    pub fn remove_watchpoint(&mut self, range: Range<usize>) {
+        self.memory
+            .borrow_mut()
+            .watchpoints_mut()
+            .retain(|watchpoint| watchpoint.range != range);
+    }
+
+    pub fn clear_debug_points(&mut self) {
+        self.cpu.replace_breakpoints(vec![]);
+        self.memory.borrow_mut().replace_watchpoints(vec![]);
+    }
+
+    // VRAM as the VIP sees it, for graphics debugging
+    pub fn inspect_characters(&self, palette: Palette) -> Image {
+        inspect::characters(&self.memory.borrow(), palette)
+    }
+
+    pub fn inspect_bg_map(&self, index: usize) -> Result<Image> {
+        inspect::bg_map(&self.memory.borrow(), index)
+    }
+
+    pub fn inspect_worlds(&self) -> Vec<WorldAttributes> {
+        inspect::worlds(&self.memory.borrow())
+    }
+
+    pub fn inspect_objects(&self) -> Vec<ObjectAttributes> {
+        inspect::objects(&self.memory.borrow())
+    }
+
     pub fn load_image(&self, left_eye: &[u8], right_eye: &[u8]) -> Result<()> {
         let video = self.video.borrow_mut();
         video.load_and_send_frame(Eye::Left, left_eye);
@@ -249,6 +889,7 @@ impl EventHandler for EmulatorEventHandler {
     }
 }
 
+#[cfg(feature = "jni")]
 #[rustfmt::skip::macros(jni_func)]
 pub mod jni {
     use super::Emulator;
@@ -290,7 +931,8 @@ pub mod jni {
         info!("SRAM length: {} byte(s)", sram.len());
         let mut this = get_emulator(env, this)?;
         info!("Beginning game pak load...");
-        this.load_game_pak(rom, sram)
+        this.load_game_pak(rom, sram)?;
+        Ok(())
     }
 
     jni_func!(Emulator_nativeUnloadGamePak, unload_game_pak);
@@ -312,7 +954,8 @@ pub mod jni {
     jni_func!(Emulator_nativeTick, tick, jint);
     fn tick(env: &mut JNIEnv, this: JObject, nanoseconds: jint) -> Result<()> {
         let mut this = get_emulator(env, this)?;
-        this.tick(nanoseconds as u64)
+        this.tick(nanoseconds as u64)?;
+        Ok(())
     }
 
     jni_func!(Emulator_nativeReadSRAM, read_sram, JByteBuffer);
@@ -355,3 +998,391 @@ pub mod jni {
         this.load_image(left_eye, right_eye)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::cheat::CheatList;
+    use crate::emulator::debug::{Breakpoint, DebugHit};
+    use crate::emulator::expansion::{Expansion, MemoryExpansion};
+    use crate::emulator::memory::{WCR, WCR_ROM_ONE_WAIT};
+    use crate::emulator::movie::MovieStatus;
+    use crate::emulator::state::{self, SaveStateMetadata};
+    use crate::emulator::video::CYCLES_PER_FRAME;
+    use crate::emulator::Emulator;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    // Everything but the metadata, which has a timestamp in it
+    fn state_data(emulator: &Emulator) -> Vec<u8> {
+        state::save_state(&SaveStateMetadata::default(), &emulator.save_state_data()).unwrap()
+    }
+
+    fn emulator_with_rom(fill: u8) -> Emulator {
+        let mut emulator = Emulator::builder()
+            .threaded_drawing(false)
+            .audio_buffer_size(16)
+            .build();
+        emulator.load_game_pak(&[fill; 0x400], &[0; 0x100]).unwrap();
+        emulator
+    }
+
+    #[test]
+    fn identifies_trimmed_roms_by_their_original_bytes() {
+        let rom: Vec<u8> = (0..0x600).map(|i| i as u8).collect();
+        let mut emulator = Emulator::builder().threaded_drawing(false).build();
+        let info = emulator.load_game_pak(&rom, &[]).unwrap();
+        assert_eq!(info.sha1, sha1_smol::Sha1::from(&rom).digest().bytes());
+        assert_eq!(info.crc32, crc32fast::hash(&rom));
+    }
+
+    #[test]
+    fn round_trips_state_through_memory() {
+        let mut emulator = emulator_with_rom(0);
+        emulator.tick(1_000_000).unwrap();
+        let state = emulator.save_state_to_vec(true).unwrap();
+        let metadata = Emulator::read_state_metadata(&state).unwrap().unwrap();
+        assert_eq!(metadata.cycle, 20_000);
+        assert_eq!(
+            metadata.rom_sha1,
+            emulator.game_info().map(|info| info.sha1)
+        );
+        assert!(metadata.thumbnail.is_some());
+
+        emulator.tick(1_000_000).unwrap();
+        emulator.load_state_from_slice(&state).unwrap();
+        let reloaded = Emulator::read_state_metadata(&emulator.save_state_to_vec(false).unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(reloaded.cycle, 20_000);
+        assert_eq!(reloaded.thumbnail, None);
+    }
+
+    #[test]
+    fn rewinds_to_earlier_frames() {
+        let mut emulator = emulator_with_rom(0);
+        emulator.enable_rewind(1, 16 * 1024 * 1024);
+        let mut cycles = vec![];
+        for _ in 0..3 {
+            emulator.tick(20_000_000).unwrap();
+            cycles.push(emulator.cycle);
+        }
+
+        assert!(emulator.rewind_one_step().unwrap());
+        assert_eq!(emulator.cycle, cycles[1]);
+        assert!(emulator.rewind_one_step().unwrap());
+        assert_eq!(emulator.cycle, cycles[0]);
+        assert!(!emulator.rewind_one_step().unwrap());
+        assert_eq!(emulator.cycle, cycles[0]);
+    }
+
+    #[test]
+    fn runs_one_frame_at_a_time() {
+        let mut emulator = emulator_with_rom(0);
+        for frame in 1..=3 {
+            let result = emulator.run_frame().unwrap();
+            assert_eq!(emulator.cycle / CYCLES_PER_FRAME, frame);
+            assert_eq!(emulator.video.borrow().frames_started(), frame);
+            // Instructions can run a few cycles past the end of the frame
+            assert!(result.cycles.abs_diff(CYCLES_PER_FRAME) < 8);
+            // One sample every 480 cycles
+            assert!((833..=834).contains(&result.audio_samples));
+            // The CPU is just running nops, and nothing turned the VIP on
+            assert!(!result.cpu_halted);
+            assert_eq!(result.frames_drawn, 0);
+        }
+    }
+
+    #[test]
+    fn runs_from_mid_frame_to_the_next_frame_start() {
+        let mut emulator = emulator_with_rom(0);
+        emulator.run_frame().unwrap();
+        emulator.tick(10_000_000).unwrap();
+        let result = emulator.run_frame().unwrap();
+        assert_eq!(emulator.video.borrow().frames_started(), 2);
+        assert!(result.cycles.abs_diff(CYCLES_PER_FRAME / 2) < 8);
+    }
+
+    #[test]
+    fn builds_audio_players_with_the_chosen_buffer_size() {
+        let mut emulator = Emulator::builder()
+            .threaded_drawing(false)
+            .audio_buffer_size(1)
+            .build();
+        emulator.load_game_pak(&[0; 0x400], &[]).unwrap();
+        let player = emulator.claim_audio_player(1.);
+        for _ in 0..3 {
+            emulator.tick(20_000_000).unwrap();
+        }
+        // Only one frame fits, and the rest is dropped
+        assert_eq!(player.available(), 833);
+    }
+
+    #[test]
+    fn scales_time_and_audio_by_speed() {
+        let mut emulator = emulator_with_rom(0);
+        let mut player = emulator.claim_audio_player(1.);
+        assert!(emulator.set_speed(16.).is_err());
+
+        emulator.set_speed(4.).unwrap();
+        emulator.tick(20_000_000).unwrap();
+        assert!(emulator.cycle >= 4 * CYCLES_PER_FRAME);
+        // Four frames of audio squeezed into one
+        assert!((833..=834).contains(&player.available()));
+
+        let mut buffer = vec![(0., 0.); player.available()];
+        player.play(&mut buffer);
+        emulator.set_speed(0.5).unwrap();
+        let start = emulator.cycle;
+        emulator.tick(20_000_000).unwrap();
+        assert!(emulator.cycle - start >= CYCLES_PER_FRAME / 2);
+        assert!((832..=836).contains(&player.available()));
+    }
+
+    #[test]
+    fn fast_states_round_trip() {
+        let mut emulator = emulator_with_rom(0);
+        emulator.tick(20_000_000).unwrap();
+        let state = emulator.save_fast_state();
+        let saved = state_data(&emulator);
+
+        emulator.tick(20_000_000).unwrap();
+        emulator.load_fast_state(&state);
+        assert_eq!(state_data(&emulator), saved);
+    }
+
+    #[test]
+    fn loading_states_restores_wait_control() {
+        let mut emulator = emulator_with_rom(0);
+        let set_wcr = |emulator: &mut Emulator, wcr: u8| {
+            emulator.memory.borrow_mut().write_byte(WCR, wcr);
+            emulator.hardware.borrow_mut().process_event(WCR);
+        };
+        set_wcr(&mut emulator, WCR_ROM_ONE_WAIT);
+        let fast_state = emulator.save_fast_state();
+        let state = emulator.save_state_to_vec(false).unwrap();
+
+        set_wcr(&mut emulator, 0);
+        emulator.load_fast_state(&fast_state);
+        assert_eq!(emulator.memory.borrow().wait_cycles(0x07000000, 2), 1);
+        set_wcr(&mut emulator, 0);
+        emulator.load_state_from_slice(&state).unwrap();
+        assert_eq!(emulator.memory.borrow().wait_cycles(0x07000000, 2), 1);
+    }
+
+    #[test]
+    fn run_ahead_leaves_the_present_alone() {
+        let mut normal = emulator_with_rom(0);
+        let mut ahead = emulator_with_rom(0);
+        ahead.set_run_ahead(2);
+        let player = ahead.claim_audio_player(1.);
+        for _ in 0..3 {
+            normal.tick(20_000_000).unwrap();
+            ahead.tick(20_000_000).unwrap();
+        }
+        assert_eq!(state_data(&ahead), state_data(&normal));
+        // Only audio from the present is played
+        assert!(player.available() <= 3 * 834);
+    }
+
+    #[test]
+    fn refuses_old_states_which_dont_fit_the_game_pak() {
+        // Version 1 states don't say which game they're for
+        let mut state = vec![];
+        ciborium::ser::into_writer(&1u8, &mut state).unwrap();
+        ciborium::ser::into_writer(&emulator_with_rom(0).save_state_data(), &mut state).unwrap();
+
+        let mut emulator = Emulator::builder().threaded_drawing(false).build();
+        emulator.load_game_pak(&[0; 0x400], &[0; 0x200]).unwrap();
+        let err = emulator.load_state_from_slice(&state).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "This state has 256 bytes of Sram, but the game pak has 512"
+        );
+
+        emulator.load_game_pak(&[0; 0x400], &[]).unwrap();
+        let err = emulator.load_state_from_slice(&state).unwrap_err();
+        assert_eq!(err.to_string(), "This state has Sram, but none is loaded");
+    }
+
+    #[test]
+    fn saves_states_without_a_game_pak() {
+        let emulator = Emulator::builder().threaded_drawing(false).build();
+        let state = emulator.save_state_to_vec(false).unwrap();
+        let mut other = Emulator::builder().threaded_drawing(false).build();
+        other.load_state_from_slice(&state).unwrap();
+    }
+
+    // Has the hardware sample the controller every few ms, like a game waiting on the key interrupt
+    fn poll_keys(emulator: &mut Emulator) {
+        const SCR: usize = 0x02000028;
+        emulator.memory.borrow_mut().write_byte(SCR, 0);
+        emulator.hardware.borrow_mut().process_event(SCR);
+    }
+
+    #[test]
+    fn movies_keep_the_timeline_rewinding_left_behind() {
+        let mut recorder = emulator_with_rom(0);
+        let buttons = recorder.claim_controller_state();
+        recorder.enable_rewind(1, 16 * 1024 * 1024);
+        recorder.record_movie(true).unwrap();
+        poll_keys(&mut recorder);
+        for frame in 0..4 {
+            buttons.store(0x0010 << frame, Ordering::Relaxed);
+            recorder.tick(20_000_000).unwrap();
+        }
+        // Take back the last frame, and press something else instead
+        assert!(recorder.rewind_one_step().unwrap());
+        buttons.store(0x1000, Ordering::Relaxed);
+        recorder.tick(20_000_000).unwrap();
+        recorder.tick(20_000_000).unwrap();
+        let movie = recorder.stop_movie().unwrap();
+        assert!(movie
+            .inputs
+            .windows(2)
+            .all(|pair| pair[0].cycle < pair[1].cycle));
+
+        assert!(!movie.inputs.is_empty());
+
+        let mut player = emulator_with_rom(0);
+        player.play_movie(movie).unwrap();
+        poll_keys(&mut player);
+        while player.cycle < recorder.cycle {
+            player.tick(20_000_000).unwrap();
+        }
+        assert_eq!(player.movie_status(), MovieStatus::Finished);
+        assert_eq!(state_data(&player), state_data(&recorder));
+    }
+
+    #[test]
+    fn refuses_state_from_another_rom() {
+        let state = emulator_with_rom(0).save_state_to_vec(false).unwrap();
+        let mut emulator = emulator_with_rom(1);
+        let err = emulator.load_state_from_slice(&state).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("This state was saved with a different ROM"));
+    }
+
+    #[test]
+    fn tick_stops_at_breakpoints() {
+        let mut emulator = emulator_with_rom(0);
+        emulator.add_breakpoint(Breakpoint::at(0xfffffff8));
+        let hit = emulator.tick(20_000_000).unwrap();
+        assert_eq!(hit, Some(DebugHit::Breakpoint { pc: 0xfffffff8 }));
+        assert_eq!(emulator.cpu.pc, 0xfffffff8);
+        assert!(emulator.cycle < 20);
+
+        // The next tick carries on from there
+        assert_eq!(emulator.tick(20_000_000).unwrap(), None);
+        emulator.remove_breakpoint(0xfffffff8);
+        assert!(emulator.cpu.breakpoints_mut().is_empty());
+    }
+
+    #[test]
+    fn maps_expansions_and_raises_the_game_pak_interrupt() {
+        let mut emulator = emulator_with_rom(0);
+        let expansion = MemoryExpansion::new(0x1000);
+        let line = expansion.interrupt_line();
+        assert!(emulator.attach_expansion(Box::new(expansion)).is_none());
+        emulator
+            .memory
+            .borrow_mut()
+            .write_word(0x04000ffc, 0x12345678);
+        assert_eq!(emulator.memory.borrow().read_word(0x04001ffc), 0x12345678);
+
+        // Let interrupts through, then pull the line
+        emulator.cpu.sys_registers[5] = 0;
+        line.store(true, Ordering::Relaxed);
+        emulator.tick(1_000).unwrap();
+        assert_eq!(emulator.cpu.sys_registers[4] & 0xffff, 0xfe30);
+        // Taking the interrupt acknowledged it, so the handler won't be interrupted again
+        assert!(!line.load(Ordering::Relaxed));
+
+        assert!(emulator.detach_expansion().is_some());
+        assert_eq!(emulator.memory.borrow().read_word(0x04000ffc), 0);
+    }
+
+    #[test]
+    fn run_ahead_and_rewind_leave_one_shot_cheats_armed() {
+        let cheats = CheatList::parse("once 05000004=07 if 05000010==01").unwrap();
+        let mut emulator = emulator_with_rom(0);
+        emulator.set_cheats(cheats.clone());
+        emulator.set_run_ahead(2);
+        emulator.tick(20_000_000).unwrap();
+
+        // Only the frames run ahead reach a frame boundary
+        emulator.memory.borrow_mut().write_byte(0x05000010, 1);
+        emulator.tick(10_000_000).unwrap();
+        assert_eq!(emulator.memory.borrow().read_byte(0x05000004), 0);
+        emulator.tick(10_000_000).unwrap();
+        assert_eq!(emulator.memory.borrow().read_byte(0x05000004), 7);
+
+        // Rewinding to before it fired lets it fire again
+        let mut emulator = emulator_with_rom(0);
+        emulator.set_cheats(cheats);
+        emulator.enable_rewind(1, 16 * 1024 * 1024);
+        emulator.tick(20_000_000).unwrap();
+        emulator.memory.borrow_mut().write_byte(0x05000010, 1);
+        emulator.tick(10_000_000).unwrap();
+        emulator.tick(10_000_000).unwrap();
+        assert_eq!(emulator.memory.borrow().read_byte(0x05000004), 7);
+        assert!(emulator.rewind_one_step().unwrap());
+        assert_eq!(emulator.memory.borrow().read_byte(0x05000004), 0);
+        emulator.memory.borrow_mut().write_byte(0x05000010, 1);
+        emulator.tick(20_000_000).unwrap();
+        assert_eq!(emulator.memory.borrow().read_byte(0x05000004), 7);
+    }
+
+    // Remembers the latest cycle it was asked to run to
+    struct CycleExpansion(Arc<AtomicU64>);
+    impl Expansion for CycleExpansion {
+        fn read_byte(&self, _offset: usize) -> u8 {
+            0
+        }
+        fn write_byte(&mut self, _offset: usize, _value: u8) {}
+        fn run(&mut self, cycle: u64) {
+            self.0.store(cycle, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn run_ahead_leaves_expansions_in_the_present() {
+        let cycle = Arc::new(AtomicU64::new(0));
+        let mut emulator = emulator_with_rom(0);
+        emulator.attach_expansion(Box::new(CycleExpansion(Arc::clone(&cycle))));
+        emulator.set_run_ahead(2);
+        for _ in 0..3 {
+            emulator.tick(20_000_000).unwrap();
+            assert_eq!(cycle.load(Ordering::Relaxed), emulator.cycle);
+        }
+
+        // Rewinding sends it back in time
+        emulator.set_run_ahead(0);
+        emulator.enable_rewind(1, 16 * 1024 * 1024);
+        emulator.tick(20_000_000).unwrap();
+        emulator.tick(20_000_000).unwrap();
+        assert!(emulator.rewind_one_step().unwrap());
+        emulator.tick(1_000_000).unwrap();
+        assert_eq!(cycle.load(Ordering::Relaxed), emulator.cycle);
+    }
+
+    #[test]
+    fn applies_cheats_at_frame_boundaries() {
+        let mut emulator = emulator_with_rom(0);
+        emulator.set_cheats(CheatList::parse("05000000=2a\nonce 05000004=07").unwrap());
+
+        // Half a frame in, nothing has changed yet
+        emulator.tick(10_000_000).unwrap();
+        assert_eq!(emulator.memory.borrow().read_byte(0x05000000), 0);
+        emulator.tick(10_000_000).unwrap();
+        assert_eq!(emulator.memory.borrow().read_byte(0x05000000), 0x2a);
+        assert_eq!(emulator.memory.borrow().read_byte(0x05000004), 7);
+
+        // Frozen values come back every frame, one-shot values don't
+        emulator.memory.borrow_mut().write_byte(0x05000000, 0);
+        emulator.memory.borrow_mut().write_byte(0x05000004, 0);
+        emulator.tick(20_000_000).unwrap();
+        assert_eq!(emulator.memory.borrow().read_byte(0x05000000), 0x2a);
+        assert_eq!(emulator.memory.borrow().read_byte(0x05000004), 0);
+    }
+}
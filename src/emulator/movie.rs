@@ -0,0 +1,221 @@
+use anyhow::{anyhow, Result};
+use ciborium::{de, ser};
+use serde_derive::{Deserialize, Serialize};
+
+const VERSION: u8 = 1;
+
+// A controller value, and the cycle at which the game read it
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MovieInput {
+    pub cycle: u64,
+    pub state: u16,
+}
+
+// A recording of every controller read a game made, starting from a save state.
+// Movies recorded from power-on start from a state captured right after the reset.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Movie {
+    pub rom_sha1: Option<[u8; 20]>,
+    pub from_power_on: bool,
+    #[serde(with = "serde_bytes")]
+    pub start_state: Vec<u8>,
+    pub inputs: Vec<MovieInput>,
+}
+impl Movie {
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes = vec![];
+        ser::into_writer(&VERSION, &mut bytes)?;
+        ser::into_writer(self, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self> {
+        let version: u8 = de::from_reader(&mut bytes)?;
+        if version != VERSION {
+            return Err(anyhow!("Could not read movie with version {}", version));
+        }
+        Ok(de::from_reader(bytes)?)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MovieStatus {
+    Inactive,
+    Recording,
+    Playing,
+    Finished,
+    // The game read the controller at a different cycle than it did while recording
+    Desynced { cycle: u64 },
+}
+
+// Sits between the controller and the hardware, so every read can be recorded or replayed
+#[derive(Default)]
+pub enum InputLog {
+    #[default]
+    Inactive,
+    Recording(Movie),
+    Playing {
+        movie: Movie,
+        next: usize,
+        desynced_at: Option<u64>,
+    },
+}
+impl InputLog {
+    pub fn sample(&mut self, cycle: u64, live_state: u16) -> u16 {
+        match self {
+            InputLog::Inactive => live_state,
+            InputLog::Recording(movie) => {
+                movie.inputs.push(MovieInput {
+                    cycle,
+                    state: live_state,
+                });
+                live_state
+            }
+            InputLog::Playing {
+                movie,
+                next,
+                desynced_at,
+            } => match movie.inputs.get(*next) {
+                Some(input) => {
+                    if input.cycle != cycle && desynced_at.is_none() {
+                        *desynced_at = Some(cycle);
+                    }
+                    *next += 1;
+                    input.state
+                }
+                // Once the movie's over, hand control back to the player
+                None => live_state,
+            },
+        }
+    }
+
+    // Time moved to the given cycle, by loading a state or rewinding.
+    // A recording forgets everything after it, and playback picks up from there.
+    pub fn seek(&mut self, cycle: u64) {
+        match self {
+            InputLog::Inactive => {}
+            InputLog::Recording(movie) => {
+                let len = movie.inputs.partition_point(|input| input.cycle <= cycle);
+                movie.inputs.truncate(len);
+            }
+            InputLog::Playing {
+                movie,
+                next,
+                desynced_at,
+            } => {
+                *next = movie.inputs.partition_point(|input| input.cycle <= cycle);
+                if desynced_at.is_some_and(|desync| desync > cycle) {
+                    *desynced_at = None;
+                }
+            }
+        }
+    }
+
+    pub fn status(&self) -> MovieStatus {
+        match self {
+            InputLog::Inactive => MovieStatus::Inactive,
+            InputLog::Recording(_) => MovieStatus::Recording,
+            InputLog::Playing {
+                desynced_at: Some(cycle),
+                ..
+            } => MovieStatus::Desynced { cycle: *cycle },
+            InputLog::Playing { movie, next, .. } if *next >= movie.inputs.len() => {
+                MovieStatus::Finished
+            }
+            InputLog::Playing { .. } => MovieStatus::Playing,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::movie::{InputLog, Movie, MovieInput, MovieStatus};
+
+    fn movie(inputs: &[(u64, u16)]) -> Movie {
+        Movie {
+            rom_sha1: None,
+            from_power_on: true,
+            start_state: vec![1, 2, 3],
+            inputs: inputs
+                .iter()
+                .map(|&(cycle, state)| MovieInput { cycle, state })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn records_every_read() {
+        let mut log = InputLog::Recording(movie(&[]));
+        assert_eq!(log.sample(10, 0x1002), 0x1002);
+        assert_eq!(log.sample(20, 0x0002), 0x0002);
+        match log {
+            InputLog::Recording(recorded) => {
+                assert_eq!(recorded.inputs, movie(&[(10, 0x1002), (20, 0x0002)]).inputs)
+            }
+            _ => panic!("Not recording"),
+        }
+    }
+
+    #[test]
+    fn plays_back_recorded_reads() {
+        let mut log = InputLog::Playing {
+            movie: movie(&[(10, 0x1002), (20, 0x0006)]),
+            next: 0,
+            desynced_at: None,
+        };
+        assert_eq!(log.sample(10, 0x0002), 0x1002);
+        assert_eq!(log.status(), MovieStatus::Playing);
+        assert_eq!(log.sample(20, 0x0002), 0x0006);
+        assert_eq!(log.status(), MovieStatus::Finished);
+        assert_eq!(log.sample(30, 0x0202), 0x0202);
+    }
+
+    #[test]
+    fn detects_desyncs() {
+        let mut log = InputLog::Playing {
+            movie: movie(&[(10, 0x1002), (20, 0x0006)]),
+            next: 0,
+            desynced_at: None,
+        };
+        log.sample(10, 0);
+        log.sample(25, 0);
+        assert_eq!(log.status(), MovieStatus::Desynced { cycle: 25 });
+    }
+
+    #[test]
+    fn seeking_truncates_recordings() {
+        let mut log = InputLog::Recording(movie(&[(10, 1), (20, 2), (30, 3)]));
+        log.seek(20);
+        log.sample(25, 4);
+        match log {
+            InputLog::Recording(recorded) => {
+                assert_eq!(recorded.inputs, movie(&[(10, 1), (20, 2), (25, 4)]).inputs)
+            }
+            _ => panic!("Not recording"),
+        }
+    }
+
+    #[test]
+    fn seeking_moves_playback() {
+        let mut log = InputLog::Playing {
+            movie: movie(&[(10, 1), (20, 2), (30, 3)]),
+            next: 0,
+            desynced_at: None,
+        };
+        log.sample(10, 0);
+        log.sample(25, 0);
+        assert_eq!(log.status(), MovieStatus::Desynced { cycle: 25 });
+        log.seek(10);
+        assert_eq!(log.status(), MovieStatus::Playing);
+        assert_eq!(log.sample(20, 0), 2);
+        log.seek(0);
+        assert_eq!(log.sample(10, 0), 1);
+    }
+
+    #[test]
+    fn round_trips_bytes() {
+        let original = movie(&[(10, 0x1002)]);
+        let bytes = original.to_bytes().unwrap();
+        assert_eq!(Movie::from_bytes(&bytes).unwrap(), original);
+    }
+}
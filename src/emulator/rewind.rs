@@ -0,0 +1,369 @@
+use anyhow::{anyhow, Result};
+use std::collections::VecDeque;
+use std::mem;
+
+use super::audio::AudioState;
+use super::cpu::CpuState;
+use super::hardware::HardwareState;
+use super::memory::Region;
+use super::state::{FastState, GlobalState};
+use super::video::drawing::{DrawnFrames, ROW_GROUPS};
+use super::video::{VideoState, CYCLES_PER_FRAME};
+
+// The newest snapshot is kept whole. Every older snapshot stores its memory and drawn frames
+// as deltas against the one after it, so walking backwards only ever needs the snapshot we just restored.
+pub struct Rewind {
+    cycles_per_snapshot: u64,
+    memory_budget: usize,
+    newest: Option<FastState>,
+    deltas: VecDeque<Delta>,
+    deltas_size: usize,
+    next_capture_cycle: u64,
+}
+
+// The components are small, so they're kept whole
+struct Delta {
+    global: GlobalState,
+    memory: Vec<(Region, Vec<u8>)>,
+    cpu: CpuState,
+    video: VideoState,
+    drawn_frames: Vec<u8>,
+    hardware: HardwareState,
+    audio: Box<AudioState>,
+    cheats_applied: Vec<bool>,
+}
+impl Delta {
+    fn encode(newer: &FastState, older: FastState) -> Self {
+        let memory = older
+            .memory
+            .iter()
+            .map(|(region, data)| {
+                let base = region_data(newer, *region);
+                (*region, encode_delta(base, data))
+            })
+            .collect();
+        let drawn_frames = encode_delta(
+            &frames_to_bytes(&newer.drawn_frames),
+            &frames_to_bytes(&older.drawn_frames),
+        );
+        Self {
+            global: older.global,
+            memory,
+            cpu: older.cpu,
+            video: older.video,
+            drawn_frames,
+            hardware: older.hardware,
+            audio: older.audio,
+            cheats_applied: older.cheats_applied,
+        }
+    }
+
+    fn apply(self, newer: &FastState) -> Result<FastState> {
+        let memory = self
+            .memory
+            .into_iter()
+            .map(|(region, delta)| {
+                let base = region_data(newer, region);
+                Ok((region, apply_delta(base, &delta)?))
+            })
+            .collect::<Result<_>>()?;
+        let drawn_frames = apply_delta(&frames_to_bytes(&newer.drawn_frames), &self.drawn_frames)?;
+        Ok(FastState {
+            global: self.global,
+            memory,
+            cpu: self.cpu,
+            video: self.video,
+            drawn_frames: bytes_to_frames(&drawn_frames)?,
+            hardware: self.hardware,
+            audio: self.audio,
+            cheats_applied: self.cheats_applied,
+        })
+    }
+
+    fn size(&self) -> usize {
+        let memory: usize = self.memory.iter().map(|(_, delta)| delta.len()).sum();
+        memory + self.drawn_frames.len() + COMPONENTS_SIZE + self.cheats_applied.len()
+    }
+}
+
+// Roughly how much a snapshot's component states take up
+const COMPONENTS_SIZE: usize = mem::size_of::<FastState>() + mem::size_of::<AudioState>();
+
+fn state_size(state: &FastState) -> usize {
+    let memory: usize = state.memory.iter().map(|(_, data)| data.len()).sum();
+    memory + mem::size_of::<DrawnFrames>() + COMPONENTS_SIZE + state.cheats_applied.len()
+}
+
+// A region which isn't in the newer snapshot (like SRAM after swapping game paks) is treated as empty
+fn region_data(state: &FastState, region: Region) -> &[u8] {
+    state
+        .memory
+        .iter()
+        .find(|(r, _)| *r == region)
+        .map_or(&[], |(_, data)| data)
+}
+
+fn frames_to_bytes(frames: &DrawnFrames) -> Vec<u8> {
+    frames
+        .iter()
+        .flatten()
+        .flatten()
+        .flat_map(|pixel| pixel.to_le_bytes())
+        .collect()
+}
+
+fn bytes_to_frames(bytes: &[u8]) -> Result<Box<DrawnFrames>> {
+    if bytes.len() != mem::size_of::<DrawnFrames>() {
+        return Err(anyhow!("Rewind snapshot is corrupt"));
+    }
+    let mut frames = Box::new([[[0; 384]; ROW_GROUPS]; 2]);
+    for (pixel, bytes) in frames
+        .iter_mut()
+        .flatten()
+        .flatten()
+        .zip(bytes.chunks_exact(2))
+    {
+        *pixel = u16::from_le_bytes([bytes[0], bytes[1]]);
+    }
+    Ok(frames)
+}
+
+impl Rewind {
+    pub fn new(frames_per_snapshot: u64, memory_budget: usize) -> Self {
+        Self {
+            cycles_per_snapshot: frames_per_snapshot.max(1) * CYCLES_PER_FRAME,
+            memory_budget,
+            newest: None,
+            deltas: VecDeque::new(),
+            deltas_size: 0,
+            next_capture_cycle: 0,
+        }
+    }
+
+    pub fn should_capture(&self, cycle: u64) -> bool {
+        cycle >= self.next_capture_cycle
+    }
+
+    pub fn push(&mut self, state: FastState) {
+        if let Some(newest) = self.newest.take() {
+            let delta = Delta::encode(&state, newest);
+            self.deltas_size += delta.size();
+            self.deltas.push_back(delta);
+        }
+        self.next_capture_cycle = state.global.cycle + self.cycles_per_snapshot;
+        self.newest = Some(state);
+
+        // Forget the oldest history first
+        while self.size() > self.memory_budget {
+            match self.deltas.pop_front() {
+                Some(delta) => self.deltas_size -= delta.size(),
+                None => break,
+            }
+        }
+    }
+
+    // Finds the newest snapshot from before the given cycle.
+    // It stays in the buffer, since the emulator is about to be in that state again.
+    pub fn step_back(&mut self, cycle: u64) -> Result<Option<&FastState>> {
+        loop {
+            let Some(newest) = &self.newest else {
+                return Ok(None);
+            };
+            if newest.global.cycle < cycle {
+                break;
+            }
+            let Some(delta) = self.deltas.pop_back() else {
+                self.newest = None;
+                return Ok(None);
+            };
+            self.deltas_size -= delta.size();
+            self.newest = Some(delta.apply(newest)?);
+        }
+        let newest = self.newest.as_ref().unwrap();
+        self.next_capture_cycle = newest.global.cycle + self.cycles_per_snapshot;
+        Ok(Some(newest))
+    }
+
+    pub fn clear(&mut self) {
+        self.newest = None;
+        self.deltas.clear();
+        self.deltas_size = 0;
+        self.next_capture_cycle = 0;
+    }
+
+    pub fn size(&self) -> usize {
+        self.deltas_size + self.newest.as_ref().map_or(0, state_size)
+    }
+}
+
+// Deltas are the target length, followed by runs of (unchanged bytes, changed bytes, XORed changed bytes).
+// Most of a state is VRAM and DRAM which barely change between frames, so the runs are long.
+fn encode_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let byte_at = |data: &[u8], index: usize| data.get(index).copied().unwrap_or(0);
+    let mut delta = vec![];
+    write_varint(&mut delta, target.len());
+
+    let mut index = 0;
+    while index < target.len() {
+        let start = index;
+        while index < target.len() && target[index] == byte_at(base, index) {
+            index += 1;
+        }
+        let unchanged = index - start;
+        let start = index;
+        while index < target.len() && target[index] != byte_at(base, index) {
+            index += 1;
+        }
+        write_varint(&mut delta, unchanged);
+        write_varint(&mut delta, index - start);
+        delta.extend((start..index).map(|i| target[i] ^ byte_at(base, i)));
+    }
+    delta
+}
+
+fn apply_delta(base: &[u8], delta: &[u8]) -> Result<Vec<u8>> {
+    let mut reader = delta;
+    let length = read_varint(&mut reader)?;
+    let mut target: Vec<u8> = (0..length)
+        .map(|index| base.get(index).copied().unwrap_or(0))
+        .collect();
+
+    let mut index = 0;
+    while !reader.is_empty() {
+        index += read_varint(&mut reader)?;
+        let changed = read_varint(&mut reader)?;
+        if changed > reader.len() || index + changed > length {
+            return Err(anyhow!("Rewind snapshot is corrupt"));
+        }
+        for (place, byte) in target[index..index + changed].iter_mut().zip(reader) {
+            *place ^= byte;
+        }
+        reader = &reader[changed..];
+        index += changed;
+    }
+    Ok(target)
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &mut &[u8]) -> Result<usize> {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = data
+            .split_first()
+            .ok_or_else(|| anyhow!("Rewind snapshot is corrupt"))?;
+        *data = rest;
+        value |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::memory::Region;
+    use crate::emulator::rewind::{apply_delta, encode_delta, Rewind, COMPONENTS_SIZE};
+    use crate::emulator::state::{FastState, GlobalState};
+    use crate::emulator::video::drawing::ROW_GROUPS;
+    use crate::emulator::video::CYCLES_PER_FRAME;
+
+    fn state(cycle: u64, fill: u8) -> FastState {
+        let mut drawn_frames = Box::new([[[0; 384]; ROW_GROUPS]; 2]);
+        drawn_frames[1][27][383] = fill as u16;
+        FastState {
+            global: GlobalState {
+                cycle,
+                tick_calls: 0,
+            },
+            memory: vec![(Region::Dram, vec![fill; 16])],
+            cpu: Default::default(),
+            video: Default::default(),
+            drawn_frames,
+            hardware: Default::default(),
+            audio: Default::default(),
+            cheats_applied: vec![fill % 2 == 1],
+        }
+    }
+
+    fn dram(state: &FastState) -> &[u8] {
+        &state.memory[0].1
+    }
+
+    #[test]
+    fn deltas_round_trip() {
+        let base = vec![0u8; 1000];
+        let mut target = base.clone();
+        target[10] = 1;
+        target[500..510].fill(0xff);
+        target.push(7);
+        let delta = encode_delta(&base, &target);
+        assert!(delta.len() < 30);
+        assert_eq!(apply_delta(&base, &delta).unwrap(), target);
+        // Shrinking works too
+        let delta = encode_delta(&target, &base);
+        assert_eq!(apply_delta(&target, &delta).unwrap(), base);
+    }
+
+    #[test]
+    fn captures_every_n_frames() {
+        let mut rewind = Rewind::new(2, usize::MAX);
+        assert!(rewind.should_capture(0));
+        rewind.push(state(0, 0));
+        assert!(!rewind.should_capture(CYCLES_PER_FRAME));
+        assert!(rewind.should_capture(2 * CYCLES_PER_FRAME));
+    }
+
+    #[test]
+    fn steps_back_through_snapshots() {
+        let mut rewind = Rewind::new(1, usize::MAX);
+        for frame in 0..4u8 {
+            rewind.push(state(frame as u64 * 100, frame));
+        }
+        // Right after a capture, stepping back skips the state we're already in
+        let snapshot = rewind.step_back(300).unwrap().unwrap();
+        assert_eq!((snapshot.global.cycle, dram(snapshot)), (200, &[2; 16][..]));
+        assert_eq!(snapshot.drawn_frames[1][27][383], 2);
+        let snapshot = rewind.step_back(200).unwrap().unwrap();
+        assert_eq!((snapshot.global.cycle, dram(snapshot)), (100, &[1; 16][..]));
+        assert_eq!(snapshot.cheats_applied, [true]);
+        // Partway through a step, go back to its start
+        let snapshot = rewind.step_back(150).unwrap().unwrap();
+        assert_eq!(snapshot.global.cycle, 100);
+        let snapshot = rewind.step_back(100).unwrap().unwrap();
+        assert_eq!((snapshot.global.cycle, dram(snapshot)), (0, &[0; 16][..]));
+        assert!(rewind.step_back(0).unwrap().is_none());
+        assert_eq!(rewind.size(), 0);
+    }
+
+    #[test]
+    fn drops_oldest_snapshots_over_budget() {
+        let newest_size = {
+            let mut rewind = Rewind::new(1, usize::MAX);
+            rewind.push(state(0, 0));
+            rewind.size()
+        };
+        // Room for a few deltas, but not all of them
+        let budget = newest_size + 4 * (COMPONENTS_SIZE + 64);
+        let mut rewind = Rewind::new(1, budget);
+        for frame in 0..10u8 {
+            rewind.push(state(frame as u64, frame));
+        }
+        assert!(rewind.size() <= budget);
+        let mut oldest = 9;
+        while let Some(snapshot) = rewind.step_back(oldest).unwrap() {
+            assert_eq!(dram(snapshot), [snapshot.global.cycle as u8; 16]);
+            oldest = snapshot.global.cycle;
+        }
+        // Some history was kept, but not all of it
+        assert!(oldest > 0 && oldest < 8);
+    }
+}
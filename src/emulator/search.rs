@@ -0,0 +1,260 @@
+use anyhow::{anyhow, Result};
+use std::convert::TryInto;
+
+use super::memory::{Memory, Region};
+
+// Only the RAM a game keeps its state in is worth searching
+const SEARCHED_REGIONS: [Region; 2] = [Region::Dram, Region::Sram];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SearchWidth {
+    Byte,
+    Halfword,
+    Word,
+}
+impl SearchWidth {
+    fn size(&self) -> usize {
+        match self {
+            SearchWidth::Byte => 1,
+            SearchWidth::Halfword => 2,
+            SearchWidth::Word => 4,
+        }
+    }
+}
+
+// How each remaining candidate is compared with the value it had at the last snapshot
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SearchFilter {
+    Unchanged,
+    Changed,
+    Increased,
+    Decreased,
+    EqualTo(i64),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SearchResult {
+    pub address: usize,
+    pub previous: i64,
+    pub current: i64,
+}
+
+struct Snapshot {
+    region: Region,
+    base: usize,
+    data: Vec<u8>,
+}
+
+// Narrows every aligned value in DRAM and SRAM down to the few which behave like, say, a lives counter.
+// Each filter compares against the snapshot taken by the one before it, then takes a new snapshot.
+pub struct RamSearch {
+    width: SearchWidth,
+    signed: bool,
+    snapshots: Vec<Snapshot>,
+    // Which snapshot, and the offset into it
+    candidates: Vec<(usize, usize)>,
+}
+impl RamSearch {
+    pub fn new(memory: &Memory, width: SearchWidth, signed: bool) -> Self {
+        let snapshots = take_snapshots(memory);
+        let candidates = snapshots
+            .iter()
+            .enumerate()
+            .flat_map(|(index, snapshot)| {
+                (0..snapshot.data.len())
+                    .step_by(width.size())
+                    .map(move |offset| (index, offset))
+            })
+            .collect();
+        Self {
+            width,
+            signed,
+            snapshots,
+            candidates,
+        }
+    }
+
+    pub fn width(&self) -> SearchWidth {
+        self.width
+    }
+
+    pub fn signed(&self) -> bool {
+        self.signed
+    }
+
+    pub fn len(&self) -> usize {
+        self.candidates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.candidates.is_empty()
+    }
+
+    pub fn filter(&mut self, memory: &Memory, filter: SearchFilter) {
+        let current = take_snapshots(memory);
+        let mut candidates = std::mem::take(&mut self.candidates);
+        candidates.retain(|&(index, offset)| {
+            let previous = self.value(&self.snapshots[index].data, offset);
+            let Some(current) = self.current_value(&current, index, offset) else {
+                return false;
+            };
+            match filter {
+                SearchFilter::Unchanged => current == previous,
+                SearchFilter::Changed => current != previous,
+                SearchFilter::Increased => current > previous,
+                SearchFilter::Decreased => current < previous,
+                SearchFilter::EqualTo(value) => current == value,
+            }
+        });
+        self.candidates = candidates;
+        self.snapshots = current;
+    }
+
+    // A page of the remaining candidates, in address order
+    pub fn results(
+        &self,
+        memory: &Memory,
+        start: usize,
+        count: usize,
+    ) -> Result<Vec<SearchResult>> {
+        if start > self.candidates.len() {
+            return Err(anyhow!(
+                "Result {} is past the end of the {} results",
+                start,
+                self.candidates.len()
+            ));
+        }
+        let current = take_snapshots(memory);
+        Ok(self
+            .candidates
+            .iter()
+            .skip(start)
+            .take(count)
+            .map(|&(index, offset)| {
+                let snapshot = &self.snapshots[index];
+                let previous = self.value(&snapshot.data, offset);
+                let current = self
+                    .current_value(&current, index, offset)
+                    .unwrap_or(previous);
+                SearchResult {
+                    address: snapshot.base + offset,
+                    previous,
+                    current,
+                }
+            })
+            .collect())
+    }
+
+    // The game pak (and its SRAM) may have changed since the last snapshot
+    fn current_value(&self, current: &[Snapshot], index: usize, offset: usize) -> Option<i64> {
+        let region = self.snapshots[index].region as usize;
+        current
+            .iter()
+            .find(|snapshot| snapshot.region as usize == region)
+            .filter(|snapshot| offset + self.width.size() <= snapshot.data.len())
+            .map(|snapshot| self.value(&snapshot.data, offset))
+    }
+
+    fn value(&self, data: &[u8], offset: usize) -> i64 {
+        let bytes = &data[offset..offset + self.width.size()];
+        match (self.width, self.signed) {
+            (SearchWidth::Byte, false) => bytes[0] as i64,
+            (SearchWidth::Byte, true) => bytes[0] as i8 as i64,
+            (SearchWidth::Halfword, false) => u16::from_le_bytes(bytes.try_into().unwrap()) as i64,
+            (SearchWidth::Halfword, true) => i16::from_le_bytes(bytes.try_into().unwrap()) as i64,
+            (SearchWidth::Word, false) => u32::from_le_bytes(bytes.try_into().unwrap()) as i64,
+            (SearchWidth::Word, true) => i32::from_le_bytes(bytes.try_into().unwrap()) as i64,
+        }
+    }
+}
+
+// Missing regions (like SRAM, when the game pak has none) are left out
+fn take_snapshots(memory: &Memory) -> Vec<Snapshot> {
+    SEARCHED_REGIONS
+        .iter()
+        .filter_map(|&region| {
+            memory.read_region(region).map(|data| Snapshot {
+                region,
+                base: (region as usize) << 24,
+                data: data.to_vec(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::memory::Memory;
+    use crate::emulator::search::{RamSearch, SearchFilter, SearchResult, SearchWidth};
+
+    #[test]
+    fn starts_with_every_aligned_address() {
+        let memory = Memory::new();
+        assert_eq!(
+            RamSearch::new(&memory, SearchWidth::Byte, false).len(),
+            0x10000
+        );
+        assert_eq!(
+            RamSearch::new(&memory, SearchWidth::Word, false).len(),
+            0x4000
+        );
+    }
+
+    #[test]
+    fn narrows_down_changing_values() {
+        let mut memory = Memory::new();
+        let mut search = RamSearch::new(&memory, SearchWidth::Halfword, false);
+
+        memory.write_halfword(0x05000010, 3);
+        memory.write_halfword(0x05000020, 5);
+        search.filter(&memory, SearchFilter::Changed);
+        assert_eq!(search.len(), 2);
+
+        memory.write_halfword(0x05000010, 2);
+        memory.write_halfword(0x05000020, 6);
+        search.filter(&memory, SearchFilter::Decreased);
+        assert_eq!(search.len(), 1);
+
+        memory.write_halfword(0x05000010, 1);
+        let results = search.results(&memory, 0, 10).unwrap();
+        assert_eq!(
+            results,
+            [SearchResult {
+                address: 0x05000010,
+                previous: 2,
+                current: 1,
+            }]
+        );
+        search.filter(&memory, SearchFilter::EqualTo(1));
+        assert_eq!(search.len(), 1);
+        search.filter(&memory, SearchFilter::Changed);
+        assert!(search.is_empty());
+    }
+
+    #[test]
+    fn compares_signed_values() {
+        let mut memory = Memory::new();
+        memory.write_byte(0x05000000, 0x01);
+        let mut signed = RamSearch::new(&memory, SearchWidth::Byte, true);
+        let mut unsigned = RamSearch::new(&memory, SearchWidth::Byte, false);
+
+        memory.write_byte(0x05000000, 0xff);
+        signed.filter(&memory, SearchFilter::Decreased);
+        unsigned.filter(&memory, SearchFilter::Decreased);
+        assert_eq!(signed.len(), 1);
+        assert!(unsigned.is_empty());
+        signed.filter(&memory, SearchFilter::EqualTo(-1));
+        assert_eq!(signed.len(), 1);
+    }
+
+    #[test]
+    fn pages_through_results() {
+        let memory = Memory::new();
+        let search = RamSearch::new(&memory, SearchWidth::Word, false);
+        let page = search.results(&memory, 8, 4).unwrap();
+        let addresses: Vec<usize> = page.iter().map(|result| result.address).collect();
+        assert_eq!(addresses, [0x05000020, 0x05000024, 0x05000028, 0x0500002c]);
+        assert!(search.results(&memory, 0x4000, 4).unwrap().is_empty());
+        assert!(search.results(&memory, 0x4001, 4).is_err());
+    }
+}
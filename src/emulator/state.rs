@@ -1,22 +1,94 @@
-use anyhow::Result;
-use ciborium::{de, ser};
+use anyhow::{anyhow, Result};
+use ciborium::{de, ser, Value};
+use log::warn;
+use serde::de::DeserializeOwned;
 use serde_derive::{Deserialize, Serialize};
-use std::fs::File;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use super::audio::AudioState;
 use super::cpu::CpuState;
 use super::hardware::HardwareState;
 use super::memory::Region;
-use super::video::VideoState;
+use super::video::drawing::DrawnFrames;
+use super::video::{VideoState, VB_HEIGHT, VB_WIDTH};
 
-const VERSION: u8 = 1;
+// Version 1 states had no metadata, and stored components in one CBOR list.
+// Version 2 states store each component in its own versioned section.
+const VERSION: u8 = 2;
+const THUMBNAIL_SCALE: usize = 2;
 
 #[derive(Copy, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
 pub struct GlobalState {
     pub cycle: u64,
     pub tick_calls: u64,
 }
 
+// Everything a frontend might want to know about a state without loading it
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SaveStateMetadata {
+    // SHA-1 of the ROM which was loaded when the state was saved
+    pub rom_sha1: Option<[u8; 20]>,
+    // Seconds since the UNIX epoch
+    pub created_at: u64,
+    pub cycle: u64,
+    pub thumbnail: Option<Thumbnail>,
+}
+impl SaveStateMetadata {
+    pub fn new(rom_sha1: Option<[u8; 20]>, cycle: u64, thumbnail: Option<Thumbnail>) -> Self {
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs());
+        Self {
+            rom_sha1,
+            created_at,
+            cycle,
+            thumbnail,
+        }
+    }
+}
+
+// A scaled-down copy of the left eye, one brightness byte per pixel
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Thumbnail {
+    pub width: usize,
+    pub height: usize,
+    #[serde(with = "serde_bytes")]
+    pub pixels: Vec<u8>,
+}
+impl Thumbnail {
+    pub fn from_frame(frame: &[u8]) -> Self {
+        let width = VB_WIDTH / THUMBNAIL_SCALE;
+        let height = VB_HEIGHT / THUMBNAIL_SCALE;
+        let mut pixels = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                // Average each block of pixels, so thin lines don't vanish
+                let mut total = 0;
+                for dy in 0..THUMBNAIL_SCALE {
+                    let row = (y * THUMBNAIL_SCALE + dy) * VB_WIDTH;
+                    for dx in 0..THUMBNAIL_SCALE {
+                        total += frame[row + x * THUMBNAIL_SCALE + dx] as usize;
+                    }
+                }
+                pixels.push((total / (THUMBNAIL_SCALE * THUMBNAIL_SCALE)) as u8);
+            }
+        }
+        Self {
+            width,
+            height,
+            pixels,
+        }
+    }
+}
+
+pub struct SaveState {
+    // Missing from states saved before metadata existed
+    pub metadata: Option<SaveStateMetadata>,
+    pub data: Vec<SaveStateData>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub enum SaveStateData {
     Global(GlobalState),
@@ -27,22 +99,331 @@ pub enum SaveStateData {
     Audio(Box<AudioState>),
 }
 
-pub fn save_state(filename: &str, data: &[SaveStateData]) -> Result<()> {
-    let mut file = File::create(filename)?;
-    ser::into_writer(&VERSION, &mut file)?;
-    ser::into_writer(data, &mut file)?;
-    Ok(())
+// A copy of the emulator's state which never leaves memory, so it skips serialization entirely.
+// Only good for as long as the emulator that made it is running.
+pub struct FastState {
+    pub global: GlobalState,
+    pub memory: Vec<(Region, Vec<u8>)>,
+    pub cpu: CpuState,
+    pub video: VideoState,
+    pub drawn_frames: Box<DrawnFrames>,
+    pub hardware: HardwareState,
+    pub audio: Box<AudioState>,
+    // Not part of the hardware, but one-shot cheats have to fire again if memory is rolled back
+    pub cheats_applied: Vec<bool>,
+}
+
+// Bump a component's version whenever its state changes in a way that #[serde(default)] can't handle,
+// and add a migration from the old version to the list below.
+const GLOBAL_SECTION: (&str, u32) = ("global", 1);
+const MEMORY_SECTION: (&str, u32) = ("memory", 1);
+const CPU_SECTION: (&str, u32) = ("cpu", 1);
+const VIDEO_SECTION: (&str, u32) = ("video", 1);
+const HARDWARE_SECTION: (&str, u32) = ("hardware", 1);
+const AUDIO_SECTION: (&str, u32) = ("audio", 1);
+
+// Turns a section from (name, version) into (name, version + 1)
+type Migration = fn(Value) -> Result<Value>;
+const MIGRATIONS: &[(&str, u32, Migration)] = &[];
+
+#[derive(Serialize, Deserialize)]
+struct Section {
+    name: String,
+    version: u32,
+    #[serde(with = "serde_bytes")]
+    data: Vec<u8>,
 }
+impl Section {
+    fn new<T: serde::Serialize>((name, version): (&str, u32), value: &T) -> Result<Self> {
+        let mut data = vec![];
+        ser::into_writer(value, &mut data)?;
+        Ok(Self {
+            name: name.to_string(),
+            version,
+            data,
+        })
+    }
+
+    fn decode<T: DeserializeOwned>(
+        &self,
+        (_, current_version): (&str, u32),
+        migrations: &[(&str, u32, Migration)],
+    ) -> Result<T> {
+        self.decode_value(current_version, migrations)
+            .map_err(|err| anyhow!("Could not read {} state: {}", self.name, err))
+    }
+
+    fn decode_value<T: DeserializeOwned>(
+        &self,
+        current_version: u32,
+        migrations: &[(&str, u32, Migration)],
+    ) -> Result<T> {
+        let mut value: Value = de::from_reader(self.data.as_slice())?;
+        let mut version = self.version;
+        while version < current_version {
+            let (_, _, migration) = migrations
+                .iter()
+                .find(|(name, from, _)| *name == self.name && *from == version)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "No migration for {} state from version {}",
+                        self.name,
+                        version
+                    )
+                })?;
+            value = migration(value)?;
+            version += 1;
+        }
+        if version > current_version {
+            // Probably saved by a newer build. Unknown fields get ignored, so try reading it anyway.
+            warn!(
+                "Reading {} state from version {}, but only understand version {}",
+                self.name, version, current_version
+            );
+        }
+        Ok(value.deserialized()?)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct MemorySection {
+    region: Region,
+    #[serde(with = "serde_bytes")]
+    data: Vec<u8>,
+}
+
+pub fn save_state(metadata: &SaveStateMetadata, data: &[SaveStateData]) -> Result<Vec<u8>> {
+    let sections = data
+        .iter()
+        .map(|datum| match datum {
+            SaveStateData::Global(state) => Section::new(GLOBAL_SECTION, state),
+            SaveStateData::Memory(region, data) => Section::new(
+                MEMORY_SECTION,
+                &MemorySection {
+                    region: *region,
+                    data: data.clone(),
+                },
+            ),
+            SaveStateData::Cpu(state) => Section::new(CPU_SECTION, state),
+            SaveStateData::Video(state) => Section::new(VIDEO_SECTION, state),
+            SaveStateData::Hardware(state) => Section::new(HARDWARE_SECTION, state),
+            SaveStateData::Audio(state) => Section::new(AUDIO_SECTION, state),
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut bytes = vec![];
+    ser::into_writer(&VERSION, &mut bytes)?;
+    ser::into_writer(metadata, &mut bytes)?;
+    ser::into_writer(&sections, &mut bytes)?;
+    Ok(bytes)
+}
+
+pub fn load_state(bytes: &[u8]) -> Result<SaveState> {
+    load_state_with_migrations(bytes, MIGRATIONS)
+}
+
+fn load_state_with_migrations(
+    mut bytes: &[u8],
+    migrations: &[(&str, u32, Migration)],
+) -> Result<SaveState> {
+    let version = read_version(&mut bytes)?;
+    let metadata = read_metadata_from(version, &mut bytes)?;
+    let data = match version {
+        // Older states were a list of components, which still deserialize with their fields defaulted
+        1 => de::from_reader(bytes)?,
+        _ => {
+            let sections: Vec<Section> = de::from_reader(bytes)?;
+            read_sections(&sections, migrations)?
+        }
+    };
+    Ok(SaveState { metadata, data })
+}
+
+fn read_sections(
+    sections: &[Section],
+    migrations: &[(&str, u32, Migration)],
+) -> Result<Vec<SaveStateData>> {
+    let mut data = vec![];
+    for section in sections {
+        let datum = match section.name.as_str() {
+            name if name == GLOBAL_SECTION.0 => {
+                SaveStateData::Global(section.decode(GLOBAL_SECTION, migrations)?)
+            }
+            name if name == MEMORY_SECTION.0 => {
+                let memory: MemorySection = section.decode(MEMORY_SECTION, migrations)?;
+                SaveStateData::Memory(memory.region, memory.data)
+            }
+            name if name == CPU_SECTION.0 => {
+                SaveStateData::Cpu(section.decode(CPU_SECTION, migrations)?)
+            }
+            name if name == VIDEO_SECTION.0 => {
+                SaveStateData::Video(section.decode(VIDEO_SECTION, migrations)?)
+            }
+            name if name == HARDWARE_SECTION.0 => {
+                SaveStateData::Hardware(section.decode(HARDWARE_SECTION, migrations)?)
+            }
+            name if name == AUDIO_SECTION.0 => {
+                SaveStateData::Audio(section.decode(AUDIO_SECTION, migrations)?)
+            }
+            name => {
+                warn!("Skipping unknown {} state", name);
+                continue;
+            }
+        };
+        data.push(datum);
+    }
+    Ok(data)
+}
+
+pub fn read_metadata(mut bytes: &[u8]) -> Result<Option<SaveStateMetadata>> {
+    let version = read_version(&mut bytes)?;
+    read_metadata_from(version, &mut bytes)
+}
+
+fn read_version(bytes: &mut &[u8]) -> Result<u8> {
+    let version: u8 = de::from_reader(&mut *bytes)?;
+    if version > VERSION {
+        return Err(anyhow!("Could not read state with version {}", version));
+    }
+    Ok(version)
+}
+
+fn read_metadata_from(version: u8, bytes: &mut &[u8]) -> Result<Option<SaveStateMetadata>> {
+    match version {
+        0 => Err(anyhow!("Could not read state with version {}", version)),
+        1 => Ok(None),
+        _ => Ok(Some(de::from_reader(&mut *bytes)?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::state::{
+        load_state, load_state_with_migrations, read_metadata, save_state, GlobalState, Migration,
+        SaveStateData, SaveStateMetadata, Section, Thumbnail, VERSION,
+    };
+    use crate::emulator::video::FRAME_SIZE;
+    use anyhow::Result;
+    use ciborium::{ser, Value};
+    use std::collections::BTreeMap;
+
+    fn with_sections(sections: &[Section]) -> Vec<u8> {
+        let mut bytes = vec![];
+        ser::into_writer(&VERSION, &mut bytes).unwrap();
+        ser::into_writer(&SaveStateMetadata::default(), &mut bytes).unwrap();
+        ser::into_writer(sections, &mut bytes).unwrap();
+        bytes
+    }
+
+    fn global_cycle(data: &[SaveStateData]) -> u64 {
+        match data {
+            [SaveStateData::Global(global)] => global.cycle,
+            _ => panic!("Unexpected state data"),
+        }
+    }
+
+    fn global(cycle: u64) -> SaveStateData {
+        SaveStateData::Global(GlobalState {
+            cycle,
+            tick_calls: 0,
+        })
+    }
+
+    #[test]
+    fn round_trips_metadata_and_data() {
+        let thumbnail = Thumbnail::from_frame(&[0x40; FRAME_SIZE]);
+        let metadata = SaveStateMetadata::new(Some([7; 20]), 1234, Some(thumbnail));
+        let bytes = save_state(&metadata, &[global(1234)]).unwrap();
+
+        assert_eq!(read_metadata(&bytes).unwrap(), Some(metadata.clone()));
+        let state = load_state(&bytes).unwrap();
+        assert_eq!(state.metadata, Some(metadata));
+        match state.data.as_slice() {
+            [SaveStateData::Global(global)] => assert_eq!(global.cycle, 1234),
+            _ => panic!("Unexpected state data"),
+        }
+    }
+
+    #[test]
+    fn reads_states_without_metadata() {
+        let mut bytes = vec![];
+        ser::into_writer(&1u8, &mut bytes).unwrap();
+        ser::into_writer(&vec![global(5)], &mut bytes).unwrap();
+        let state = load_state(&bytes).unwrap();
+        assert_eq!(state.metadata, None);
+        assert_eq!(state.data.len(), 1);
+    }
+
+    #[test]
+    fn rejects_unknown_versions() {
+        let mut bytes = vec![];
+        ser::into_writer(&99u8, &mut bytes).unwrap();
+        let err = load_state(&bytes).err().unwrap();
+        assert_eq!(err.to_string(), "Could not read state with version 99");
+    }
+
+    #[test]
+    fn thumbnail_averages_pixels() {
+        let mut frame = [0; FRAME_SIZE];
+        frame[0] = 0x80;
+        frame[1] = 0x80;
+        let thumbnail = Thumbnail::from_frame(&frame);
+        assert_eq!((thumbnail.width, thumbnail.height), (192, 112));
+        assert_eq!(thumbnail.pixels[0], 0x40);
+        assert_eq!(thumbnail.pixels[1], 0);
+    }
+
+    #[test]
+    fn skips_unknown_sections() {
+        let bytes = with_sections(&[
+            Section::new(("from-the-future", 1), &"hello").unwrap(),
+            Section::new(
+                ("global", 1),
+                &GlobalState {
+                    cycle: 3,
+                    tick_calls: 0,
+                },
+            )
+            .unwrap(),
+        ]);
+        assert_eq!(global_cycle(&load_state(&bytes).unwrap().data), 3);
+    }
+
+    #[test]
+    fn defaults_missing_fields_and_ignores_new_ones() {
+        let fields = BTreeMap::from([("cycle", Value::from(9)), ("added_later", Value::from(1))]);
+        let bytes = with_sections(&[Section::new(("global", 2), &fields).unwrap()]);
+        let state = load_state(&bytes).unwrap();
+        match state.data.as_slice() {
+            [SaveStateData::Global(global)] => {
+                assert_eq!(global.cycle, 9);
+                assert_eq!(global.tick_calls, 0);
+            }
+            _ => panic!("Unexpected state data"),
+        }
+    }
+
+    #[test]
+    fn migrates_old_sections() {
+        // Pretend version 0 of the global state called its cycle "ticks"
+        fn rename_ticks(value: Value) -> Result<Value> {
+            let fields: BTreeMap<String, u64> = value.deserialized()?;
+            Ok(Value::serialized(&BTreeMap::from([(
+                "cycle",
+                fields["ticks"],
+            )]))?)
+        }
+        let migrations: &[(&str, u32, Migration)] = &[("global", 0, rename_ticks)];
+        let old = BTreeMap::from([("ticks", 42u64)]);
+        let bytes = with_sections(&[Section::new(("global", 0), &old).unwrap()]);
+
+        let state = load_state_with_migrations(&bytes, migrations).unwrap();
+        assert_eq!(global_cycle(&state.data), 42);
 
-pub fn load_state(filename: &str) -> Result<Vec<SaveStateData>> {
-    let file = File::open(filename)?;
-    let version: u8 = de::from_reader(&file)?;
-    if version != VERSION {
-        return Err(anyhow::anyhow!(
-            "Could not read state with version {}",
-            version
-        ));
+        let err = load_state(&bytes).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Could not read global state: No migration for global state from version 0"
+        );
     }
-    let result = de::from_reader(&file)?;
-    Ok(result)
 }
@@ -0,0 +1,226 @@
+use anyhow::Result;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use super::disasm;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Register {
+    General(usize),
+    System(usize),
+}
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Register::General(index) => write!(f, "r{}", index),
+            Register::System(index) => write!(f, "{}", disasm::SYS_REGISTERS[index]),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TraceEntry {
+    Instruction {
+        cycle: u64,
+        pc: usize,
+        // Both halfwords at the PC, even for 16-bit instructions
+        opcode: [u16; 2],
+        changes: Vec<(Register, u32)>,
+    },
+    Exception {
+        cycle: u64,
+        // The PC which the handler will return to
+        pc: usize,
+        code: u16,
+        handler: usize,
+        // None for errors
+        interrupt_level: Option<u8>,
+    },
+}
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceEntry::Instruction {
+                cycle,
+                pc,
+                opcode,
+                changes,
+            } => {
+                let bytes = [opcode[0].to_le_bytes(), opcode[1].to_le_bytes()].concat();
+                let instruction = disasm::disassemble_one(&bytes, *pc);
+                let raw = if instruction.size == 4 {
+                    format!("{:04x} {:04x}", opcode[0], opcode[1])
+                } else {
+                    format!("{:04x}", opcode[0])
+                };
+                write!(
+                    f,
+                    "{:>12} {:08x}  {:<9}  {:<28}",
+                    cycle, pc, raw, instruction.text
+                )?;
+                for (register, value) in changes {
+                    write!(f, " {}={:08x}", register, value)?;
+                }
+                Ok(())
+            }
+            TraceEntry::Exception {
+                cycle,
+                pc,
+                code,
+                handler,
+                interrupt_level,
+            } => {
+                let kind = match interrupt_level {
+                    Some(level) => format!("interrupt (level {})", level),
+                    None => "exception".to_string(),
+                };
+                write!(
+                    f,
+                    "{:>12} {} 0x{:04x} from {:08x} to {:08x}",
+                    cycle, kind, code, pc, handler
+                )
+            }
+        }
+    }
+}
+
+// Remembers the last few thousand things the CPU did, for figuring out why a game hung
+pub struct Tracer {
+    capacity: usize,
+    entries: VecDeque<TraceEntry>,
+}
+impl Tracer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            entries: VecDeque::with_capacity(capacity.max(1)),
+        }
+    }
+
+    pub fn record(&mut self, entry: TraceEntry) {
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    pub fn record_instruction(
+        &mut self,
+        cycle: u64,
+        pc: usize,
+        opcode: [u16; 2],
+        before: &[[u32; 32]; 2],
+        after: &[[u32; 32]; 2],
+    ) {
+        let general = before[0]
+            .iter()
+            .zip(after[0])
+            .enumerate()
+            .filter(|(_, (old, new))| *old != new)
+            .map(|(index, (_, new))| (Register::General(index), new));
+        let system = before[1]
+            .iter()
+            .zip(after[1])
+            .enumerate()
+            .filter(|(_, (old, new))| *old != new)
+            .map(|(index, (_, new))| (Register::System(index), new));
+        self.record(TraceEntry::Instruction {
+            cycle,
+            pc,
+            opcode,
+            changes: general.chain(system).collect(),
+        });
+    }
+
+    // Oldest first
+    pub fn entries(&self) -> impl Iterator<Item = &TraceEntry> {
+        self.entries.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn write(&self, writer: &mut impl Write) -> Result<()> {
+        for entry in &self.entries {
+            writeln!(writer, "{}", entry)?;
+        }
+        Ok(())
+    }
+
+    pub fn dump(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::trace::{Register, TraceEntry, Tracer};
+
+    #[test]
+    fn keeps_only_the_newest_entries() {
+        let mut tracer = Tracer::new(2);
+        for cycle in 0..3 {
+            tracer.record_instruction(cycle, 0x07000000, [0, 0], &[[0; 32]; 2], &[[0; 32]; 2]);
+        }
+        let cycles: Vec<u64> = tracer
+            .entries()
+            .map(|entry| match entry {
+                TraceEntry::Instruction { cycle, .. } => *cycle,
+                _ => panic!("Expected an instruction"),
+            })
+            .collect();
+        assert_eq!(cycles, [1, 2]);
+    }
+
+    #[test]
+    fn records_changed_registers() {
+        let mut tracer = Tracer::new(4);
+        let before = [[0; 32]; 2];
+        let mut after = before;
+        after[0][2] = 5;
+        after[1][5] = 0x2;
+        tracer.record_instruction(10, 0x07000000, [0x40a2, 0], &before, &after);
+        match tracer.entries().next().unwrap() {
+            TraceEntry::Instruction { changes, .. } => assert_eq!(
+                changes,
+                &[(Register::General(2), 5), (Register::System(5), 2)]
+            ),
+            _ => panic!("Expected an instruction"),
+        };
+    }
+
+    #[test]
+    fn writes_readable_lines() {
+        let mut tracer = Tracer::new(4);
+        let before = [[0; 32]; 2];
+        let mut after = before;
+        after[0][5] = 2;
+        tracer.record_instruction(10, 0x07000000, [0x40a2, 0], &before, &after);
+        tracer.record(TraceEntry::Exception {
+            cycle: 12,
+            pc: 0x07000002,
+            code: 0xfe40,
+            handler: 0xfffffe40,
+            interrupt_level: Some(4),
+        });
+        let mut out = vec![];
+        tracer.write(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines[0],
+            "          10 07000000  40a2       mov 2, r5                    r5=00000002"
+        );
+        assert_eq!(
+            lines[1],
+            "          12 interrupt (level 4) 0xfe40 from 07000002 to fffffe40"
+        );
+    }
+}
@@ -1,6 +1,8 @@
 use crate::emulator::cpu::Exception;
 use crate::emulator::memory::Memory;
-use crate::emulator::video::drawing::DrawingProcess;
+use crate::emulator::video::drawing::{
+    estimate_group_cycles, DrawingProcess, DrawnFrames, ROW_GROUPS,
+};
 use anyhow::Result;
 use log::error;
 use serde_derive::{Deserialize, Serialize};
@@ -11,10 +13,13 @@ use std::rc::Rc;
 
 mod buffer;
 pub mod drawing;
+pub mod inspect;
 
 pub const VB_WIDTH: usize = 384;
 pub const VB_HEIGHT: usize = 224;
 pub const FRAME_SIZE: usize = VB_WIDTH * VB_HEIGHT;
+// The display runs at 50Hz
+pub const CYCLES_PER_FRAME: u64 = 400_000;
 
 const INTPND: usize = 0x0005f800;
 const INTENB: usize = 0x0005f802;
@@ -70,6 +75,10 @@ const XPEN: u16 = 0x0002;
 const XPRST: u16 = 0x0001;
 const XP_READONLY_MASK: u16 = !(XPEN | XPRST);
 
+// The least time the XP takes to draw one group of 8 rows, so quiet scenes take 5ms to draw.
+// Busier scenes take longer, and can run past the start of the next game frame.
+const GROUP_CYCLES: u64 = 100_000 / ROW_GROUPS as u64;
+
 #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 enum Buffer {
     Buffer0,
@@ -106,6 +115,7 @@ impl TryFrom<i32> for Eye {
 }
 
 #[derive(Serialize, Deserialize)]
+#[serde(default)]
 pub struct VideoState {
     cycle: u64,
     displaying: bool,
@@ -116,6 +126,11 @@ pub struct VideoState {
     pending_interrupts: u16,
     enabled_interrupts: u16,
     display_buffer: Buffer,
+    sbcmp: u16,
+    groups_started: usize,
+    segment_group: usize,
+    segment_cycle: u64,
+    group_cycles: u64,
 }
 
 impl Default for VideoState {
@@ -130,6 +145,11 @@ impl Default for VideoState {
             pending_interrupts: 0,
             enabled_interrupts: 0,
             display_buffer: Buffer0,
+            sbcmp: 0,
+            groups_started: 0,
+            segment_group: 0,
+            segment_cycle: 0,
+            group_cycles: GROUP_CYCLES,
         }
     }
 }
@@ -192,13 +212,33 @@ pub struct Video {
     pending_interrupts: u16,
     enabled_interrupts: u16,
     display_buffer: Buffer,
+    // The row group which raises SBHIT when the XP reaches it
+    sbcmp: u16,
+    // How many row groups of this frame have been handed to the drawing process
+    groups_started: usize,
+    // The XP draws the frame in segments, so later rows can see changes made after SBHIT.
+    // This is the first group of the latest segment, when the XP started drawing it,
+    // and how long each of its groups takes.
+    segment_group: usize,
+    segment_cycle: u64,
+    group_cycles: u64,
     memory: Rc<RefCell<Memory>>,
     xp_module: DrawingProcess,
     frame_buffers: Option<FrameBuffers>,
+    // Running totals for frontends, not part of the hardware state
+    frames_started: u64,
+    frames_drawn: u64,
+    frame_skip: u64,
+    sending_frames: bool,
 }
 impl Video {
-    pub fn new(memory: Rc<RefCell<Memory>>) -> Video {
+    pub fn new(memory: Rc<RefCell<Memory>>, threaded_drawing: bool) -> Video {
         let state = VideoState::default();
+        let xp_module = if threaded_drawing {
+            DrawingProcess::new()
+        } else {
+            DrawingProcess::single_threaded()
+        };
         Video {
             cycle: state.cycle,
             displaying: state.displaying,
@@ -209,9 +249,18 @@ impl Video {
             pending_interrupts: state.pending_interrupts,
             enabled_interrupts: state.enabled_interrupts,
             display_buffer: state.display_buffer,
+            sbcmp: state.sbcmp,
+            groups_started: state.groups_started,
+            segment_group: state.segment_group,
+            segment_cycle: state.segment_cycle,
+            group_cycles: state.group_cycles,
             memory,
-            xp_module: DrawingProcess::new(),
+            xp_module,
             frame_buffers: None,
+            frames_started: 0,
+            frames_drawn: 0,
+            frame_skip: 0,
+            sending_frames: true,
         }
     }
 
@@ -236,6 +285,11 @@ impl Video {
             pending_interrupts: self.pending_interrupts,
             enabled_interrupts: self.enabled_interrupts,
             display_buffer: self.display_buffer,
+            sbcmp: self.sbcmp,
+            groups_started: self.groups_started,
+            segment_group: self.segment_group,
+            segment_cycle: self.segment_cycle,
+            group_cycles: self.group_cycles,
         }
     }
 
@@ -249,6 +303,44 @@ impl Video {
         self.pending_interrupts = state.pending_interrupts;
         self.enabled_interrupts = state.enabled_interrupts;
         self.display_buffer = state.display_buffer;
+        self.sbcmp = state.sbcmp;
+        self.groups_started = state.groups_started;
+        self.segment_group = state.segment_group;
+        self.segment_cycle = state.segment_cycle;
+        self.group_cycles = state.group_cycles;
+    }
+
+    // How many display frames have started
+    pub fn frames_started(&self) -> u64 {
+        self.frames_started
+    }
+
+    // How many frames the VIP has finished drawing
+    pub fn frames_drawn(&self) -> u64 {
+        self.frames_drawn
+    }
+
+    // Only send every (frame_skip + 1)th frame to the frame buffers, since nobody can see them all when running fast
+    pub fn set_frame_skip(&mut self, frame_skip: u64) {
+        self.frame_skip = frame_skip;
+    }
+
+    // Stops sending anything to the frame buffers, for emulating frames nobody should see
+    pub fn set_sending_frames(&mut self, sending_frames: bool) {
+        self.sending_frames = sending_frames;
+    }
+
+    fn showing_frame(&self) -> bool {
+        self.sending_frames && self.frames_started.is_multiple_of(self.frame_skip + 1)
+    }
+
+    // The frames being drawn aren't part of VideoState, but restoring mid-draw needs them
+    pub fn save_drawn_frames(&self) -> Box<DrawnFrames> {
+        self.xp_module.save_frames()
+    }
+
+    pub fn load_drawn_frames(&mut self, frames: &DrawnFrames) {
+        self.xp_module.load_frames(frames);
     }
 
     pub fn next_event(&self) -> u64 {
@@ -260,13 +352,10 @@ impl Video {
         } else {
             u64::MAX
         };
-        let next_sbcount_event = if self.drawing && (self.xpctrl_flags & SBOUT) != 0 {
-            // When we're "drawing", SBCOUNT goes through 24 values over the course of 5ms.
-            // find the current row batch based on how much time has passed
-            const CYCLES_PER_ROW_BATCH: u64 = 100000 / 24;
-            let last_draw_start = (self.cycle / 200000) * 200000;
-            (((self.cycle - last_draw_start) / CYCLES_PER_ROW_BATCH) + 1) * CYCLES_PER_ROW_BATCH
-                + last_draw_start
+        let next_sbcount_event = if (self.xpctrl_flags & SBOUT) != 0 {
+            // SBCOUNT changes (and SBHIT can happen) whenever the XP moves on to another row group,
+            // and the last one is when drawing finishes
+            self.group_start_cycle((self.current_group(self.cycle) + 1).min(ROW_GROUPS))
         } else {
             u64::MAX
         };
@@ -312,6 +401,8 @@ impl Video {
 
         if address == XPCTRL {
             let mut xpctrl = memory.read_halfword(XPCTRL);
+            // Games write SBCMP to the same bits they read SBCOUNT from
+            self.sbcmp = (xpctrl & SBCOUNT_MASK) >> 8;
 
             if (xpctrl & XPRST) != 0 {
                 self.pending_interrupts &= !XP_INTERRUPTS;
@@ -351,9 +442,12 @@ impl Video {
 
         let mut curr_ms = self.cycle / 20000;
         let next_ms = target_cycle / 20000;
+        let mut last_cycle = self.cycle;
         while curr_ms < next_ms {
             curr_ms += 1;
             self.cycle += curr_ms * 20000;
+            let ms_cycle = curr_ms * 20000;
+            self.catch_up_drawing(last_cycle, ms_cycle);
 
             match curr_ms % 20 {
                 0 => {
@@ -376,16 +470,22 @@ impl Video {
                     // Frame clock up
                     self.dpctrl_flags |= FCLK;
                     self.pending_interrupts |= FRAMESTART;
+                    self.frames_started += 1;
 
-                    if self.drawing {
+                    if self.drawing && (self.xpctrl_flags & SBOUT) != 0 {
+                        // The last frame took too long to draw, so this one gets dropped
+                        self.pending_interrupts |= TIMEERR;
+                    } else if self.drawing {
                         // Start drawing on whichever buffer was displayed before
-                        self.xp_module.start(self.memory.borrow());
                         self.xpctrl_flags |= match self.display_buffer {
                             Buffer0 => F0BSY,
                             Buffer1 => F1BSY,
                         };
-                        // Pretend we're drawing line by line
                         self.xpctrl_flags |= SBOUT;
+                        self.groups_started = 0;
+                        self.segment_group = 0;
+                        self.segment_cycle = ms_cycle;
+                        self.catch_up_drawing(last_cycle, ms_cycle);
 
                         // Switch to displaying the other buffer
                         self.display_buffer = self.display_buffer.toggle();
@@ -401,20 +501,10 @@ impl Video {
                     }
                 }
                 5 => {
-                    if self.drawing {
-                        // Actually draw on the background buffer
-                        self.draw();
-                    }
-                    if self.displaying {
+                    if self.displaying && self.showing_frame() {
                         // Actually display the left eye
                         self.build_and_send_frame(Left);
                     }
-
-                    if self.drawing {
-                        // "Stop drawing" on background buffer
-                        self.xpctrl_flags &= !(F0BSY | F1BSY | SBOUT);
-                        self.pending_interrupts |= XPEND;
-                    }
                 }
                 8 => {
                     // "Stop displaying" left eye
@@ -437,21 +527,21 @@ impl Video {
                     }
                 }
                 15 => {
-                    if self.displaying {
+                    if self.displaying && self.showing_frame() {
                         // Actually display the right eye
                         self.build_and_send_frame(Right);
                     }
                 }
-                18 => {
+                18 if self.displaying => {
                     // "Stop displaying" right eye,
-                    if self.displaying {
-                        self.dpctrl_flags &= !(R0BSY | R1BSY);
-                        self.pending_interrupts |= RFBEND;
-                    }
+                    self.dpctrl_flags &= !(R0BSY | R1BSY);
+                    self.pending_interrupts |= RFBEND;
                 }
                 _ => (),
             };
+            last_cycle = ms_cycle;
         }
+        self.catch_up_drawing(last_cycle, target_cycle);
         self.cycle = target_cycle;
 
         let mut memory = self.memory.borrow_mut();
@@ -464,7 +554,7 @@ impl Video {
             if self.drawing && (self.dpctrl_flags & DPBSY) != 0 {
                 // Find the current column based on how much time has passed since drawing started
                 let column = (((self.cycle % 200000) - 60000) / 1040) as u16;
-                let eye = if (self.cycle % 400000) < 200000 {
+                let eye = if (self.cycle % CYCLES_PER_FRAME) < CYCLES_PER_FRAME / 2 {
                     Eye::Left
                 } else {
                     Eye::Right
@@ -479,13 +569,10 @@ impl Video {
             memory.write_halfword(CTA, cta);
         }
 
-        // calculate SBCOUNT
+        // SBCOUNT is whichever row group the XP is drawing
         self.xpctrl_flags &= !SBCOUNT_MASK;
         if (self.xpctrl_flags & SBOUT) != 0 {
-            // find the current row batch based on how much time has passed
-            const CYCLES_PER_ROW_BATCH: u64 = 100000 / 24;
-            let row_batch = ((self.cycle % 100000) / CYCLES_PER_ROW_BATCH) as u16;
-            self.xpctrl_flags |= row_batch << 8;
+            self.xpctrl_flags |= (self.current_group(self.cycle) as u16) << 8;
         }
 
         dpctrl &= !DP_READONLY_MASK;
@@ -500,6 +587,64 @@ impl Video {
         Ok(())
     }
 
+    // When the XP starts (or started) drawing the given row group
+    fn group_start_cycle(&self, group: usize) -> u64 {
+        self.segment_cycle + (group.saturating_sub(self.segment_group) as u64) * self.group_cycles
+    }
+
+    fn current_group(&self, cycle: u64) -> usize {
+        let groups = cycle.saturating_sub(self.segment_cycle) / self.group_cycles;
+        (self.segment_group + groups as usize).min(ROW_GROUPS - 1)
+    }
+
+    // Everything the XP does between two cycles: raising SBHIT, starting new segments, and finishing the frame
+    fn catch_up_drawing(&mut self, from: u64, to: u64) {
+        while (self.xpctrl_flags & SBOUT) != 0 {
+            let sbcmp = self.sbcmp as usize;
+            if (self.segment_group..self.groups_started).contains(&sbcmp) {
+                let hit_cycle = self.group_start_cycle(sbcmp);
+                if from < hit_cycle && hit_cycle <= to {
+                    self.pending_interrupts |= SBHIT;
+                }
+            }
+            if self.groups_started < ROW_GROUPS {
+                let start_cycle = self.group_start_cycle(self.groups_started);
+                if start_cycle > to {
+                    break;
+                }
+                self.start_segment(start_cycle);
+                continue;
+            }
+            if self.group_start_cycle(ROW_GROUPS) <= to {
+                // Actually draw on the background buffer, and "stop drawing"
+                self.draw();
+                self.xpctrl_flags &= !(F0BSY | F1BSY | SBOUT);
+                self.pending_interrupts |= XPEND;
+                self.frames_drawn += 1;
+            }
+            break;
+        }
+    }
+
+    // Hands the next row groups to the drawing process, using whatever is in vram right now.
+    // If SBHIT is enabled, the segment stops at SBCMP, so the rows after it see any changes the game makes.
+    fn start_segment(&mut self, cycle: u64) {
+        let first = self.groups_started;
+        let sbcmp = self.sbcmp as usize;
+        let last = if (self.enabled_interrupts & SBHIT) != 0 && (first..ROW_GROUPS).contains(&sbcmp)
+        {
+            sbcmp + 1
+        } else {
+            ROW_GROUPS
+        };
+        self.segment_group = first;
+        self.segment_cycle = cycle;
+        self.groups_started = last;
+        let memory = self.memory.borrow();
+        self.group_cycles = GROUP_CYCLES.max(estimate_group_cycles(&memory, first..last));
+        self.xp_module.start(memory, first..last);
+    }
+
     pub fn claim_frame_buffer_consumers(&mut self) -> FrameBufferConsumers {
         let frame_buffers = FrameBuffers::default();
         let consumers = frame_buffers.consumers();
@@ -528,6 +673,13 @@ impl Video {
         buffer.write(|data| self.write_frame(eye, data));
     }
 
+    // Renders whatever the given eye is currently displaying, whether or not anyone claimed the frame buffers
+    pub fn capture_frame(&self, eye: Eye) -> Vec<u8> {
+        let mut frame = vec![0; FRAME_SIZE];
+        self.write_frame(eye, &mut frame);
+        frame
+    }
+
     fn write_frame(&self, eye: Eye, buffer: &mut [u8]) {
         let buf_address = self.get_buffer_address(eye, self.display_buffer);
         let memory = self.memory.borrow();
@@ -595,9 +747,10 @@ impl Video {
 #[cfg(test)]
 mod tests {
     use crate::emulator::memory::Memory;
+    use crate::emulator::video::drawing::{LON, RON, WORLD_ATTRIBUTE_MEMORY};
     use crate::emulator::video::{
-        Video, DISP, DPCTRL, DPRST, FRAMESTART, FRMCYC, GAMESTART, INTCLR, INTENB, INTPND,
-        SBCOUNT_MASK, SBOUT, XPEND, XPRST,
+        Video, DISP, DPCTRL, DPRST, FRAMESTART, FRMCYC, GAMESTART, GROUP_CYCLES, INTCLR, INTENB,
+        INTPND, SBCOUNT_MASK, SBHIT, SBOUT, TIMEERR, XPEND, XPRST,
     };
     use crate::emulator::video::{DPSTTS, FCLK, L0BSY, L1BSY, R0BSY, R1BSY, SCANRDY};
     use crate::emulator::video::{F0BSY, F1BSY, XPCTRL, XPEN, XPSTTS};
@@ -627,7 +780,7 @@ mod tests {
 
     fn get_video() -> (Video, Rc<RefCell<Memory>>) {
         let memory = Rc::new(RefCell::new(Memory::new()));
-        let video = Video::new(Rc::clone(&memory));
+        let video = Video::new(Rc::clone(&memory), true);
         (video, memory)
     }
 
@@ -949,4 +1102,84 @@ mod tests {
         assert_eq!(memory.borrow().read_halfword(INTPND) & XPEND, 0);
         assert!(video.active_interrupt().is_none());
     }
+
+    #[test]
+    fn counts_row_groups_while_drawing() {
+        let (mut video, memory) = get_video();
+
+        video.init();
+        write_xpctrl(&mut video, &memory, XPEN);
+
+        let draw_start = ms_to_cycles(40);
+        video.run(draw_start + GROUP_CYCLES * 3 + 1).unwrap();
+        assert_eq!(memory.borrow().read_halfword(XPSTTS) & SBCOUNT_MASK, 3 << 8);
+        assert_eq!(video.next_event(), draw_start + GROUP_CYCLES * 4);
+
+        video.run(draw_start + GROUP_CYCLES * 27).unwrap();
+        assert_eq!(
+            memory.borrow().read_halfword(XPSTTS) & SBCOUNT_MASK,
+            27 << 8
+        );
+        assert_eq!(video.next_event(), draw_start + GROUP_CYCLES * 28);
+
+        // Drawing finishes once the last group is done
+        video.run(draw_start + GROUP_CYCLES * 28).unwrap();
+        assert_eq!(memory.borrow().read_halfword(XPSTTS), XPEN);
+        assert_ne!(memory.borrow().read_halfword(INTPND) & XPEND, 0);
+    }
+
+    #[test]
+    fn raises_timeerr_when_drawing_overruns_the_game_frame() {
+        let (mut video, memory) = get_video();
+
+        video.init();
+        // Eight full screen affine worlds are far too much to draw in one frame
+        for world in 0..8 {
+            let address = WORLD_ATTRIBUTE_MEMORY + (31 - world) * 32;
+            let mut memory = memory.borrow_mut();
+            memory.write_halfword(address, LON | RON | (2 << 12));
+            memory.write_halfword(address + 14, 383);
+            memory.write_halfword(address + 16, 223);
+        }
+        write_xpctrl(&mut video, &memory, XPEN);
+
+        video.run(ms_to_cycles(39)).unwrap();
+        assert_eq!(memory.borrow().read_halfword(INTPND) & (TIMEERR | XPEND), 0);
+
+        // The XP is still drawing when the next game frame starts
+        video.run(ms_to_cycles(40)).unwrap();
+        assert_ne!(memory.borrow().read_halfword(INTPND) & TIMEERR, 0);
+        assert_ne!(memory.borrow().read_halfword(XPSTTS) & SBOUT, 0);
+
+        // It finishes the late frame instead of starting a new one
+        video.run(ms_to_cycles(59)).unwrap();
+        assert_ne!(memory.borrow().read_halfword(INTPND) & XPEND, 0);
+        assert_eq!(memory.borrow().read_halfword(XPSTTS) & SBOUT, 0);
+    }
+
+    #[test]
+    fn can_trigger_sbhit_interrupt() {
+        let (mut video, memory) = get_video();
+
+        video.init();
+        write_xpctrl(&mut video, &memory, XPEN | (5 << 8));
+        write_intenb(&mut video, &memory, SBHIT);
+
+        // The interrupt happens right as the XP starts on row group SBCMP
+        let hit_cycle = ms_to_cycles(20) + GROUP_CYCLES * 5;
+        video.run(hit_cycle - 1).unwrap();
+        assert_eq!(memory.borrow().read_halfword(INTPND) & SBHIT, 0);
+        assert_eq!(video.next_event(), hit_cycle);
+        video.run(hit_cycle).unwrap();
+        assert_ne!(memory.borrow().read_halfword(INTPND) & SBHIT, 0);
+        assert!(video.active_interrupt().is_some());
+        assert_eq!(memory.borrow().read_halfword(XPSTTS) & SBCOUNT_MASK, 5 << 8);
+
+        // It only happens once per frame
+        write_intclr(&mut video, &memory, SBHIT);
+        video.run(ms_to_cycles(39)).unwrap();
+        assert_eq!(memory.borrow().read_halfword(INTPND) & SBHIT, 0);
+        video.run(ms_to_cycles(40) + GROUP_CYCLES * 5).unwrap();
+        assert_ne!(memory.borrow().read_halfword(INTPND) & SBHIT, 0);
+    }
 }
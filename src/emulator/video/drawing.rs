@@ -1,51 +1,136 @@
 use crate::emulator::memory::{Memory, Region};
 use crate::emulator::video::Eye;
 use std::cell::{Ref, RefMut};
-use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::ops::Range;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock};
 use std::thread::JoinHandle;
 
-const BACKGROUND_MAP_MEMORY: usize = 0x00020000;
-const WORLD_ATTRIBUTE_MEMORY: usize = 0x0003d800;
-const OBJECT_ATTRIBUTE_MEMORY: usize = 0x0003e000;
-const CHARACTER_TABLE: usize = 0x00078000;
+pub(super) const BACKGROUND_MAP_MEMORY: usize = 0x00020000;
+pub(super) const WORLD_ATTRIBUTE_MEMORY: usize = 0x0003d800;
+pub(super) const OBJECT_ATTRIBUTE_MEMORY: usize = 0x0003e000;
+pub(super) const CHARACTER_TABLE: usize = 0x00078000;
 
 const SPT0: usize = 0x0005f848;
 
-const GPLT0: usize = 0x0005f860;
+pub(super) const GPLT0: usize = 0x0005f860;
 
-const JPLT0: usize = 0x0005f868;
+pub(super) const JPLT0: usize = 0x0005f868;
 
 const BKCOL: usize = 0x0005f870;
 
 // World attribute flags
-const LON: u16 = 0x8000;
-const RON: u16 = 0x4000;
-const BGM: u16 = 0x3000;
-const SCX: u16 = 0x0c00;
-const SCY: u16 = 0x0300;
-const OVERPLANE_FLAG: u16 = 0x0080;
-const END_FLAG: u16 = 0x0040;
-const BG_MAP_BASE: u16 = 0x000f;
+pub(super) const LON: u16 = 0x8000;
+pub(super) const RON: u16 = 0x4000;
+pub(super) const BGM: u16 = 0x3000;
+pub(super) const SCX: u16 = 0x0c00;
+pub(super) const SCY: u16 = 0x0300;
+pub(super) const OVERPLANE_FLAG: u16 = 0x0080;
+pub(super) const END_FLAG: u16 = 0x0040;
+pub(super) const BG_MAP_BASE: u16 = 0x000f;
 
 // Object attribute flags
-const JX: u16 = 0x03ff;
-const JLON: u16 = 0x8000;
-const JRON: u16 = 0x4000;
-const JP: u16 = 0x03ff;
-const JY: u16 = 0x00ff;
-const JHFLP: u16 = 0x2000;
-const JVFLP: u16 = 0x1000;
-const JCA: u16 = 0x07ff;
+pub(super) const JX: u16 = 0x03ff;
+pub(super) const JLON: u16 = 0x8000;
+pub(super) const JRON: u16 = 0x4000;
+pub(super) const JP: u16 = 0x03ff;
+pub(super) const JY: u16 = 0x00ff;
+pub(super) const JHFLP: u16 = 0x2000;
+pub(super) const JVFLP: u16 = 0x1000;
+pub(super) const JCA: u16 = 0x07ff;
 
 fn modulus(a: i16, b: i16) -> u16 {
     debug_assert_eq!(b.count_ones(), 1);
     (a & (b - 1)) as u16
 }
 
-// Coordinates the drawing process between two workers on their own threads
+// The VIP draws the screen in groups of 8 rows
+pub const ROW_GROUPS: usize = 28;
+
+// What each eye's worker has drawn, but not yet written to the frame buffer
+pub type DrawnFrames = [[[u16; 384]; ROW_GROUPS]; 2];
+
+// Rough guesses at how quickly the XP works through a scene, since nobody has measured it.
+// Affine worlds look up every pixel's source separately, so they're much slower than the others.
+const PIXELS_PER_CYCLE: u64 = 8;
+const AFFINE_PIXELS_PER_CYCLE: u64 = 2;
+const OBJECT_CYCLES: u64 = 8;
+
+// How long the XP would spend on each of the given row groups with what's in vram now, on average
+pub fn estimate_group_cycles(memory: &Memory, row_groups: Range<usize>) -> u64 {
+    let top = row_groups.start as i32 * 8;
+    let bottom = row_groups.end as i32 * 8;
+    let rows_drawn = |y: i32, height: i32| (bottom.min(y + height) - top.max(y)).max(0) as u64;
+
+    let mut cycles = 0;
+    let mut object_world = 3;
+    for world in (0..32).rev() {
+        let world_address = WORLD_ATTRIBUTE_MEMORY + (world * 32);
+        let header = memory.read_halfword(world_address);
+        if (header & END_FLAG) != 0 {
+            break;
+        }
+        let eyes = (header & LON != 0) as u64 + (header & RON != 0) as u64;
+        if eyes == 0 {
+            continue;
+        }
+        let bgm = (header & BGM) >> 12;
+        if bgm == 3 {
+            // Walk the same objects draw_object_world would
+            let mut obj_index = memory.read_halfword(SPT0 + (object_world * 2)) as usize & 0x03ff;
+            let target_obj_index = if object_world == 0 {
+                object_world = 3;
+                1023
+            } else {
+                object_world -= 1;
+                memory.read_halfword(SPT0 + (object_world * 2)) as usize & 0x03ff
+            };
+            while obj_index != target_obj_index {
+                let obj_address = OBJECT_ATTRIBUTE_MEMORY + (obj_index * 8);
+                let attributes = memory.read_halfword(obj_address + 2);
+                let obj_eyes = (attributes & JLON != 0) as u64 + (attributes & JRON != 0) as u64;
+                let jy = (memory.read_halfword(obj_address + 4) & JY) as i16;
+                let jy = if jy > 224 {
+                    jy.wrapping_shl(8).wrapping_shr(8)
+                } else {
+                    jy
+                };
+                if rows_drawn(jy as i32, 8) > 0 {
+                    cycles += OBJECT_CYCLES * obj_eyes;
+                }
+                obj_index = if obj_index == 0 { 1023 } else { obj_index - 1 };
+            }
+            continue;
+        }
+
+        let dest_y = memory.read_halfword(world_address + 6) as i16 as i32;
+        let width = memory.read_halfword(world_address + 14) as i16 as i32 + 1;
+        let height = i32::max(
+            memory.read_halfword(world_address + 16) as i16 as i32 + 1,
+            8,
+        );
+        let pixels = rows_drawn(dest_y, height) * width.max(0) as u64 * eyes;
+        cycles += if bgm == 2 {
+            pixels / AFFINE_PIXELS_PER_CYCLE
+        } else {
+            pixels / PIXELS_PER_CYCLE
+        };
+    }
+    cycles / row_groups.len().max(1) as u64
+}
+
+// Coordinates the drawing process between two workers,
+// either on their own threads or inline on the emulator's thread
 pub struct DrawingProcess {
-    memory: Arc<RwLock<Memory>>,
-    workers: [Worker; 2],
+    mode: DrawingMode,
+}
+enum DrawingMode {
+    Threaded {
+        memory: Arc<RwLock<Memory>>,
+        workers: [Worker; 2],
+    },
+    Inline {
+        logic: Box<[DrawingLogic; 2]>,
+    },
 }
 impl Default for DrawingProcess {
     fn default() -> Self {
@@ -59,25 +144,93 @@ impl DrawingProcess {
             Worker::new(Arc::clone(&memory), Eye::Left),
             Worker::new(Arc::clone(&memory), Eye::Right),
         ];
-        Self { memory, workers }
+        Self {
+            mode: DrawingMode::Threaded { memory, workers },
+        }
+    }
+
+    // Draws on whichever thread calls start, without spawning any workers
+    pub fn single_threaded() -> Self {
+        let logic = Box::new([DrawingLogic::new(Eye::Left), DrawingLogic::new(Eye::Right)]);
+        Self {
+            mode: DrawingMode::Inline { logic },
+        }
+    }
+
+    // Capture the current contents of vram and start drawing the given row groups in the background.
+    // Row groups drawn earlier in the frame are left alone, so later ones can see different vram.
+    pub fn start(&mut self, memory: Ref<Memory>, row_groups: Range<usize>) {
+        match &mut self.mode {
+            DrawingMode::Threaded {
+                memory: vram,
+                workers,
+            } => {
+                // The workers read from the copy, so they have to finish with it first
+                for worker in workers.iter() {
+                    drop(worker.wait_until_idle());
+                }
+                if let Some(real_vram) = memory.read_region(Region::Vram) {
+                    if let Some(vram) = vram.write().unwrap().write_region(Region::Vram) {
+                        vram.copy_from_slice(real_vram);
+                    }
+                }
+                for worker in workers.iter_mut() {
+                    worker.start(row_groups.clone());
+                }
+            }
+            DrawingMode::Inline { logic } => {
+                // Nothing to capture, just draw it all right now
+                for logic in logic.iter_mut() {
+                    logic.draw(&memory, row_groups.clone());
+                }
+            }
+        }
     }
 
-    // Capture the current contents of vram and start drawing in the background
-    pub fn start(&mut self, memory: Ref<Memory>) {
-        if let Some(real_vram) = memory.read_region(Region::Vram) {
-            if let Some(vram) = self.memory.write().unwrap().write_region(Region::Vram) {
-                vram.copy_from_slice(real_vram);
+    // Waits for any drawing in progress, and copies the results
+    pub fn save_frames(&self) -> Box<DrawnFrames> {
+        let mut frames = Box::new([[[0; 384]; ROW_GROUPS]; 2]);
+        match &self.mode {
+            DrawingMode::Threaded { workers, .. } => {
+                for (frame, worker) in frames.iter_mut().zip(workers) {
+                    *frame = worker.wait_until_idle().logic.buffer;
+                }
+            }
+            DrawingMode::Inline { logic } => {
+                for (frame, logic) in frames.iter_mut().zip(logic.iter()) {
+                    *frame = logic.buffer;
+                }
             }
         }
-        for worker in self.workers.iter_mut() {
-            worker.start();
+        frames
+    }
+
+    // Replaces the results of the last drawing, as if they had just been drawn
+    pub fn load_frames(&mut self, frames: &DrawnFrames) {
+        match &mut self.mode {
+            DrawingMode::Threaded { workers, .. } => {
+                for (frame, worker) in frames.iter().zip(workers) {
+                    worker.wait_until_idle().logic.buffer = *frame;
+                }
+            }
+            DrawingMode::Inline { logic } => {
+                for (frame, logic) in frames.iter().zip(logic.iter_mut()) {
+                    logic.buffer = *frame;
+                }
+            }
         }
     }
 
     // Draw the contents of the given eye to the given address in memory
     pub fn draw_eye(&mut self, memory: &mut RefMut<Memory>, eye: Eye, buf_address: usize) {
-        let worker = &self.workers[eye as usize];
-        worker.draw_eye(memory, buf_address);
+        match &self.mode {
+            DrawingMode::Threaded { workers, .. } => {
+                workers[eye as usize].draw_eye(memory, buf_address);
+            }
+            DrawingMode::Inline { logic } => {
+                logic[eye as usize].update(memory, buf_address);
+            }
+        }
     }
 }
 
@@ -132,9 +285,10 @@ impl Worker {
     }
 
     // Wake the thread up so it starts drawing
-    pub fn start(&mut self) {
+    pub fn start(&mut self, row_groups: Range<usize>) {
         let (state, start, _) = &*self.state;
         let mut state = state.lock().unwrap();
+        state.row_groups = row_groups;
         state.processing = true;
         start.notify_one();
     }
@@ -142,18 +296,23 @@ impl Worker {
     // Block until the thread has finished drawing,
     // and then write the results to the given address
     pub fn draw_eye(&self, memory: &mut RefMut<Memory>, buf_address: usize) {
+        self.wait_until_idle().logic.update(memory, buf_address);
+    }
+
+    fn wait_until_idle(&self) -> MutexGuard<'_, ThreadState> {
         let (state, _, stop) = &*self.state;
         let mut state = state.lock().unwrap();
         while state.processing {
             state = stop.wait(state).unwrap();
         }
-        state.logic.update(memory, buf_address);
+        state
     }
 }
 
 struct ThreadState {
     memory: Arc<RwLock<Memory>>,
     logic: DrawingLogic,
+    row_groups: Range<usize>,
     processing: bool,
     terminated: bool,
 }
@@ -162,19 +321,24 @@ impl ThreadState {
         Self {
             memory,
             logic: DrawingLogic::new(eye),
+            row_groups: 0..ROW_GROUPS,
             processing: false,
             terminated: false,
         }
     }
     pub fn draw(&mut self) {
-        self.logic.draw(&self.memory.read().unwrap());
+        self.logic
+            .draw(&self.memory.read().unwrap(), self.row_groups.clone());
     }
 }
 
 struct DrawingLogic {
     eye: Eye,
-    buffer: [[u16; 384]; 28],
+    buffer: [[u16; 384]; ROW_GROUPS],
     object_world: usize,
+    // Which pixel rows are being drawn right now
+    top: i16,
+    bottom: i16,
 
     last_char_rel_address: u16,
     last_char_data: u16,
@@ -191,8 +355,10 @@ impl DrawingLogic {
     pub fn new(eye: Eye) -> Self {
         Self {
             eye,
-            buffer: [[0; 384]; 28],
+            buffer: [[0; 384]; ROW_GROUPS],
             object_world: 3,
+            top: 0,
+            bottom: 224,
 
             last_char_rel_address: u16::MAX,
             last_char_data: 0,
@@ -215,15 +381,18 @@ impl DrawingLogic {
         }
     }
 
-    // Prepares the buffer with the contents of the appropriate eye
-    pub fn draw(&mut self, memory: &Memory) {
-        // Clear both frames to BKCOL
+    // Prepares the given row groups of the buffer with the contents of the appropriate eye
+    pub fn draw(&mut self, memory: &Memory, row_groups: Range<usize>) {
+        self.top = row_groups.start as i16 * 8;
+        self.bottom = row_groups.end as i16 * 8;
+
+        // Clear those rows to BKCOL
         let bkcol = memory.read_halfword(BKCOL) & 0x03;
         let fill = (0..16)
             .step_by(2)
             .map(|shift| bkcol << shift)
             .fold(0, |a, b| a | b);
-        for row in self.buffer.iter_mut() {
+        for row in self.buffer[row_groups].iter_mut() {
             for column in row.iter_mut() {
                 *column = fill;
             }
@@ -285,7 +454,10 @@ impl DrawingLogic {
             Eye::Right => dest_x + dest_parallax_x,
         };
 
-        for row in 0..height {
+        // Skip straight to the rows being drawn
+        let first_row = self.top.saturating_sub(dest_y).max(0);
+        let last_row = self.bottom.saturating_sub(dest_y).min(height);
+        for row in first_row..last_row {
             for column in 0..width {
                 // figure out which cell in this background map is being read
                 let (bg_x, bg_y) = background.get_coords(self.eye, column, row);
@@ -418,7 +590,7 @@ impl DrawingLogic {
     }
 
     fn draw_pixel(&mut self, column: i16, row: i16, color: u16) {
-        if column < 0 || row < 0 || column >= 384 || row >= 224 {
+        if column < 0 || row < self.top || column >= 384 || row >= self.bottom {
             return;
         }
         let row_index = row as usize >> 3;
@@ -449,7 +621,7 @@ struct Background<'a> {
     pub param_base: usize,
 }
 impl Background<'_> {
-    pub fn parse(memory: &Memory, address: usize) -> Background {
+    pub fn parse(memory: &Memory, address: usize) -> Background<'_> {
         let header = memory.read_halfword(address);
         let bgm = (header & BGM) >> 12;
         let mode = match bgm {
@@ -575,3 +747,53 @@ impl Background<'_> {
         BACKGROUND_MAP_MEMORY + (map_index << 13) + (index << 1)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::memory::{Memory, Region};
+    use crate::emulator::video::drawing::{DrawingProcess, ROW_GROUPS};
+    use crate::emulator::video::Eye;
+    use std::cell::RefCell;
+
+    fn draw(mut xp: DrawingProcess, memory: &RefCell<Memory>) -> Vec<u8> {
+        xp.start(memory.borrow(), 0..ROW_GROUPS);
+        xp.draw_eye(&mut memory.borrow_mut(), Eye::Left, 0x00000000);
+        xp.draw_eye(&mut memory.borrow_mut(), Eye::Right, 0x00010000);
+        memory.borrow().read_region(Region::Vram).unwrap()[..0x00018000].to_vec()
+    }
+
+    #[test]
+    fn single_threaded_drawing_matches_threaded_drawing() {
+        let memory = RefCell::new(Memory::new());
+        // fill vram with garbage so there's something to draw
+        let mut seed = 0x12345678u32;
+        for byte in memory.borrow_mut().write_region(Region::Vram).unwrap() {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            *byte = (seed >> 16) as u8;
+        }
+        let threaded = draw(DrawingProcess::new(), &memory);
+        let single_threaded = draw(DrawingProcess::single_threaded(), &memory);
+        assert!(threaded == single_threaded);
+    }
+
+    #[test]
+    fn later_row_groups_see_later_vram() {
+        let memory = RefCell::new(Memory::new());
+        // Nothing but the background color
+        memory
+            .borrow_mut()
+            .write_halfword(0x0003d800 + 31 * 32, 0x0040);
+        memory.borrow_mut().write_halfword(0x0005f870, 1);
+
+        let mut xp = DrawingProcess::new();
+        xp.start(memory.borrow(), 0..10);
+        memory.borrow_mut().write_halfword(0x0005f870, 2);
+        xp.start(memory.borrow(), 10..ROW_GROUPS);
+        xp.draw_eye(&mut memory.borrow_mut(), Eye::Left, 0x00000000);
+
+        let memory = memory.borrow();
+        assert_eq!(memory.read_halfword(9 * 2), 0x5555);
+        assert_eq!(memory.read_halfword(10 * 2), 0xaaaa);
+        assert_eq!(memory.read_halfword(383 * 64 + 27 * 2), 0xaaaa);
+    }
+}
@@ -0,0 +1,312 @@
+// Decodes VIP memory for debugging tools, without drawing anything to the frame buffers
+use crate::emulator::memory::Memory;
+use crate::emulator::video::drawing::{
+    BACKGROUND_MAP_MEMORY, BGM, BG_MAP_BASE, CHARACTER_TABLE, END_FLAG, GPLT0, JCA, JHFLP, JLON,
+    JP, JPLT0, JRON, JVFLP, JX, JY, LON, OBJECT_ATTRIBUTE_MEMORY, OVERPLANE_FLAG, RON, SCX, SCY,
+    WORLD_ATTRIBUTE_MEMORY,
+};
+use anyhow::{anyhow, Result};
+
+pub const CHARACTER_COUNT: usize = 2048;
+pub const BG_MAP_COUNT: usize = 14;
+pub const WORLD_COUNT: usize = 32;
+pub const OBJECT_COUNT: usize = 1024;
+
+// Characters are laid out in a grid this many wide
+const CHARACTERS_PER_ROW: usize = 32;
+// Each BG map is 64x64 cells
+const BG_MAP_CELLS: usize = 64;
+
+// How bright each of the four shades is, from off to full
+const SHADES: [u8; 4] = [0, 85, 170, 255];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    // RGBA, one row after another
+    pub pixels: Vec<u8>,
+}
+impl Image {
+    fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![0; width * height * 4],
+        }
+    }
+
+    // Pixel value 0 is always transparent, the rest are shades of red like on the real thing
+    fn set(&mut self, x: usize, y: usize, pixel: u16, shade: u16) {
+        let index = (y * self.width + x) * 4;
+        let alpha = if pixel == 0 { 0 } else { 255 };
+        self.pixels[index..index + 4].copy_from_slice(&[SHADES[shade as usize & 3], 0, 0, alpha]);
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Palette {
+    // The pixel values themselves, 0-3
+    Raw,
+    // GPLT0-3
+    Background(usize),
+    // JPLT0-3
+    Object(usize),
+}
+impl Palette {
+    fn shade(&self, memory: &Memory, pixel: u16) -> u16 {
+        let address = match *self {
+            Palette::Raw => return pixel,
+            Palette::Background(index) => GPLT0 + (index & 3) * 2,
+            Palette::Object(index) => JPLT0 + (index & 3) * 2,
+        };
+        (memory.read_halfword(address) >> (pixel * 2)) & 0x03
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WorldMode {
+    Normal,
+    HBias,
+    Affine,
+    Object,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WorldAttributes {
+    pub left_enabled: bool,
+    pub right_enabled: bool,
+    pub mode: WorldMode,
+    // The world's background is 2^scx by 2^scy BG maps, starting from bg_map_base
+    pub scx: u16,
+    pub scy: u16,
+    pub overplane: bool,
+    pub end: bool,
+    pub bg_map_base: usize,
+    pub gx: i16,
+    pub gp: i16,
+    pub gy: i16,
+    pub mx: i16,
+    pub mp: i16,
+    pub my: i16,
+    pub width: u16,
+    pub height: u16,
+    pub param_base: u16,
+    pub overplane_cell: u16,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ObjectAttributes {
+    pub jx: i16,
+    pub jp: i16,
+    pub jy: i16,
+    pub left_enabled: bool,
+    pub right_enabled: bool,
+    pub palette: usize,
+    pub flip_horizontally: bool,
+    pub flip_vertically: bool,
+    pub character: u16,
+}
+
+fn character_pixel(memory: &Memory, index: usize, x: usize, y: usize) -> u16 {
+    let row = memory.read_halfword(CHARACTER_TABLE + (index % CHARACTER_COUNT) * 16 + y * 2);
+    (row >> (x * 2)) & 0x3
+}
+
+// Every character, 32 to a row
+pub fn characters(memory: &Memory, palette: Palette) -> Image {
+    let rows = CHARACTER_COUNT / CHARACTERS_PER_ROW;
+    let mut image = Image::new(CHARACTERS_PER_ROW * 8, rows * 8);
+    for index in 0..CHARACTER_COUNT {
+        let left = (index % CHARACTERS_PER_ROW) * 8;
+        let top = (index / CHARACTERS_PER_ROW) * 8;
+        for y in 0..8 {
+            for x in 0..8 {
+                let pixel = character_pixel(memory, index, x, y);
+                image.set(left + x, top + y, pixel, palette.shade(memory, pixel));
+            }
+        }
+    }
+    image
+}
+
+// One 512x512 BG map, with every cell drawn in its own palette
+pub fn bg_map(memory: &Memory, index: usize) -> Result<Image> {
+    if index >= BG_MAP_COUNT {
+        return Err(anyhow!("BG map index must be less than {}", BG_MAP_COUNT));
+    }
+    let size = BG_MAP_CELLS * 8;
+    let mut image = Image::new(size, size);
+    let base = BACKGROUND_MAP_MEMORY + index * BG_MAP_CELLS * BG_MAP_CELLS * 2;
+    for cell in 0..BG_MAP_CELLS * BG_MAP_CELLS {
+        let data = memory.read_halfword(base + cell * 2);
+        let palette = Palette::Background((data >> 14) as usize & 0x3);
+        let flip_horizontally = data & 0x2000 != 0;
+        let flip_vertically = data & 0x1000 != 0;
+        let character = (data & 0x07ff) as usize;
+
+        let left = (cell % BG_MAP_CELLS) * 8;
+        let top = (cell / BG_MAP_CELLS) * 8;
+        for y in 0..8 {
+            let char_y = if flip_vertically { 7 - y } else { y };
+            for x in 0..8 {
+                let char_x = if flip_horizontally { 7 - x } else { x };
+                let pixel = character_pixel(memory, character, char_x, char_y);
+                image.set(left + x, top + y, pixel, palette.shade(memory, pixel));
+            }
+        }
+    }
+    Ok(image)
+}
+
+// All 32 worlds, in memory order. The VIP draws them from 31 down, stopping at the first with END set.
+pub fn worlds(memory: &Memory) -> Vec<WorldAttributes> {
+    (0..WORLD_COUNT)
+        .map(|world| {
+            let address = WORLD_ATTRIBUTE_MEMORY + world * 32;
+            let read = |offset: usize| memory.read_halfword(address + offset);
+            let header = read(0);
+            let mode = match (header & BGM) >> 12 {
+                0 => WorldMode::Normal,
+                1 => WorldMode::HBias,
+                2 => WorldMode::Affine,
+                _ => WorldMode::Object,
+            };
+            WorldAttributes {
+                left_enabled: header & LON != 0,
+                right_enabled: header & RON != 0,
+                mode,
+                scx: (header & SCX) >> 10,
+                scy: (header & SCY) >> 8,
+                overplane: header & OVERPLANE_FLAG != 0,
+                end: header & END_FLAG != 0,
+                bg_map_base: (header & BG_MAP_BASE) as usize,
+                gx: read(2) as i16,
+                gp: read(4) as i16,
+                gy: read(6) as i16,
+                mx: read(8) as i16,
+                mp: read(10) as i16,
+                my: read(12) as i16,
+                width: read(14),
+                height: read(16),
+                param_base: read(18),
+                overplane_cell: read(20),
+            }
+        })
+        .collect()
+}
+
+// All 1024 objects, decoded the same way they're drawn
+pub fn objects(memory: &Memory) -> Vec<ObjectAttributes> {
+    (0..OBJECT_COUNT)
+        .map(|object| {
+            let address = OBJECT_ATTRIBUTE_MEMORY + object * 8;
+            let read = |offset: usize| memory.read_halfword(address + offset);
+            let jy = (read(4) & JY) as i16;
+            // JY is effectively the lower 8 bits of an i16, so anything past the screen is negative
+            let jy = if jy > 224 {
+                jy.wrapping_shl(8).wrapping_shr(8)
+            } else {
+                jy
+            };
+            ObjectAttributes {
+                jx: ((read(0) & JX) as i16).wrapping_shl(6).wrapping_shr(6),
+                jp: ((read(2) & JP) as i16).wrapping_shl(6).wrapping_shr(6),
+                jy,
+                left_enabled: read(2) & JLON != 0,
+                right_enabled: read(2) & JRON != 0,
+                palette: (read(6) >> 14) as usize & 0x3,
+                flip_horizontally: read(6) & JHFLP != 0,
+                flip_vertically: read(6) & JVFLP != 0,
+                character: read(6) & JCA,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::memory::Memory;
+    use crate::emulator::video::inspect::{
+        bg_map, characters, objects, worlds, Image, Palette, WorldMode,
+    };
+
+    fn pixel(image: &Image, x: usize, y: usize) -> [u8; 4] {
+        let index = (y * image.width + x) * 4;
+        image.pixels[index..index + 4].try_into().unwrap()
+    }
+
+    #[test]
+    fn draws_characters_with_a_palette() {
+        let mut memory = Memory::new();
+        // Character 33's top row is pixel values 0, 1, 2, 3
+        memory.write_halfword(0x00078000 + 33 * 16, 0b11_10_01_00);
+        // GPLT1 swaps shades 1 and 3
+        memory.write_halfword(0x0005f862, 0b01_10_11_00);
+
+        let raw = characters(&memory, Palette::Raw);
+        assert_eq!((raw.width, raw.height), (256, 512));
+        assert_eq!(pixel(&raw, 8, 8), [0, 0, 0, 0]);
+        assert_eq!(pixel(&raw, 9, 8), [85, 0, 0, 255]);
+        assert_eq!(pixel(&raw, 11, 8), [255, 0, 0, 255]);
+
+        let paletted = characters(&memory, Palette::Background(1));
+        assert_eq!(pixel(&paletted, 9, 8), [255, 0, 0, 255]);
+        assert_eq!(pixel(&paletted, 11, 8), [85, 0, 0, 255]);
+    }
+
+    #[test]
+    fn draws_bg_maps_with_flipped_cells() {
+        let mut memory = Memory::new();
+        memory.write_halfword(0x00078000 + 16, 0b11);
+        memory.write_halfword(0x0005f860, 0b11_10_01_00);
+        // BG map 2, cell (1, 0) is character 1 flipped horizontally
+        memory.write_halfword(0x00020000 + 2 * 0x2000 + 2, 0x2001);
+
+        let image = bg_map(&memory, 2).unwrap();
+        assert_eq!((image.width, image.height), (512, 512));
+        assert_eq!(pixel(&image, 8, 0), [0, 0, 0, 0]);
+        assert_eq!(pixel(&image, 15, 0), [255, 0, 0, 255]);
+        assert!(bg_map(&memory, 14).is_err());
+    }
+
+    #[test]
+    fn parses_worlds() {
+        let mut memory = Memory::new();
+        let world = 0x0003d800 + 31 * 32;
+        memory.write_halfword(world, 0xc000 | 0x1000 | 0x0400 | 0x0080 | 0x0003);
+        memory.write_halfword(world + 2, (-8i16) as u16);
+        memory.write_halfword(world + 14, 383);
+        memory.write_halfword(0x0003d800 + 30 * 32, 0x0040);
+
+        let worlds = worlds(&memory);
+        assert_eq!(worlds.len(), 32);
+        let world = &worlds[31];
+        assert!(world.left_enabled && world.right_enabled && world.overplane);
+        assert_eq!(world.mode, WorldMode::HBias);
+        assert_eq!((world.scx, world.scy), (1, 0));
+        assert_eq!(world.bg_map_base, 3);
+        assert_eq!(world.gx, -8);
+        assert_eq!(world.width, 383);
+        assert!(worlds[30].end);
+    }
+
+    #[test]
+    fn parses_objects() {
+        let mut memory = Memory::new();
+        let object = 0x0003e000 + 5 * 8;
+        memory.write_halfword(object, 0x03ff);
+        memory.write_halfword(object + 2, 0x8000 | 0x0002);
+        memory.write_halfword(object + 4, 0x00f0);
+        memory.write_halfword(object + 6, 0x8000 | 0x1000 | 0x0123);
+
+        let objects = objects(&memory);
+        assert_eq!(objects.len(), 1024);
+        let object = &objects[5];
+        assert_eq!((object.jx, object.jp, object.jy), (-1, 2, -16));
+        assert!(object.left_enabled && !object.right_enabled);
+        assert_eq!(object.palette, 2);
+        assert!(!object.flip_horizontally && object.flip_vertically);
+        assert_eq!(object.character, 0x123);
+    }
+}
@@ -0,0 +1,195 @@
+mod output;
+mod script;
+
+use anyhow::{anyhow, Result};
+use output::AudioFile;
+use script::InputScript;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use vvb::emulator::cheat::CheatList;
+use vvb::emulator::game_pak::patch::Patch;
+use vvb::emulator::gdb;
+use vvb::emulator::movie::{Movie, MovieStatus};
+use vvb::emulator::video::{Eye, FRAME_SIZE};
+use vvb::emulator::Emulator;
+
+// One display frame is 20ms
+const NANOSECONDS_PER_FRAME: u64 = 20_000_000;
+// How many instructions --trace remembers
+const TRACE_CAPACITY: usize = 100_000;
+const SRAM_SIZE: usize = 0x2000;
+
+const USAGE: &str = "Usage: vvb-headless <rom.vb> [--frames N] [--input FILE] [--sram FILE] [--patch FILE]... [--cheats FILE] [--record-movie FILE] [--play-movie FILE] [--gdb ADDRESS] [--trace FILE] [--dump-vram] [--out DIR] [--dump-every N]";
+
+struct Options {
+    rom: PathBuf,
+    frames: u64,
+    input: Option<PathBuf>,
+    sram: Option<PathBuf>,
+    patches: Vec<PathBuf>,
+    cheats: Option<PathBuf>,
+    record_movie: Option<PathBuf>,
+    play_movie: Option<PathBuf>,
+    gdb: Option<String>,
+    trace: Option<PathBuf>,
+    dump_vram: bool,
+    out: PathBuf,
+    dump_every: u64,
+}
+
+fn parse_options() -> Result<Options> {
+    let mut args = env::args().skip(1);
+    let mut rom = None;
+    let mut options = Options {
+        rom: PathBuf::new(),
+        frames: 300,
+        input: None,
+        sram: None,
+        patches: vec![],
+        cheats: None,
+        record_movie: None,
+        play_movie: None,
+        gdb: None,
+        trace: None,
+        dump_vram: false,
+        out: PathBuf::from("headless-output"),
+        dump_every: 1,
+    };
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| anyhow!("Missing value for {}", arg))
+        };
+        match arg.as_str() {
+            "--frames" => options.frames = value()?.parse()?,
+            "--input" => options.input = Some(value()?.into()),
+            "--sram" => options.sram = Some(value()?.into()),
+            "--patch" => options.patches.push(value()?.into()),
+            "--cheats" => options.cheats = Some(value()?.into()),
+            "--record-movie" => options.record_movie = Some(value()?.into()),
+            "--play-movie" => options.play_movie = Some(value()?.into()),
+            "--gdb" => options.gdb = Some(value()?),
+            "--trace" => options.trace = Some(value()?.into()),
+            "--dump-vram" => options.dump_vram = true,
+            "--out" => options.out = value()?.into(),
+            "--dump-every" => options.dump_every = value()?.parse()?,
+            _ if arg.starts_with("--") => return Err(anyhow!("Unknown option {}\n{}", arg, USAGE)),
+            _ => rom = Some(PathBuf::from(arg)),
+        }
+    }
+    options.rom = rom.ok_or_else(|| anyhow!(USAGE))?;
+    if options.dump_every == 0 {
+        return Err(anyhow!("--dump-every must be at least 1"));
+    }
+    Ok(options)
+}
+
+fn main() -> Result<()> {
+    let options = parse_options()?;
+
+    let rom = fs::read(&options.rom)?;
+    let mut sram = match &options.sram {
+        Some(path) => fs::read(path)?,
+        None => vec![0; SRAM_SIZE],
+    };
+    let script = match &options.input {
+        Some(path) => InputScript::parse(&fs::read_to_string(path)?)?,
+        None => InputScript::default(),
+    };
+    let patches = options
+        .patches
+        .iter()
+        .map(|path| Patch::new(path.display().to_string(), fs::read(path)?))
+        .collect::<Result<Vec<_>>>()?;
+    fs::create_dir_all(&options.out)?;
+
+    let mut emulator = Emulator::builder()
+        .controller_state(script.state_at(0))
+        .build();
+    let mut frame_buffers = emulator.claim_frame_buffer_consumers();
+    let mut audio_player = emulator.claim_audio_player(1.0);
+    let controller_state = emulator.claim_controller_state();
+    let game_info = emulator.load_patched_game_pak(&rom, &patches, &sram)?;
+    println!(
+        "Loaded \"{}\" (game code {}{}, sha1 {})",
+        game_info.title,
+        game_info.maker_code,
+        game_info.game_code,
+        game_info.sha1_hex()
+    );
+    if let Some(path) = &options.cheats {
+        emulator.set_cheats(CheatList::parse(&fs::read_to_string(path)?)?);
+    }
+    // A movie's input takes priority over the input script
+    if let Some(path) = &options.play_movie {
+        emulator.play_movie(Movie::from_bytes(&fs::read(path)?)?)?;
+    }
+    if options.record_movie.is_some() {
+        emulator.record_movie(true)?;
+    }
+    if options.trace.is_some() {
+        emulator.enable_trace(TRACE_CAPACITY);
+    }
+    // Let a debugger drive the emulator until it detaches, then carry on with the run
+    if let Some(address) = &options.gdb {
+        gdb::listen(&mut emulator, address.as_str())?;
+    }
+
+    let mut audio_file = AudioFile::create(&options.out.join("audio.wav"))?;
+    let mut audio = vec![];
+    // If the game doesn't display anything on a frame, keep dumping the last thing it showed
+    let mut left = vec![0; FRAME_SIZE];
+    let mut right = vec![0; FRAME_SIZE];
+
+    for frame in 0..options.frames {
+        controller_state.store(script.state_at(frame), Ordering::Relaxed);
+        let result = emulator.tick(NANOSECONDS_PER_FRAME);
+        // The trace is most useful when something went wrong, so write it before giving up
+        if result.is_err() {
+            if let Some(path) = &options.trace {
+                emulator.dump_trace(path)?;
+            }
+        }
+        result?;
+
+        frame_buffers[Eye::Left].try_read(|data| left.copy_from_slice(data));
+        frame_buffers[Eye::Right].try_read(|data| right.copy_from_slice(data));
+        if frame % options.dump_every == 0 {
+            output::write_frame(&options.out.join(format!("{:06}-left.png", frame)), &left)?;
+            output::write_frame(&options.out.join(format!("{:06}-right.png", frame)), &right)?;
+        }
+
+        audio.resize(audio_player.available(), (0., 0.));
+        audio_player.play(&mut audio);
+        audio_file.write(&audio)?;
+    }
+    audio_file.finish()?;
+    if let Some(path) = &options.trace {
+        emulator.dump_trace(path)?;
+    }
+
+    if let MovieStatus::Desynced { cycle } = emulator.movie_status() {
+        println!("Movie desynced at cycle {}", cycle);
+    }
+    if let Some(path) = &options.record_movie {
+        if let Some(movie) = emulator.stop_movie() {
+            fs::write(path, movie.to_bytes()?)?;
+        }
+    }
+
+    if options.dump_vram {
+        output::write_vram(&options.out, &emulator)?;
+    }
+
+    emulator.read_sram(&mut sram)?;
+    fs::write(options.out.join("sram.bin"), sram)?;
+
+    println!(
+        "Ran {} frame(s), output written to {}",
+        options.frames,
+        options.out.display()
+    );
+    Ok(())
+}
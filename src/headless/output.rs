@@ -0,0 +1,85 @@
+use anyhow::Result;
+use hound::{SampleFormat, WavSpec, WavWriter};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use vvb::emulator::audio::SAMPLE_RATE;
+use vvb::emulator::video::inspect::{Image, Palette, BG_MAP_COUNT};
+use vvb::emulator::video::{VB_HEIGHT, VB_WIDTH};
+use vvb::emulator::Emulator;
+
+// Frames are one brightness byte per pixel, so save them as grayscale
+pub fn write_frame(path: &Path, frame: &[u8]) -> Result<()> {
+    let file = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(file, VB_WIDTH as u32, VB_HEIGHT as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(frame)?;
+    writer.finish()?;
+    Ok(())
+}
+
+pub fn write_image(path: &Path, image: &Image) -> Result<()> {
+    let file = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(file, image.width as u32, image.height as u32);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&image.pixels)?;
+    writer.finish()?;
+    Ok(())
+}
+
+// The characters, every BG map, and a listing of the worlds and visible objects
+pub fn write_vram(dir: &Path, emulator: &Emulator) -> Result<()> {
+    write_image(
+        &dir.join("characters.png"),
+        &emulator.inspect_characters(Palette::Raw),
+    )?;
+    for index in 0..BG_MAP_COUNT {
+        let image = emulator.inspect_bg_map(index)?;
+        write_image(&dir.join(format!("bg-map-{:02}.png", index)), &image)?;
+    }
+
+    let mut listing = String::new();
+    for (index, world) in emulator.inspect_worlds().iter().enumerate().rev() {
+        listing += &format!("world {}: {:?}\n", index, world);
+    }
+    for (index, object) in emulator.inspect_objects().iter().enumerate() {
+        if object.left_enabled || object.right_enabled {
+            listing += &format!("object {}: {:?}\n", index, object);
+        }
+    }
+    std::fs::write(dir.join("vram.txt"), listing)?;
+    Ok(())
+}
+
+pub struct AudioFile {
+    writer: WavWriter<BufWriter<File>>,
+}
+impl AudioFile {
+    pub fn create(path: &Path) -> Result<Self> {
+        let spec = WavSpec {
+            channels: 2,
+            sample_rate: SAMPLE_RATE,
+            bits_per_sample: 32,
+            sample_format: SampleFormat::Float,
+        };
+        let writer = WavWriter::create(path, spec)?;
+        Ok(Self { writer })
+    }
+
+    pub fn write(&mut self, frames: &[(f32, f32)]) -> Result<()> {
+        for (left, right) in frames {
+            self.writer.write_sample(*left)?;
+            self.writer.write_sample(*right)?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<()> {
+        self.writer.finalize()?;
+        Ok(())
+    }
+}
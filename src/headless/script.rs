@@ -0,0 +1,126 @@
+use anyhow::{anyhow, Result};
+
+// The controller always reports this "signature" bit as set
+pub const SIGNATURE: u16 = 0x0002;
+
+const BUTTONS: [(&str, u16); 14] = [
+    ("ll", 0x0200),
+    ("lr", 0x0100),
+    ("lu", 0x0800),
+    ("ld", 0x0400),
+    ("rl", 0x4000),
+    ("rr", 0x0080),
+    ("ru", 0x0040),
+    ("rd", 0x8000),
+    ("a", 0x0004),
+    ("b", 0x0008),
+    ("lt", 0x0020),
+    ("rt", 0x0010),
+    ("select", 0x2000),
+    ("start", 0x1000),
+];
+
+// A list of controller states, and the frame each one starts on.
+// Each line of a script looks like "<frame> <button>+<button>+...",
+// and the buttons stay held until a later line changes them.
+// Raw controller values can be passed as hex, e.g. "120 0x1002".
+#[derive(Debug, Default)]
+pub struct InputScript {
+    entries: Vec<(u64, u16)>,
+}
+impl InputScript {
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut entries: Vec<(u64, u16)> = vec![];
+        for (index, line) in text.lines().enumerate() {
+            let line = match line.split_once('#') {
+                Some((content, _)) => content,
+                None => line,
+            }
+            .trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (frame, buttons) = match line.split_once(char::is_whitespace) {
+                Some((frame, buttons)) => (frame, buttons.trim()),
+                None => (line, ""),
+            };
+            let frame: u64 = frame
+                .parse()
+                .map_err(|_| anyhow!("Line {}: invalid frame \"{}\"", index + 1, frame))?;
+            if let Some((last_frame, _)) = entries.last() {
+                if *last_frame > frame {
+                    return Err(anyhow!("Line {}: frames must be in order", index + 1));
+                }
+            }
+            let state = parse_buttons(buttons).map_err(|e| anyhow!("Line {}: {}", index + 1, e))?;
+            entries.push((frame, state));
+        }
+        Ok(Self { entries })
+    }
+
+    // The controller state to use for the given frame
+    pub fn state_at(&self, frame: u64) -> u16 {
+        self.entries
+            .iter()
+            .take_while(|(start, _)| *start <= frame)
+            .last()
+            .map_or(SIGNATURE, |(_, state)| *state)
+    }
+}
+
+fn parse_buttons(buttons: &str) -> Result<u16> {
+    if let Some(hex) = buttons.strip_prefix("0x") {
+        return u16::from_str_radix(hex, 16).map_err(|_| anyhow!("invalid state \"{}\"", buttons));
+    }
+    let mut state = SIGNATURE;
+    for name in buttons.split('+').map(str::trim).filter(|n| !n.is_empty()) {
+        let name = name.to_ascii_lowercase();
+        let (_, mask) = BUTTONS
+            .iter()
+            .find(|(button, _)| *button == name)
+            .ok_or_else(|| anyhow!("unknown button \"{}\"", name))?;
+        state |= mask;
+    }
+    Ok(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InputScript, SIGNATURE};
+
+    #[test]
+    fn holds_buttons_until_changed() {
+        let script = InputScript::parse(
+            "
+            # press start for a few frames
+            10 start
+            15
+            20 a+LU
+            ",
+        )
+        .unwrap();
+        assert_eq!(script.state_at(0), SIGNATURE);
+        assert_eq!(script.state_at(10), SIGNATURE | 0x1000);
+        assert_eq!(script.state_at(14), SIGNATURE | 0x1000);
+        assert_eq!(script.state_at(15), SIGNATURE);
+        assert_eq!(script.state_at(1000), SIGNATURE | 0x0004 | 0x0800);
+    }
+
+    #[test]
+    fn accepts_raw_states() {
+        let script = InputScript::parse("5 0x1000").unwrap();
+        assert_eq!(script.state_at(5), 0x1000);
+    }
+
+    #[test]
+    fn rejects_unknown_buttons() {
+        let err = InputScript::parse("1 a\n2 jump").unwrap_err();
+        assert_eq!(err.to_string(), "Line 2: unknown button \"jump\"");
+    }
+
+    #[test]
+    fn rejects_out_of_order_frames() {
+        let err = InputScript::parse("10 a\n5 b").unwrap_err();
+        assert_eq!(err.to_string(), "Line 2: frames must be in order");
+    }
+}
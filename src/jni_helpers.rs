@@ -32,6 +32,7 @@ pub trait EnvExtensions<'a> {
     fn get_integer_value<O: AsRef<JObject<'a>>>(&mut self, integer: O) -> Result<Option<i32>>;
     fn get_int<O: AsRef<JObject<'a>>>(&mut self, this: O, field: &str) -> Result<i32>;
     fn get_percent<O: AsRef<JObject<'a>>>(&mut self, this: O, field: &str) -> Result<f32>;
+    #[cfg(feature = "gl-renderers")]
     fn get_color<O: AsRef<JObject<'a>>>(&mut self, this: O, field: &str) -> Result<(u8, u8, u8)>;
     fn get_direct_buffer<'buf>(&'a self, buf: JByteBuffer) -> Result<&'buf mut [u8]>;
 }
@@ -51,6 +52,7 @@ impl<'a> EnvExtensions<'a> for JNIEnv<'a> {
         let res = self.get_field(this, field, "F")?.f()?;
         Ok(res)
     }
+    #[cfg(feature = "gl-renderers")]
     fn get_color<O: AsRef<JObject<'a>>>(&mut self, this: O, field: &str) -> Result<(u8, u8, u8)> {
         let color = self.get_int(this, field)?;
         // android passes color as ARGB
@@ -47,7 +47,7 @@ impl<T> JavaBinding<T> {
         Ok(())
     }
 
-    pub fn get_value(&self, env: &mut JNIEnv, obj: JObject) -> JavaGetResult<T> {
+    pub fn get_value(&self, env: &mut JNIEnv, obj: JObject) -> JavaGetResult<'_, T> {
         let mutex_ptr = self.get_mutex_ptr(env, &obj)?;
         // safe because we already null checked
         Ok(unsafe { (*mutex_ptr).lock().unwrap() })
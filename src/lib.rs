@@ -2,25 +2,43 @@
 #![allow(clippy::missing_safety_doc)] // because auto-generated code
 #![allow(clippy::unnecessary_wraps)] // JNI interop is easier if everything returns Result
 
+#[cfg(feature = "jni")]
 mod audio;
+#[cfg(feature = "jni")]
 mod controller;
 pub mod emulator;
+#[cfg(feature = "jni")]
 mod jni_helpers;
+#[cfg(feature = "gl-renderers")]
 mod video;
 
+#[cfg(feature = "android")]
 use android_logger::{self, Config};
+#[cfg(feature = "jni")]
 use anyhow::Result;
+#[cfg(feature = "jni")]
 use jni::objects::JObject;
+#[cfg(feature = "jni")]
 use jni::JNIEnv;
-use log::{info, LevelFilter};
+#[cfg(feature = "jni")]
+use log::info;
+#[cfg(feature = "android")]
+use log::LevelFilter;
+#[cfg(feature = "gl-renderers")]
 use video::{Cardboard, QrCode};
 
+#[cfg(feature = "jni")]
 use crate::jni_helpers::EnvExtensions;
+#[cfg(feature = "jni")]
 pub use audio::jni::*;
+#[cfg(feature = "jni")]
 pub use controller::jni::*;
+#[cfg(feature = "jni")]
 pub use emulator::jni::*;
+#[cfg(feature = "gl-renderers")]
 pub use video::jni::*;
 
+#[cfg(feature = "jni")]
 jni_func!(
     VvbLibrary_nativeInitialize,
     init,
@@ -28,6 +46,8 @@ jni_func!(
     JObject<'a>,
     JObject<'a>
 );
+#[cfg(feature = "jni")]
+#[cfg_attr(not(feature = "gl-renderers"), allow(unused_variables))]
 fn init<'a>(
     env: &mut JNIEnv<'a>,
     _this: JObject,
@@ -35,11 +55,15 @@ fn init<'a>(
     sample_rate: JObject<'a>,
     frames_per_burst: JObject<'a>,
 ) -> Result<()> {
+    #[cfg(feature = "android")]
     android_logger::init_once(Config::default().with_max_level(LevelFilter::Info));
     info!("Hello from vvb");
 
-    let vm = env.get_java_vm()?;
-    Cardboard::initialize(vm.get_java_vm_pointer(), context);
+    #[cfg(feature = "gl-renderers")]
+    {
+        let vm = env.get_java_vm()?;
+        Cardboard::initialize(vm.get_java_vm_pointer(), context);
+    }
 
     let sample_rate = env.get_integer_value(sample_rate)?;
     let frames_per_burst = env.get_integer_value(frames_per_burst)?;
@@ -49,7 +73,9 @@ fn init<'a>(
     Ok(())
 }
 
+#[cfg(feature = "gl-renderers")]
 jni_func!(VvbLibrary_nativeChangeDeviceParams, change_device_params);
+#[cfg(feature = "gl-renderers")]
 fn change_device_params(_env: &mut JNIEnv, _this: JObject) -> Result<()> {
     QrCode::scan_qr_code_and_save_device_params();
     Ok(())